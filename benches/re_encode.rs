@@ -0,0 +1,71 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use odyssey::encoder::FrameEncoder;
+
+const SCREEN_WIDTH: usize = 6480;
+const SCREEN_HEIGHT: usize = 3600;
+
+/// The original byte-at-a-time re-encode, kept as a baseline for comparison
+fn legacy_re_encode(display_bit_depth: &[u8], buffer: Vec<u8>, bit_depth: u8) -> Vec<u8> {
+    if display_bit_depth.len() == 1 && display_bit_depth[0] == bit_depth {
+        return buffer;
+    }
+
+    let chunk_size: u8 = display_bit_depth.iter().sum();
+    let pixels_per_chunk = display_bit_depth.len();
+
+    let mut new_buffer: Vec<u8> = Vec::new();
+
+    buffer
+        .chunks_exact(pixels_per_chunk)
+        .for_each(|pixel_chunk| {
+            let mut raw_chunk = 0b0;
+            let mut pos_shift = chunk_size;
+            for (pixel, depth) in pixel_chunk.iter().zip(display_bit_depth) {
+                let depth_difference = bit_depth - depth;
+                pos_shift -= depth;
+
+                let shifted_pixel: u64 = ((*pixel as u64) >> depth_difference) << (pos_shift);
+                raw_chunk |= shifted_pixel;
+            }
+
+            for i in 0..(chunk_size / 8) {
+                let byte = ((raw_chunk >> (8 * i)) & 0xFF) as u8;
+                new_buffer.push(byte);
+            }
+        });
+
+    new_buffer
+}
+
+fn test_frame() -> Vec<u8> {
+    (0..SCREEN_WIDTH * SCREEN_HEIGHT)
+        .map(|i| ((i % SCREEN_WIDTH) ^ (i / SCREEN_WIDTH)) as u8)
+        .collect()
+}
+
+fn bench_re_encode(c: &mut Criterion) {
+    let frame = test_frame();
+
+    let mut group = c.benchmark_group("re_encode");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(frame.len() as u64));
+
+    for layout in [vec![5, 6, 5], vec![8, 8, 8], vec![4, 4]] {
+        let name = format!("{:?}", layout);
+
+        group.bench_with_input(BenchmarkId::new("legacy", &name), &layout, |b, layout| {
+            b.iter(|| legacy_re_encode(layout, frame.clone(), 8))
+        });
+
+        let mut encoder = FrameEncoder::new(layout.clone(), SCREEN_WIDTH);
+        group.bench_with_input(BenchmarkId::new("lookup_table", &name), &layout, |b, _| {
+            b.iter(|| encoder.encode(&frame, 8).len())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_re_encode);
+criterion_main!(benches);
@@ -0,0 +1,174 @@
+use std::io::{Error, ErrorKind};
+
+use crate::{
+    api_objects::{BoundingBox, LayerAnalysis, PrintAnalysis},
+    display::Frame,
+    printfile::PrintFile,
+};
+
+/// Number of separate regions of lit pixels in a layer, counting diagonally
+/// touching pixels as connected, and how many of them have nothing lit
+/// beneath them in the previous layer
+fn count_islands(lit: &[bool], width: usize, previous: Option<&[bool]>) -> (usize, usize) {
+    let height = lit.len() / width.max(1);
+    let mut visited = vec![false; lit.len()];
+    let mut stack = Vec::new();
+    let (mut islands, mut unsupported) = (0, 0);
+
+    for start in 0..lit.len() {
+        if !lit[start] || visited[start] {
+            continue;
+        }
+
+        islands += 1;
+        let mut supported = false;
+        visited[start] = true;
+        stack.push(start);
+
+        while let Some(pixel) = stack.pop() {
+            supported |= previous.is_none_or(|previous| previous[pixel]);
+
+            let (x, y) = (pixel % width, pixel / width);
+            for neighbour_y in y.saturating_sub(1)..(y + 2).min(height) {
+                for neighbour_x in x.saturating_sub(1)..(x + 2).min(width) {
+                    let neighbour = neighbour_y * width + neighbour_x;
+                    if lit[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+
+        if !supported {
+            unsupported += 1;
+        }
+    }
+
+    (islands, unsupported)
+}
+
+/// Lit pixels in a layer, with anti-aliased pixels counting in proportion to
+/// their grey level
+pub fn pixel_area(frame: &Frame) -> f64 {
+    frame.buffer.iter().map(|&level| level as f64 / 255.0).sum()
+}
+
+/// Measure a print from its layers, in pixels. Physical measurements are
+/// filled in by `with_pixel_pitch`, so the analysis can be cached whatever
+/// display it ends up printed on.
+pub async fn analyse(file: &mut (dyn PrintFile + Send)) -> std::io::Result<PrintAnalysis> {
+    let metadata = file.get_metadata();
+    let mut layers = Vec::with_capacity(metadata.layer_count);
+    let mut bounding_box: Option<BoundingBox> = None;
+    let mut previous: Option<(u32, u32, Vec<bool>)> = None;
+
+    for layer in 0..file.get_layer_count() {
+        let frame = match file.get_layer_data(layer).await {
+            Some(data) => Frame::from_vec(data.file_name, data.exposure_time, data.data)?,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Layer {} not found", layer),
+                ))
+            }
+        };
+        let width = frame.width as usize;
+
+        let lit: Vec<bool> = frame.buffer.iter().map(|&level| level > 0).collect();
+        let pixel_area = pixel_area(&frame);
+
+        for (pixel, _) in lit.iter().enumerate().filter(|(_, &lit)| lit) {
+            let (x, y) = ((pixel % width) as u32, (pixel / width) as u32);
+            let bounds = bounding_box.get_or_insert(BoundingBox {
+                min_x: x,
+                min_y: y,
+                max_x: x,
+                max_y: y,
+            });
+            bounds.min_x = bounds.min_x.min(x);
+            bounds.min_y = bounds.min_y.min(y);
+            bounds.max_x = bounds.max_x.max(x);
+            bounds.max_y = bounds.max_y.max(y);
+        }
+
+        let previous_lit = match &previous {
+            Some((previous_width, previous_height, previous_lit)) => {
+                if (*previous_width, *previous_height) != (frame.width, frame.height) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Layer {} is a different size to the others", layer),
+                    ));
+                }
+                Some(previous_lit.as_slice())
+            }
+            None => None,
+        };
+        let (islands, unsupported_islands) = count_islands(&lit, width, previous_lit);
+
+        layers.push(LayerAnalysis {
+            layer,
+            pixel_area,
+            area: None,
+            islands,
+            unsupported_islands,
+        });
+        previous = Some((frame.width, frame.height, lit));
+    }
+
+    let largest_layer = layers
+        .iter()
+        .max_by(|a, b| a.pixel_area.total_cmp(&b.pixel_area))
+        .filter(|layer| layer.pixel_area > 0.0)
+        .map(|layer| layer.layer);
+    let unsupported_islands = layers.iter().map(|layer| layer.unsupported_islands).sum();
+
+    Ok(PrintAnalysis {
+        file_data: metadata.file_data,
+        layer_height: metadata.layer_height,
+        pixel_pitch: None,
+        height: metadata.layer_height * layers.len() as f64,
+        layers,
+        bounding_box,
+        width: None,
+        depth: None,
+        volume: None,
+        largest_layer,
+        unsupported_islands,
+    })
+}
+
+/// Fill in the physical measurements of an analysis, given the size of a
+/// display pixel in mm
+pub fn with_pixel_pitch(analysis: PrintAnalysis, pixel_pitch: Option<f64>) -> PrintAnalysis {
+    let Some(pixel_pitch) = pixel_pitch.filter(|pitch| *pitch > 0.0) else {
+        return analysis;
+    };
+    let pixel_size = pixel_pitch * pixel_pitch;
+
+    let layers: Vec<LayerAnalysis> = analysis
+        .layers
+        .into_iter()
+        .map(|layer| LayerAnalysis {
+            area: Some(layer.pixel_area * pixel_size),
+            ..layer
+        })
+        .collect();
+
+    // mm³ to ml
+    let volume =
+        layers.iter().filter_map(|layer| layer.area).sum::<f64>() * analysis.layer_height / 1000.0;
+
+    PrintAnalysis {
+        pixel_pitch: Some(pixel_pitch),
+        width: analysis
+            .bounding_box
+            .map(|bounds| (bounds.max_x - bounds.min_x + 1) as f64 * pixel_pitch),
+        depth: analysis
+            .bounding_box
+            .map(|bounds| (bounds.max_y - bounds.min_y + 1) as f64 * pixel_pitch),
+        volume: Some(volume),
+        layers,
+        ..analysis
+    }
+}
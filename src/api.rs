@@ -1,69 +1,111 @@
 use std::{
+    collections::HashSet,
     ffi::OsStr,
     fs::File,
-    io::{Error, ErrorKind, Read, Write},
+    io::{Error, ErrorKind, Read},
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, UNIX_EPOCH},
 };
 
-use glob::glob;
+use futures::{executor::block_on, stream::BoxStream, SinkExt, StreamExt};
 use itertools::Itertools;
 use poem::{
     error::{
-        BadRequest, GetDataError, InternalServerError, MethodNotAllowedError, NotImplemented,
-        ServiceUnavailable, Unauthorized,
+        BadRequest, Conflict, GatewayTimeout, GetDataError, InternalServerError,
+        MethodNotAllowedError, NotFound, ServiceUnavailable, Unauthorized,
     },
+    get, handler,
     listener::TcpListener,
     middleware::Cors,
-    web::Data,
-    EndpointExt, Result, Route, Server,
+    web::{
+        websocket::{Message, WebSocket},
+        Data, Multipart,
+    },
+    Body, EndpointExt, IntoResponse, Result, Route, Server,
 };
 use poem_openapi::{
-    param::Query,
-    payload::{Attachment, Json},
-    types::multipart::Upload,
-    Multipart, Object, OpenApi, OpenApiService,
+    param::{self, Query},
+    payload::{Attachment, Binary, EventStream, Json},
+    Object, OpenApi, OpenApiService,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs,
     sync::{broadcast, mpsc, RwLock},
-    time::interval,
+    time::{interval, timeout},
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    analysis,
     api_objects::{
-        DisplayTest, FileMetadata, LocationCategory, PhysicalState, PrintMetadata, PrinterState,
-        PrinterStatus, ThumbnailSize,
+        ConnectionStatus, ConsoleResponse, CrossSectionAxis, DisplayTest, FileMetadata,
+        LocationCategory, PhysicalState, PrintAnalysis, PrintMetadata, PrinterState, PrinterStatus,
+        SortKey, SortOrder, ThumbnailSize, TrafficEntry, UploadProgress, UploadSession, UsbEvent,
     },
     configuration::{ApiConfig, Configuration},
+    file_listing::{ListingEntry, ListingOptions},
+    in_flight::InFlight,
+    local_files::LocalFiles,
+    metadata_cache::{self, MetadataCache},
+    paths,
+    preview::{self, Preview},
     printer::Operation,
     printfile::PrintFile,
     sl1::Sl1,
+    traffic_log::TrafficLog,
+    upload::{self, Uploads},
+    usb::{self, UsbDrives},
 };
 
-#[derive(Debug, Multipart)]
-struct UploadPayload {
-    file: Upload,
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
 pub struct FilesResponse {
     pub files: Vec<PrintMetadata>,
     pub dirs: Vec<FileMetadata>,
+    /// Files named like print files which couldn't be loaded
+    pub invalid_files: Vec<FileMetadata>,
     pub next_index: Option<usize>,
 }
 
 const DEFAULT_PAGE_INDEX: usize = 0;
 const DEFAULT_PAGE_SIZE: usize = 100;
+/// Seconds to wait for the response to a console command
+const DEFAULT_CONSOLE_TIMEOUT: u64 = 30;
+
+static NEXT_COMMAND_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_command_id() -> u64 {
+    NEXT_COMMAND_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Console commands are only run by the printer while it is idle
+async fn check_console_available(state_ref: &Arc<RwLock<PrinterState>>) -> Result<()> {
+    if !matches!(state_ref.read().await.status, PrinterStatus::Idle) {
+        return Err(Conflict(GetDataError(
+            "Printer must be idle to run console commands",
+        )));
+    }
+    Ok(())
+}
 
 struct Api;
 
+/// Cross-sections being rendered, by file, axis and position
+type CrossSections = InFlight<(PathBuf, CrossSectionAxis, Option<u32>), Preview>;
+
+/// Layer previews being rendered, by file and layer
+type LayerPreviews = InFlight<(PathBuf, usize), Preview>;
+
 #[OpenApi]
 impl Api {
+    /// Start printing the given file, rejecting it if it isn't a valid print
+    /// file. Should it fail to load once the print starts, the reason is
+    /// reported in the printer state's `error`.
     #[oai(path = "/print/start", method = "post")]
     async fn start_print(
         &self,
@@ -71,12 +113,15 @@ impl Api {
         Query(location): Query<Option<LocationCategory>>,
         Data(operation_sender): Data<&mpsc::Sender<Operation>>,
         Data(configuration): Data<&ApiConfig>,
+        Data(metadata_cache): Data<&MetadataCache>,
     ) -> Result<()> {
         let location = location.unwrap_or(LocationCategory::Local);
 
         let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
 
-        let file_data = Api::_get_filedata(full_file_path, &location, configuration)?;
+        let file_data =
+            Api::_get_print_metadata(full_file_path, &location, metadata_cache, configuration)?
+                .file_data;
 
         operation_sender
             .send(Operation::StartPrint { file_data })
@@ -133,6 +178,15 @@ impl Api {
         Json(state_ref.read().await.clone())
     }
 
+    #[oai(path = "/traffic", method = "get")]
+    async fn get_traffic(
+        &self,
+        Query(count): Query<Option<usize>>,
+        Data(traffic_log): Data<&TrafficLog>,
+    ) -> Json<Vec<TrafficEntry>> {
+        Json(traffic_log.recent(count))
+    }
+
     #[oai(path = "/config", method = "get")]
     async fn get_config(&self, Data(full_config): Data<&Configuration>) -> Json<Configuration> {
         Json(full_config.clone())
@@ -144,9 +198,14 @@ impl Api {
         z: Query<Option<f64>>,
         cure: Query<Option<bool>>,
         Data(operation_sender): Data<&mpsc::Sender<Operation>>,
-        Data(_state_ref): Data<&Arc<RwLock<PrinterState>>>,
+        Data(state_ref): Data<&Arc<RwLock<PrinterState>>>,
     ) -> Result<()> {
         if let Query(Some(z)) = z {
+            if !state_ref.read().await.physical_state.homed {
+                return Err(Conflict(GetDataError(
+                    "Plate must be homed before manual moves",
+                )));
+            }
             operation_sender
                 .send(Operation::ManualMove {
                     z: (z * 1000.0).trunc() as u32,
@@ -187,13 +246,53 @@ impl Api {
         Data(_state_ref): Data<&Arc<RwLock<PrinterState>>>,
     ) -> Result<()> {
         operation_sender
-            .send(Operation::ManualCommand { command })
+            .send(Operation::ManualCommand {
+                command,
+                id: next_command_id(),
+            })
             .await
             .map_err(ServiceUnavailable)?;
 
         Ok(())
     }
 
+    /// Run a gcode command, returning the firmware's response to it
+    #[oai(path = "/console", method = "post")]
+    async fn console_command(
+        &self,
+        Query(command): Query<String>,
+        Query(timeout_secs): Query<Option<u64>>,
+        Data(operation_sender): Data<&mpsc::Sender<Operation>>,
+        Data(console_sender): Data<&broadcast::Sender<ConsoleResponse>>,
+        Data(state_ref): Data<&Arc<RwLock<PrinterState>>>,
+    ) -> Result<Json<ConsoleResponse>> {
+        check_console_available(state_ref).await?;
+
+        // Subscribe before sending, so the response can't be missed
+        let mut console_receiver = console_sender.subscribe();
+        let id = next_command_id();
+
+        operation_sender
+            .send(Operation::ManualCommand { command, id })
+            .await
+            .map_err(ServiceUnavailable)?;
+
+        let duration = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_CONSOLE_TIMEOUT));
+        let response = timeout(duration, async {
+            loop {
+                match console_receiver.recv().await {
+                    Ok(response) if response.id == id => return Ok(response),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(e) => return Err(ServiceUnavailable(e)),
+                }
+            }
+        })
+        .await
+        .map_err(|_| GatewayTimeout(GetDataError("Timed out awaiting command response")))??;
+
+        Ok(Json(response))
+    }
+
     #[oai(path = "/manual/display_test", method = "post")]
     async fn manual_display_test(
         &self,
@@ -228,131 +327,364 @@ impl Api {
             .map_err(ServiceUnavailable)
     }
 
+    /// Upload a multipart form's `file` field, streamed straight to disk. The
+    /// optional `sha256` digest to verify it against, and `subdirectory` to
+    /// upload into, must come before it.
     #[oai(path = "/files", method = "post")]
     async fn upload_file(
         &self,
-        file_upload: UploadPayload,
+        mut multipart: Multipart,
         Data(configuration): Data<&ApiConfig>,
-    ) -> Result<()> {
+        Data(uploads): Data<&Uploads>,
+        Data(metadata_cache): Data<&MetadataCache>,
+    ) -> Result<Json<FileMetadata>> {
         log::info!("Uploading file");
 
-        let file_name = file_upload
-            .file
-            .file_name()
-            .map(|s| s.to_string().clone())
-            .ok_or(BadRequest(GetDataError("Could not get file name")))?;
+        let mut sha256 = None;
+        let mut subdirectory = None;
+        let mut received = None;
+
+        // Every field is read before the file is moved into place, so that a
+        // checksum sent after it rejects the upload rather than being ignored
+        let fields = async {
+            while let Some(field) = multipart.next_field().await? {
+                match (field.name(), received.is_some()) {
+                    (Some("sha256" | "subdirectory"), true) => {
+                        return Err(BadRequest(GetDataError(
+                            "The sha256 and subdirectory fields must come before the file",
+                        )));
+                    }
+                    (Some("sha256"), false) => sha256 = Some(field.text().await?),
+                    (Some("subdirectory"), false) => subdirectory = Some(field.text().await?),
+                    (Some("file"), false) => {
+                        let file_name = field
+                            .file_name()
+                            .map(str::to_string)
+                            .ok_or(BadRequest(GetDataError("Could not get file name")))?;
+
+                        received = Some(
+                            uploads
+                                .receive(
+                                    subdirectory.as_deref(),
+                                    &file_name,
+                                    Box::pin(field.into_async_read()),
+                                    None,
+                                    sha256.as_deref(),
+                                )
+                                .await
+                                .map_err(file_error)?,
+                        );
+                    }
+                    (name, _) => log::debug!("Ignoring upload field {:?}", name),
+                }
+            }
+            Ok(())
+        }
+        .await;
 
-        let bytes = file_upload.file.into_vec().await.map_err(BadRequest)?;
+        match (fields, received) {
+            (Ok(()), Some(received)) => {
+                let path = uploads.complete(received).await.map_err(file_error)?;
+                Api::_uploaded_file(path, metadata_cache, configuration)
+            }
+            (Ok(()), None) => Err(BadRequest(GetDataError("No file field was uploaded"))),
+            (Err(e), received) => {
+                if let Some(received) = received {
+                    uploads.discard(received).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Metadata of a newly uploaded file, caching its print metadata ahead of
+    /// it being listed
+    fn _uploaded_file(
+        path: PathBuf,
+        metadata_cache: &MetadataCache,
+        configuration: &ApiConfig,
+    ) -> Result<Json<FileMetadata>> {
+        let file_data = Api::_get_filedata(path, &LocationCategory::Local, configuration)?;
+
+        if let Err(e) = metadata_cache.print_metadata(file_data.clone()) {
+            log::warn!(
+                "Uploaded file {} is not a valid print file: {}",
+                file_data.name,
+                e
+            );
+        }
+        metadata_cache.save_or_log();
 
-        let mut f = File::create(configuration.upload_path.clone() + "/" + &file_name)
-            .expect("Could not create new file");
-        f.write_all(bytes.as_slice())
-            .expect("Failed to write file contents");
+        Ok(Json(file_data))
+    }
 
-        Ok(())
+    /// Begin a resumable upload, sent in chunks
+    #[oai(path = "/files/chunked", method = "post")]
+    async fn start_chunked_upload(
+        &self,
+        Query(file_name): Query<String>,
+        Query(total_size): Query<u64>,
+        Query(sha256): Query<Option<String>>,
+        Query(subdirectory): Query<Option<String>>,
+        Data(uploads): Data<&Uploads>,
+    ) -> Result<Json<UploadSession>> {
+        log::info!("Starting chunked upload of {}", file_name);
+
+        uploads
+            .start_chunked(subdirectory.as_deref(), &file_name, total_size, sha256)
+            .await
+            .map(Json)
+            .map_err(file_error)
+    }
+
+    /// Get the state of a chunked upload, such as to resume it
+    #[oai(path = "/files/chunked/:id", method = "get")]
+    async fn get_chunked_upload(
+        &self,
+        param::Path(id): param::Path<String>,
+        Data(uploads): Data<&Uploads>,
+    ) -> Result<Json<UploadSession>> {
+        uploads.session(&id).map(Json).map_err(file_error)
+    }
+
+    /// Append a chunk at the given offset, which must be the number of bytes
+    /// received so far
+    #[oai(path = "/files/chunked/:id", method = "put")]
+    async fn write_upload_chunk(
+        &self,
+        param::Path(id): param::Path<String>,
+        Query(offset): Query<u64>,
+        chunk: Binary<Body>,
+        Data(uploads): Data<&Uploads>,
+    ) -> Result<Json<UploadSession>> {
+        uploads
+            .write_chunk(&id, offset, chunk.0.into_async_read())
+            .await
+            .map(Json)
+            .map_err(file_error)
+    }
+
+    /// Finish a chunked upload once all of it has been received
+    #[oai(path = "/files/chunked/:id/complete", method = "post")]
+    async fn complete_chunked_upload(
+        &self,
+        param::Path(id): param::Path<String>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(uploads): Data<&Uploads>,
+        Data(metadata_cache): Data<&MetadataCache>,
+    ) -> Result<Json<FileMetadata>> {
+        let path = uploads.complete_chunked(&id).await.map_err(file_error)?;
+
+        Api::_uploaded_file(path, metadata_cache, configuration)
+    }
+
+    #[oai(path = "/files/chunked/:id", method = "delete")]
+    async fn abort_chunked_upload(
+        &self,
+        param::Path(id): param::Path<String>,
+        Data(uploads): Data<&Uploads>,
+    ) -> Result<()> {
+        uploads.abort_chunked(&id).await.map_err(file_error)
+    }
+
+    /// Stream of progress updates for all uploads
+    #[oai(path = "/files/upload_events", method = "get")]
+    async fn upload_events(
+        &self,
+        Data(uploads): Data<&Uploads>,
+    ) -> EventStream<BoxStream<'static, UploadProgress>> {
+        EventStream::new(broadcast_stream(uploads.subscribe())).keep_alive(Duration::from_secs(15))
     }
 
     #[oai(path = "/files", method = "get")]
+    #[allow(clippy::too_many_arguments)]
     async fn get_files(
         &self,
         Query(subdirectory): Query<Option<String>>,
         Query(location): Query<Option<LocationCategory>>,
         Query(page_index): Query<Option<usize>>,
         Query(page_size): Query<Option<usize>>,
+        /// Property to sort by, defaulting to the name
+        Query(sort): Query<Option<SortKey>>,
+        Query(order): Query<Option<SortOrder>>,
+        /// Only list entries whose name contains this, ignoring case
+        Query(search): Query<Option<String>>,
+        /// Only list files with this extension
+        Query(format): Query<Option<String>>,
+        /// Only list valid, or only invalid, print files
+        Query(valid): Query<Option<bool>>,
         Data(configuration): Data<&ApiConfig>,
+        Data(metadata_cache): Data<&MetadataCache>,
     ) -> Result<Json<FilesResponse>> {
         let location = location.unwrap_or(LocationCategory::Local);
         let page_index = page_index.unwrap_or(DEFAULT_PAGE_INDEX);
-        let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        let options = ListingOptions {
+            sort: sort.unwrap_or_default(),
+            order: order.unwrap_or_default(),
+            search,
+            format,
+            valid,
+        };
 
         log::info!(
-            "Getting files in location={:?}, subdirectory={:?}, page_index={:?}, page_size={:?}",
+            "Getting files in location={:?}, subdirectory={:?}, page_index={:?}, page_size={:?}, options={:?}",
             location,
             subdirectory,
             page_index,
-            page_size
+            page_size,
+            options
         );
 
-        match location {
-            LocationCategory::Local => {
-                Api::_get_local_files(subdirectory, page_index, page_size, configuration)
-            }
-            LocationCategory::Usb => Api::_get_usb_files(page_index, page_size, configuration),
-        }
+        // Files missing from the cache are read in full, so the listing is
+        // kept off the async workers
+        let metadata_cache = metadata_cache.clone();
+        let configuration = configuration.clone();
+        let entries = tokio::task::spawn_blocking(move || {
+            let entries = match location {
+                LocationCategory::Local => {
+                    Api::_get_local_files(subdirectory, &options, &metadata_cache, &configuration)
+                }
+                LocationCategory::Usb => {
+                    Api::_get_usb_files(subdirectory, &options, &metadata_cache, &configuration)
+                }
+            };
+            metadata_cache.save_or_log();
+            entries
+        })
+        .await
+        .map_err(InternalServerError)??;
+
+        // Everything is filtered and sorted before being paginated, so pages
+        // are consistent
+        let mut pages = entries.chunks(page_size).skip(page_index);
+        let page = pages.next().unwrap_or_default();
+        let next_index = pages.next().is_some().then_some(page_index + 1);
+
+        Ok(Json(Api::_files_response(page.to_vec(), next_index)))
     }
 
     fn _get_local_files(
         subdirectory: Option<String>,
-        page_index: usize,
-        page_size: usize,
+        options: &ListingOptions,
+        metadata_cache: &MetadataCache,
         configuration: &ApiConfig,
-    ) -> Result<Json<FilesResponse>> {
-        let directory = subdirectory.unwrap_or("".to_string());
-
-        if directory.starts_with('/') || directory.starts_with('.') {
-            return Err(Unauthorized(MethodNotAllowedError));
-        }
-
-        let upload_string = &configuration.upload_path;
-
-        let upload_path = Path::new(upload_string.as_str());
-        let full_path = upload_path.join(directory.as_str());
+    ) -> Result<Vec<ListingEntry>> {
+        let full_path = LocalFiles::new(&configuration.upload_path)
+            .directory(subdirectory.as_deref())
+            .map_err(file_error)?;
+
+        Api::_list_directory(
+            full_path,
+            &LocationCategory::Local,
+            |f| f.extension().and_then(OsStr::to_str).eq(&Some("sl1")),
+            options,
+            metadata_cache,
+            configuration,
+        )
+    }
 
+    /// List the print files and subdirectories in the given directory,
+    /// filtered and sorted
+    fn _list_directory(
+        full_path: PathBuf,
+        location: &LocationCategory,
+        is_print_file: impl Fn(&Path) -> bool,
+        options: &ListingOptions,
+        metadata_cache: &MetadataCache,
+        configuration: &ApiConfig,
+    ) -> Result<Vec<ListingEntry>> {
+        let root = Api::get_location_root(configuration, location)?;
         let read_dir = full_path.read_dir();
 
-        let files_vec = read_dir
+        let entries = read_dir
             .map_err(InternalServerError)?
             .flatten()
+            .filter(|f| !f.file_name().to_string_lossy().starts_with('.'))
             .map(|f| f.path())
-            // TODO add sorting here
-            .filter(|f| f.is_dir() || f.extension().and_then(OsStr::to_str).eq(&Some("sl1")));
-
-        let chunks = files_vec.chunks(page_size);
-
-        let mut chunks_iterator = chunks.into_iter();
-
-        let paths = chunks_iterator
-            .nth(page_index)
-            .map_or(Vec::new(), |dirs| dirs.collect_vec());
-
-        let dirs = paths
-            .iter()
-            .filter(|f| f.is_dir())
-            .flat_map(|f| {
-                Api::_get_filedata(f.clone(), &LocationCategory::Local, configuration).ok()
-            })
-            .collect_vec();
-        let files = paths
-            .iter()
-            .filter(|f| !f.is_dir())
+            .filter(|f| f.is_dir() || is_print_file(f))
+            // Symlinks leading out of the location are hidden
+            .filter(|f| paths::is_within(&root, f))
             .flat_map(|f| {
-                Api::_get_print_metadata(f.clone(), &LocationCategory::Local, configuration).ok()
+                if f.is_dir() {
+                    Api::_get_filedata(f, location, configuration)
+                        .map(ListingEntry::Directory)
+                        .ok()
+                } else {
+                    Api::_get_print_metadata(f.clone(), location, metadata_cache, configuration)
+                        .map(ListingEntry::PrintFile)
+                        .or_else(|_| {
+                            Api::_get_filedata(f, location, configuration)
+                                .map(ListingEntry::Invalid)
+                        })
+                        .ok()
+                }
             })
             .collect_vec();
 
-        let next_index = Some(page_index + 1).filter(|_| chunks_iterator.next().is_some());
+        Ok(options.apply(entries))
+    }
 
-        Ok(Json(FilesResponse {
-            files,
-            dirs,
+    fn _files_response(entries: Vec<ListingEntry>, next_index: Option<usize>) -> FilesResponse {
+        let mut response = FilesResponse {
+            files: Vec::new(),
+            dirs: Vec::new(),
+            invalid_files: Vec::new(),
             next_index,
-        }))
+        };
+
+        for entry in entries {
+            match entry {
+                ListingEntry::Directory(file_data) => response.dirs.push(file_data),
+                ListingEntry::PrintFile(metadata) => response.files.push(metadata),
+                ListingEntry::Invalid(file_data) => response.invalid_files.push(file_data),
+            }
+        }
+        response
     }
 
+    // At the top level, each mounted drive is listed as a directory
     fn _get_usb_files(
-        _page_index: usize,
-        _page_size: usize,
-        _configuration: &ApiConfig,
-    ) -> Result<Json<FilesResponse>> {
-        Err(NotImplemented(MethodNotAllowedError))
+        subdirectory: Option<String>,
+        options: &ListingOptions,
+        metadata_cache: &MetadataCache,
+        configuration: &ApiConfig,
+    ) -> Result<Vec<ListingEntry>> {
+        let drives = UsbDrives::new(&configuration.usb_glob).map_err(InternalServerError)?;
+
+        match subdirectory.filter(|directory| !directory.is_empty()) {
+            Some(directory) => Api::_list_directory(
+                Api::get_usb_file_path(configuration, &directory)?,
+                &LocationCategory::Usb,
+                |f| drives.is_print_file(f),
+                options,
+                metadata_cache,
+                configuration,
+            ),
+            None => {
+                let mounts = Api::_get_usb_mounts(&drives, configuration)
+                    .into_iter()
+                    .map(ListingEntry::Directory)
+                    .collect();
+
+                Ok(options.apply(mounts))
+            }
+        }
+    }
 
-        /*
-        poem::web::Json(glob(&configuration.usb_glob)
-            .expect("Failed to read glob pattern")
-            .map(|result| result.expect("Error reading path"))
-            .map(|path| path.into_os_string().into_string().expect("Error parsing path"))
-            .collect_vec())
-        */
+    fn _get_usb_mounts(drives: &UsbDrives, configuration: &ApiConfig) -> Vec<FileMetadata> {
+        drives
+            .mounts()
+            .iter()
+            .flat_map(|mount| {
+                Api::_get_filedata(
+                    drives.root().join(mount),
+                    &LocationCategory::Usb,
+                    configuration,
+                )
+                .ok()
+            })
+            .collect_vec()
     }
 
     fn get_file_path(
@@ -368,31 +700,44 @@ impl Api {
         }
     }
 
-    // Since USB paths are specified as a glob, find all and filter to file_name
-    fn get_usb_file_path(configuration: &ApiConfig, file_name: &str) -> Result<PathBuf> {
-        let paths = glob(&configuration.usb_glob).map_err(InternalServerError)?;
-
-        let path_buf = paths
-            .filter_map(|path| path.ok())
-            .find(|path| path.ends_with(file_name))
-            .ok_or(InternalServerError(Error::new(
-                ErrorKind::NotFound,
-                "Unable to find USB file",
-            )))?;
+    // USB paths start with the name of the drive they're on
+    fn get_usb_file_path(configuration: &ApiConfig, file_path: &str) -> Result<PathBuf> {
+        UsbDrives::new(&configuration.usb_glob)
+            .and_then(|drives| drives.resolve(file_path))
+            .map_err(file_error)
+    }
 
-        Ok(path_buf)
+    /// Directory which file paths in the given location are relative to
+    fn get_location_root(
+        configuration: &ApiConfig,
+        location: &LocationCategory,
+    ) -> Result<PathBuf> {
+        match location {
+            LocationCategory::Local => Ok(PathBuf::from(&configuration.upload_path)),
+            LocationCategory::Usb => UsbDrives::new(&configuration.usb_glob)
+                .map(|drives| drives.root().to_path_buf())
+                .map_err(InternalServerError),
+        }
     }
 
-    // For Local files, look directly for specific file
     fn get_local_file_path(configuration: &ApiConfig, file_path: &str) -> Result<PathBuf> {
-        let path = Path::new(&configuration.upload_path).join(file_path);
+        LocalFiles::new(&configuration.upload_path)
+            .resolve(file_path)
+            .map_err(file_error)
+    }
 
-        path.exists()
-            .then_some(path)
-            .ok_or(InternalServerError(Error::new(
-                ErrorKind::NotFound,
-                "Unable to find local file",
-            )))
+    fn _preview_response(
+        preview: Preview,
+        max_size: Option<u32>,
+        name: String,
+    ) -> Result<Attachment<Vec<u8>>> {
+        let preview = match max_size {
+            Some(max_size) => preview.downscale(max_size),
+            None => preview,
+        };
+        let data = preview.to_png().map_err(InternalServerError)?;
+
+        Ok(Attachment::new(data).filename(name))
     }
 
     fn _get_filedata(
@@ -410,10 +755,11 @@ impl Api {
 
         let file_size = target_file.metadata().ok().map(|meta| meta.size());
 
-        // TODO handle USB _get_filedata
+        let root = Api::get_location_root(configuration, location)?;
+
         Ok(FileMetadata {
             path: target_file
-                .strip_prefix(configuration.upload_path.as_str())
+                .strip_prefix(&root)
                 .map_err(InternalServerError)?
                 .to_str()
                 .map(|path_str| path_str.to_string())
@@ -432,19 +778,19 @@ impl Api {
             last_modified: modified_time,
             file_size,
             location_category: location.clone(),
-            parent_path: configuration.upload_path.clone(),
+            parent_path: root.to_string_lossy().to_string(),
         })
     }
 
     fn _get_print_metadata(
         target_file: PathBuf,
         location: &LocationCategory,
+        metadata_cache: &MetadataCache,
         configuration: &ApiConfig,
     ) -> Result<PrintMetadata> {
         let file_data = Api::_get_filedata(target_file, location, configuration)?;
-        log::info!("Extracting print metadata");
 
-        Ok(Sl1::from_file(file_data).get_metadata())
+        metadata_cache.print_metadata(file_data).map_err(file_error)
     }
 
     #[oai(path = "/file", method = "get")]
@@ -484,6 +830,7 @@ impl Api {
         Query(file_path): Query<String>,
         Query(location): Query<Option<LocationCategory>>,
         Data(configuration): Data<&ApiConfig>,
+        Data(metadata_cache): Data<&MetadataCache>,
     ) -> Result<Json<PrintMetadata>> {
         let location = location.unwrap_or(LocationCategory::Local);
 
@@ -494,37 +841,219 @@ impl Api {
         );
         let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
 
-        Ok(Json(Api::_get_print_metadata(
-            full_file_path,
-            &location,
-            configuration,
-        )?))
+        let metadata =
+            Api::_get_print_metadata(full_file_path, &location, metadata_cache, configuration)?;
+        metadata_cache.save_or_log();
+
+        Ok(Json(metadata))
+    }
+
+    /// Measure a print from its layers: the lit area and islands of each
+    /// layer, its bounding box, volume and largest cross-section. Areas and
+    /// sizes in mm need the display's `pixel_pitch` to be configured.
+    #[oai(path = "/file/analysis", method = "get")]
+    async fn get_file_analysis(
+        &self,
+        Query(file_path): Query<String>,
+        Query(location): Query<Option<LocationCategory>>,
+        Data(full_config): Data<&Configuration>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(metadata_cache): Data<&MetadataCache>,
+    ) -> Result<Json<PrintAnalysis>> {
+        let location = location.unwrap_or(LocationCategory::Local);
+
+        log::info!("Analysing {:?} in {:?}", file_path, location);
+        let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
+        let file_data = Api::_get_filedata(full_file_path, &location, configuration)?;
+
+        let analysis = metadata_cache
+            .analysis(file_data)
+            .await
+            .map_err(file_error)?;
+        metadata_cache.save_or_log();
+
+        Ok(Json(analysis::with_pixel_pitch(
+            analysis,
+            full_config.display.pixel_pitch,
+        )))
     }
 
+    /// Get a print file's thumbnail. Files without one get a thumbnail of
+    /// their silhouette instead.
     #[oai(path = "/file/thumbnail", method = "get")]
+    #[allow(clippy::too_many_arguments)]
     async fn get_thumbnail(
         &self,
         Query(file_path): Query<String>,
         Query(location): Query<Option<LocationCategory>>,
         Query(size): Query<Option<ThumbnailSize>>,
+        /// Width to scale the thumbnail to, instead of that of `size`
+        Query(width): Query<Option<u32>>,
+        /// Height to scale the thumbnail to, instead of that of `size`
+        Query(height): Query<Option<u32>>,
         Data(configuration): Data<&ApiConfig>,
+        Data(metadata_cache): Data<&MetadataCache>,
     ) -> Result<Attachment<Vec<u8>>> {
         let location = location.unwrap_or(LocationCategory::Local);
         let size = size.unwrap_or(ThumbnailSize::Small);
 
+        // Given only one dimension, the thumbnail is square
+        let dimensions = match (width, height) {
+            (None, None) => None,
+            (width, height) => Some((
+                width.or(height).unwrap_or_default(),
+                height.or(width).unwrap_or_default(),
+            )),
+        };
+
         log::info!("Getting thumbnail from {:?} in {:?}", file_path, location);
         let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
 
         let file_metadata = Api::_get_filedata(full_file_path, &location, configuration)?;
         log::info!("Extracting print thumbnail");
 
-        let file_data = Sl1::from_file(file_metadata)
-            .get_thumbnail(size)
-            .map_err(InternalServerError)?;
+        let file_data = metadata_cache
+            .thumbnail(file_metadata, size, dimensions)
+            .await
+            .map_err(file_error)?;
+        metadata_cache.save_or_log();
 
         Ok(Attachment::new(file_data.data).filename(file_data.name))
     }
 
+    /// Render a layer of a print file as a PNG, as it would be shown on the
+    /// display
+    #[oai(path = "/file/layer", method = "get")]
+    async fn get_layer_preview(
+        &self,
+        Query(file_path): Query<String>,
+        Query(location): Query<Option<LocationCategory>>,
+        Query(layer): Query<usize>,
+        /// Largest width or height of the image, which is downscaled to fit
+        Query(max_size): Query<Option<u32>>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(layer_previews): Data<&LayerPreviews>,
+    ) -> Result<Attachment<Vec<u8>>> {
+        let location = location.unwrap_or(LocationCategory::Local);
+
+        log::info!(
+            "Rendering layer {} of {:?} in {:?}",
+            layer,
+            file_path,
+            location
+        );
+        let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
+        let file_data = Api::_get_filedata(full_file_path.clone(), &location, configuration)?;
+
+        let preview = layer_previews
+            .run((full_file_path, layer), move || {
+                let mut print_file = Sl1::from_file(file_data)?;
+                block_on(preview::layer_preview(&mut print_file, layer))
+            })
+            .await
+            .map_err(file_error)?;
+
+        Api::_preview_response(preview, max_size, format!("layer{:05}.png", layer))
+    }
+
+    /// Render a side view of a print file as a PNG, with a row for each
+    /// layer. Given a position, the print is cut through that row or column
+    /// of its layers, otherwise its outline is shown.
+    #[oai(path = "/file/cross_section", method = "get")]
+    #[allow(clippy::too_many_arguments)]
+    async fn get_cross_section(
+        &self,
+        Query(file_path): Query<String>,
+        Query(location): Query<Option<LocationCategory>>,
+        Query(axis): Query<Option<CrossSectionAxis>>,
+        Query(position): Query<Option<u32>>,
+        /// Largest width or height of the image, which is downscaled to fit
+        Query(max_size): Query<Option<u32>>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(cross_sections): Data<&CrossSections>,
+    ) -> Result<Attachment<Vec<u8>>> {
+        let location = location.unwrap_or(LocationCategory::Local);
+        let axis = axis.unwrap_or(CrossSectionAxis::X);
+
+        log::info!(
+            "Rendering {:?} cross-section of {:?} in {:?}",
+            axis,
+            file_path,
+            location
+        );
+        let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
+        let file_data = Api::_get_filedata(full_file_path.clone(), &location, configuration)?;
+
+        let preview = cross_sections
+            .run((full_file_path, axis, position), move || {
+                let mut print_file = Sl1::from_file(file_data)?;
+                block_on(preview::cross_section(&mut print_file, axis, position))
+            })
+            .await
+            .map_err(file_error)?;
+
+        Api::_preview_response(preview, max_size, "cross_section.png".to_string())
+    }
+
+    /// Copy a file from a USB drive into local storage, optionally into the
+    /// given subdirectory
+    #[oai(path = "/file/copy_to_local", method = "post")]
+    async fn copy_to_local(
+        &self,
+        Query(file_path): Query<String>,
+        Query(subdirectory): Query<Option<String>>,
+        Data(configuration): Data<&ApiConfig>,
+    ) -> Result<Json<FileMetadata>> {
+        log::info!("Copying USB file {:?} to local storage", file_path);
+
+        let usb_file_path = Api::get_usb_file_path(configuration, &file_path)?;
+
+        if usb_file_path.is_dir() {
+            return Err(BadRequest(GetDataError("Can only copy files")));
+        }
+        let file_name = usb_file_path
+            .file_name()
+            .ok_or(BadRequest(GetDataError("Can only copy files")))?;
+        let local_file_path = LocalFiles::new(&configuration.upload_path)
+            .destination(
+                &Path::new(&subdirectory.unwrap_or_default())
+                    .join(file_name)
+                    .to_string_lossy(),
+            )
+            .map_err(file_error)?;
+
+        fs::copy(&usb_file_path, &local_file_path)
+            .await
+            .map_err(InternalServerError)?;
+
+        Ok(Json(Api::_get_filedata(
+            local_file_path,
+            &LocationCategory::Local,
+            configuration,
+        )?))
+    }
+
+    /// Currently mounted USB drives
+    #[oai(path = "/usb/mounts", method = "get")]
+    async fn get_usb_mounts(
+        &self,
+        Data(configuration): Data<&ApiConfig>,
+    ) -> Result<Json<Vec<FileMetadata>>> {
+        let drives = UsbDrives::new(&configuration.usb_glob).map_err(InternalServerError)?;
+
+        Ok(Json(Api::_get_usb_mounts(&drives, configuration)))
+    }
+
+    /// Stream of USB drives being mounted and unmounted
+    #[oai(path = "/usb/events", method = "get")]
+    async fn usb_events(
+        &self,
+        Data(usb_sender): Data<&broadcast::Sender<UsbEvent>>,
+    ) -> EventStream<BoxStream<'static, UsbEvent>> {
+        EventStream::new(broadcast_stream(usb_sender.subscribe()))
+            .keep_alive(Duration::from_secs(15))
+    }
+
     #[oai(path = "/file", method = "delete")]
     async fn delete_file(
         &self,
@@ -537,6 +1066,16 @@ impl Api {
 
         let full_file_path = Api::get_file_path(configuration, &file_path, &location)?;
 
+        // Neither the upload directory nor a whole USB drive can be deleted
+        let root = Api::get_location_root(configuration, &location)?;
+        let location_root = match location {
+            LocationCategory::Local => full_file_path == root,
+            LocationCategory::Usb => full_file_path.parent() == Some(root.as_path()),
+        };
+        if location_root {
+            return Err(BadRequest(GetDataError("Can't delete a location's root")));
+        }
+
         let metadata = Api::_get_filedata(full_file_path.clone(), &location, configuration)?;
 
         if full_file_path.is_dir() {
@@ -551,6 +1090,188 @@ impl Api {
 
         Ok(Json(metadata))
     }
+
+    /// Create a local directory
+    #[oai(path = "/files/directory", method = "post")]
+    async fn create_directory(
+        &self,
+        Query(path): Query<String>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(local_files): Data<&LocalFiles>,
+    ) -> Result<Json<FileMetadata>> {
+        log::info!("Creating directory {:?}", path);
+
+        let full_path = local_files
+            .create_directory(&path)
+            .await
+            .map_err(file_error)?;
+
+        Ok(Json(Api::_get_filedata(
+            full_path,
+            &LocationCategory::Local,
+            configuration,
+        )?))
+    }
+
+    /// Move a local file or directory to a new path
+    #[oai(path = "/file/move", method = "post")]
+    async fn move_file(
+        &self,
+        Query(file_path): Query<String>,
+        Query(destination): Query<String>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(local_files): Data<&LocalFiles>,
+    ) -> Result<Json<FileMetadata>> {
+        log::info!("Moving {:?} to {:?}", file_path, destination);
+
+        let full_path = local_files
+            .move_file(&file_path, &destination)
+            .await
+            .map_err(file_error)?;
+
+        Ok(Json(Api::_get_filedata(
+            full_path,
+            &LocationCategory::Local,
+            configuration,
+        )?))
+    }
+
+    /// Rename a local file or directory, keeping it in the same directory
+    #[oai(path = "/file/rename", method = "post")]
+    async fn rename_file(
+        &self,
+        Query(file_path): Query<String>,
+        Query(name): Query<String>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(local_files): Data<&LocalFiles>,
+    ) -> Result<Json<FileMetadata>> {
+        log::info!("Renaming {:?} to {:?}", file_path, name);
+
+        let full_path = local_files
+            .rename(&file_path, &name)
+            .await
+            .map_err(file_error)?;
+
+        Ok(Json(Api::_get_filedata(
+            full_path,
+            &LocationCategory::Local,
+            configuration,
+        )?))
+    }
+
+    /// Copy a local file to a new path
+    #[oai(path = "/file/copy", method = "post")]
+    async fn copy_file(
+        &self,
+        Query(file_path): Query<String>,
+        Query(destination): Query<String>,
+        Data(configuration): Data<&ApiConfig>,
+        Data(local_files): Data<&LocalFiles>,
+    ) -> Result<Json<FileMetadata>> {
+        log::info!("Copying {:?} to {:?}", file_path, destination);
+
+        let full_path = local_files
+            .copy_file(&file_path, &destination)
+            .await
+            .map_err(file_error)?;
+
+        Ok(Json(Api::_get_filedata(
+            full_path,
+            &LocationCategory::Local,
+            configuration,
+        )?))
+    }
+}
+
+/// Stream the messages from a broadcast channel, skipping any missed
+fn broadcast_stream<T: Clone + Send + 'static>(
+    receiver: broadcast::Receiver<T>,
+) -> BoxStream<'static, T> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => return Some((message, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+fn file_error(error: Error) -> poem::Error {
+    match error.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => BadRequest(error),
+        ErrorKind::PermissionDenied => Unauthorized(MethodNotAllowedError),
+        ErrorKind::NotFound => NotFound(error),
+        ErrorKind::AlreadyExists | ErrorKind::ResourceBusy => Conflict(error),
+        _ => InternalServerError(error),
+    }
+}
+
+/// Console over a websocket. Each text message received is run as a gcode
+/// command, and answered with its `ConsoleResponse` as JSON.
+#[handler]
+async fn console_websocket(
+    websocket: WebSocket,
+    Data(operation_sender): Data<&mpsc::Sender<Operation>>,
+    Data(console_sender): Data<&broadcast::Sender<ConsoleResponse>>,
+    Data(state_ref): Data<&Arc<RwLock<PrinterState>>>,
+    Data(cancellation_token): Data<&CancellationToken>,
+) -> impl IntoResponse {
+    let operation_sender = operation_sender.clone();
+    let mut console_receiver = console_sender.subscribe();
+    let state_ref = state_ref.clone();
+    let cancellation_token = cancellation_token.clone();
+
+    websocket.on_upgrade(move |mut socket| async move {
+        let mut pending = HashSet::new();
+
+        loop {
+            let response = tokio::select! {
+                message = socket.next() => match message {
+                    Some(Ok(Message::Text(command))) => {
+                        let id = next_command_id();
+
+                        if let Err(e) = check_console_available(&state_ref).await {
+                            Some(ConsoleResponse {
+                                id,
+                                command,
+                                lines: Vec::new(),
+                                error: Some(e.to_string()),
+                            })
+                        } else if operation_sender
+                            .send(Operation::ManualCommand { command, id })
+                            .await
+                            .is_ok()
+                        {
+                            pending.insert(id);
+                            None
+                        } else {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => None,
+                },
+                response = console_receiver.recv() => match response {
+                    Ok(response) => pending.remove(&response.id).then_some(response),
+                    Err(broadcast::error::RecvError::Lagged(_)) => None,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = cancellation_token.cancelled() => break,
+            };
+
+            if let Some(response) = response {
+                let Ok(text) = serde_json::to_string(&response) else {
+                    continue;
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    })
 }
 
 async fn run_state_listener(
@@ -576,24 +1297,61 @@ pub async fn start_api(
     full_config: Configuration,
     operation_sender: mpsc::Sender<Operation>,
     state_receiver: broadcast::Receiver<PrinterState>,
+    console_sender: broadcast::Sender<ConsoleResponse>,
+    traffic_log: TrafficLog,
     cancellation_token: CancellationToken,
 ) {
     let state_ref = Arc::new(RwLock::new(PrinterState {
         print_data: None,
         paused: None,
         layer: None,
+        movement: None,
+        error: None,
         physical_state: PhysicalState {
             z: 0.0,
             z_microns: 0,
             curing: false,
+            homed: false,
         },
         status: PrinterStatus::Shutdown,
+        connection: ConnectionStatus::Disconnected,
     }));
 
     let configuration = full_config.api.clone();
 
     tokio::spawn(run_state_listener(state_receiver, state_ref.clone()));
 
+    let (usb_sender, _) = broadcast::channel::<UsbEvent>(20);
+    match UsbDrives::new(&configuration.usb_glob) {
+        Ok(drives) => {
+            tokio::spawn(usb::watch_mounts(
+                drives,
+                usb_sender.clone(),
+                cancellation_token.clone(),
+            ));
+        }
+        Err(e) => log::error!("Invalid usb_glob {}: {}", configuration.usb_glob, e),
+    }
+
+    let metadata_cache = MetadataCache::new(Some(
+        configuration
+            .metadata_cache
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&configuration.upload_path).join(".metadata_cache")),
+    ));
+    tokio::spawn(metadata_cache::watch_files(
+        metadata_cache.clone(),
+        PathBuf::from(&configuration.upload_path),
+        cancellation_token.clone(),
+    ));
+
+    let uploads = Uploads::new(&configuration.upload_path);
+    tokio::spawn(upload::expire_sessions(
+        uploads.clone(),
+        cancellation_token.clone(),
+    ));
+
     let port = configuration.port.to_string();
     let addr = format!("0.0.0.0:{port}");
 
@@ -601,7 +1359,9 @@ pub async fn start_api(
 
     let ui = api_service.swagger_ui();
 
-    let mut app = Route::new().nest("/", api_service);
+    let mut app = Route::new()
+        .at("/console/ws", get(console_websocket))
+        .nest("/", api_service);
 
     if cfg!(debug_assertions) {
         app = app.nest("/docs", ui);
@@ -612,6 +1372,15 @@ pub async fn start_api(
         .data(state_ref.clone())
         .data(full_config.clone())
         .data(configuration.clone())
+        .data(traffic_log)
+        .data(console_sender)
+        .data(usb_sender)
+        .data(uploads)
+        .data(LocalFiles::new(&configuration.upload_path))
+        .data(metadata_cache)
+        .data(CrossSections::default())
+        .data(LayerPreviews::default())
+        .data(cancellation_token.clone())
         .with(Cors::new());
 
     Server::new(TcpListener::bind(addr))
@@ -33,17 +33,110 @@ pub struct PrintMetadata {
     pub layer_count: usize,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Enum)]
+/// Pixel bounds of everything lit in any layer of a print, inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct BoundingBox {
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct LayerAnalysis {
+    pub layer: usize,
+    /// Lit pixels, with anti-aliased pixels counting in proportion to their
+    /// grey level
+    pub pixel_area: f64,
+    /// Lit area in mm², if the display's pixel pitch is configured
+    pub area: Option<f64>,
+    /// Separate regions of lit pixels
+    pub islands: usize,
+    /// Islands with nothing lit beneath them in the previous layer
+    pub unsupported_islands: usize,
+}
+
+/// Measurements of a print, taken from its layers rather than the slicer
+#[derive(Clone, Debug, Serialize, Deserialize, Object)]
+pub struct PrintAnalysis {
+    pub file_data: FileMetadata,
+    pub layer_height: f64,
+    /// Size of a display pixel in mm, which physical measurements need
+    pub pixel_pitch: Option<f64>,
+    pub layers: Vec<LayerAnalysis>,
+    pub bounding_box: Option<BoundingBox>,
+    /// Size of the bounding box in mm
+    pub width: Option<f64>,
+    pub depth: Option<f64>,
+    pub height: f64,
+    /// Resin used in ml, comparable to `PrintMetadata::used_material`
+    pub volume: Option<f64>,
+    /// Layer with the largest cross-section, and so the greatest peel force
+    pub largest_layer: Option<usize>,
+    pub unsupported_islands: usize,
+}
+
+/// Property to sort file listings by. Directories and invalid files have no
+/// print time or layer count, so are sorted by name instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, Enum)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Modified,
+    Size,
+    PrintTime,
+    LayerCount,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, Enum)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Axis a cross-section preview is viewed along. `X` shows the print from
+/// the front, with the layers' x axis across the image, and `Y` from the side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Enum)]
+pub enum CrossSectionAxis {
+    X,
+    Y,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Enum)]
 pub enum ThumbnailSize {
     Large,
     Small,
 }
 
+impl ThumbnailSize {
+    /// Width and height of thumbnails of this size
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            ThumbnailSize::Large => (800, 480),
+            ThumbnailSize::Small => (400, 400),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, Object)]
 pub struct PhysicalState {
     pub z: f64,
     pub z_microns: u32,
     pub curing: bool,
+    /// Whether the plate has been homed since boot, so its position is known
+    pub homed: bool,
+}
+
+/// Lift move used for the layer being printed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct LayerMovement {
+    pub lift: f64,
+    pub lift_microns: u32,
+    pub up_speed: f64,
+    pub down_speed: f64,
+    /// Lit area of the layer in mm², when the lift was picked by area
+    pub area: Option<f64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
@@ -51,8 +144,74 @@ pub struct PrinterState {
     pub print_data: Option<PrintMetadata>,
     pub paused: Option<bool>,
     pub layer: Option<usize>,
+    pub movement: Option<LayerMovement>,
+    /// Why the current or last print was interrupted, if it was
+    pub error: Option<String>,
     pub physical_state: PhysicalState,
     pub status: PrinterStatus,
+    pub connection: ConnectionStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Enum)]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct ConsoleResponse {
+    /// Identifies the command this is the response to
+    pub id: u64,
+    pub command: String,
+    /// Lines received from the firmware while running the command
+    pub lines: Vec<String>,
+    /// Reason the command failed, if it did
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct UploadSession {
+    pub id: String,
+    pub file_name: String,
+    /// Bytes received so far, and so the offset of the next chunk
+    pub received: u64,
+    pub total_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Enum)]
+pub enum UploadState {
+    InProgress,
+    Complete,
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct UploadProgress {
+    pub file_name: String,
+    pub received: u64,
+    pub total_size: Option<u64>,
+    pub state: UploadState,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct UsbEvent {
+    /// Name of the drive, as used at the start of its files' paths
+    pub mount: String,
+    pub mounted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Enum)]
+pub enum TrafficDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct TrafficEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    pub direction: TrafficDirection,
+    pub line: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Enum)]
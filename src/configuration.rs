@@ -1,7 +1,11 @@
+use std::io::{Error, ErrorKind};
+
 use config::{Config, ConfigError, Environment, File};
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 
+use crate::template::{Template, TemplateError};
+
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
 pub struct PrinterConfig {
     pub serial: String,
@@ -13,6 +17,68 @@ pub struct PrinterConfig {
     pub default_wait_before_exposure: f64,
     pub default_wait_after_exposure: f64,
     pub pause_lift: f64,
+    /// Push each layer to the LCD as soon as the plate is in position,
+    /// rather than only once the pre-exposure wait is over
+    pub early_layer_display: Option<bool>,
+    /// How Odyssey talks to the printer's firmware, defaults to `Serial`
+    pub backend: Option<HardwareBackend>,
+    /// Lift distance and speeds picked by each layer's lit area, in place of
+    /// those of the print file. Needs the display's `pixel_pitch`.
+    pub lift_curve: Option<Vec<LiftCurvePoint>>,
+}
+
+/// Lift for layers of a given lit area, with layers between points getting
+/// a lift interpolated between theirs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct LiftCurvePoint {
+    /// Lit layer area in mm²
+    pub area: f64,
+    /// Lift distance in mm
+    pub lift: f64,
+    pub up_speed: f64,
+    pub down_speed: f64,
+}
+
+impl PrinterConfig {
+    /// Check the lift curve's areas and lifts are non-negative, and its
+    /// speeds positive, so every layer gets a usable lift
+    pub fn validate(&self) -> std::io::Result<()> {
+        for (index, point) in self.lift_curve.iter().flatten().enumerate() {
+            let non_negative = |value: f64| value.is_finite() && value >= 0.0;
+            let positive = |value: f64| value.is_finite() && value > 0.0;
+
+            let invalid = [
+                ("area", "non-negative", non_negative(point.area)),
+                ("lift", "non-negative", non_negative(point.lift)),
+                ("up_speed", "positive", positive(point.up_speed)),
+                ("down_speed", "positive", positive(point.down_speed)),
+            ]
+            .into_iter()
+            .find(|(_, _, valid)| !valid);
+
+            if let Some((field, requirement, _)) = invalid {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "printer.lift_curve[{}].{} must be a {} number",
+                        index, field, requirement
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Enum)]
+pub enum HardwareBackend {
+    /// Gcode sent line by line over the configured serial port
+    Serial,
+    /// Gcode scripts sent to Klipper through Moonraker's websocket API
+    Moonraker,
+    /// Hardware modelled in-process, for running without a board attached
+    Simulated,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
@@ -21,6 +87,15 @@ pub struct DisplayConfig {
     pub bit_depth: Vec<u8>,
     pub screen_width: u32,
     pub screen_height: u32,
+    /// Gamma exponent applied to layer grey levels
+    pub gamma: Option<f64>,
+    /// Grey level response curve, as output levels evenly spaced across the
+    /// input range and linearly interpolated between
+    pub grey_curve: Option<Vec<u8>>,
+    /// Layer grey levels below this threshold are switched off entirely
+    pub min_grey: Option<u8>,
+    /// Size of a display pixel in mm, for measuring prints
+    pub pixel_pitch: Option<f64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
@@ -38,6 +113,140 @@ pub struct GcodeConfig {
     pub move_timeout: usize,
     pub status_check: String,
     pub status_desired: String,
+    /// Response the firmware sends once it has processed each line, such as
+    /// `ok`. If unset, a fixed delay is used between lines instead
+    pub ack_response: Option<String>,
+    /// Patterns which identify a response as a firmware error
+    pub error_patterns: Option<Vec<String>>,
+    /// Seconds to wait for each line to be acknowledged, defaults to
+    /// `move_timeout`
+    pub response_timeout: Option<usize>,
+    /// Line protocol used when talking to the firmware, defaults to `Plain`
+    pub protocol: Option<GcodeProtocol>,
+    /// Gcode which reports the plate's actual position, such as `M114`. If
+    /// unset, the plate is assumed to reach each commanded position
+    pub position_query: Option<String>,
+    /// Pattern extracting the Z position from the position query response,
+    /// as the named group `z`
+    pub position_pattern: Option<String>,
+}
+
+/// Variables available to every gcode template
+const STATE_VARIABLES: [&str; 4] = ["curing", "z", "max_z", "z_lift"];
+/// Print metadata variables, available to templates run as part of a print
+pub const METADATA_VARIABLES: [&str; 4] =
+    ["file_name", "layer_height", "print_time", "used_material"];
+
+impl GcodeConfig {
+    /// Check that every template parses, and only requires variables which
+    /// are available wherever it is run
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        for (name, source, variables) in self.templates() {
+            let template = Template::parse(source)
+                .map_err(|error| TemplateError::Gcode(name.to_string(), Box::new(error)))?;
+
+            if let Some(missing) = template.required_variables().into_iter().find(|variable| {
+                !STATE_VARIABLES.contains(&variable.as_str())
+                    && !variables.contains(&variable.as_str())
+            }) {
+                return Err(TemplateError::Gcode(
+                    name.to_string(),
+                    Box::new(TemplateError::MissingVariable(missing)),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Source of every configured gcode template
+    pub fn template_sources(&self) -> impl Iterator<Item = &str> {
+        self.templates()
+            .into_iter()
+            .map(|(_, source, _)| source.as_str())
+    }
+
+    /// Every configured gcode template, by name, along with the variables
+    /// available to it beyond the state variables
+    fn templates(&self) -> Vec<(&'static str, &String, Vec<&'static str>)> {
+        let print_variables = [METADATA_VARIABLES.as_slice(), &["total_layers"]].concat();
+
+        let templates = [
+            ("boot", Some(&self.boot), vec![]),
+            ("shutdown", Some(&self.shutdown), vec![]),
+            ("home_command", Some(&self.home_command), vec![]),
+            ("move_command", Some(&self.move_command), vec!["speed"]),
+            (
+                "print_start",
+                Some(&self.print_start),
+                print_variables.clone(),
+            ),
+            ("print_end", Some(&self.print_end), print_variables.clone()),
+            (
+                "layer_start",
+                Some(&self.layer_start),
+                [print_variables.as_slice(), &["layer"]].concat(),
+            ),
+            ("cure_start", Some(&self.cure_start), vec![]),
+            ("cure_end", Some(&self.cure_end), vec![]),
+            ("status_check", Some(&self.status_check), vec![]),
+            ("position_query", self.position_query.as_ref(), vec![]),
+        ];
+
+        templates
+            .into_iter()
+            .filter_map(|(name, source, variables)| Some((name, source?, variables)))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Enum)]
+pub enum GcodeProtocol {
+    /// Lines are sent as they are, as expected by Klipper's pseudo-tty
+    Plain,
+    /// Lines are numbered and checksummed, with resends on request, for
+    /// Marlin-based boards connected directly over serial
+    Marlin,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Object)]
+pub struct MoonrakerConfig {
+    /// Websocket URL of the Moonraker API
+    pub url: String,
+}
+
+impl Default for MoonrakerConfig {
+    fn default() -> Self {
+        MoonrakerConfig {
+            url: String::from("ws://localhost:7125/websocket"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Object)]
+pub struct SimulationConfig {
+    /// Multiplier applied to the real duration of plate moves, 0 to move
+    /// instantly. Defaults to 1
+    pub time_scale: Option<f64>,
+    /// Number of readiness checks reported as not ready after startup
+    pub startup_checks: Option<usize>,
+    /// Report not ready on every nth readiness check
+    pub flap_interval: Option<usize>,
+    /// Patterns matching gcode lines which the simulated firmware rejects
+    pub rejected_gcode: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Object)]
+pub struct TrafficLogConfig {
+    /// File to record serial traffic to. Only recent traffic is kept in
+    /// memory if unset
+    pub path: Option<String>,
+    /// Size in bytes at which the log file is rotated. Defaults to 10MB
+    pub max_size: Option<u64>,
+    /// Number of rotated log files kept. Defaults to 3
+    pub max_files: Option<usize>,
+    /// Number of recent lines kept in memory for the API. Defaults to 500
+    pub history: Option<usize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
@@ -45,6 +254,9 @@ pub struct ApiConfig {
     pub upload_path: String,
     pub usb_glob: String,
     pub port: u16,
+    /// Directory to cache print file metadata and thumbnails in, defaulting
+    /// to a hidden directory in the upload_path
+    pub metadata_cache: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Object)]
@@ -53,6 +265,9 @@ pub struct Configuration {
     pub gcode: GcodeConfig,
     pub api: ApiConfig,
     pub display: DisplayConfig,
+    pub moonraker: Option<MoonrakerConfig>,
+    pub simulation: Option<SimulationConfig>,
+    pub traffic_log: Option<TrafficLogConfig>,
 }
 
 impl Configuration {
@@ -62,6 +277,17 @@ impl Configuration {
             .add_source(Environment::with_prefix("odyssey"))
             .build()?;
 
-        s.try_deserialize()
+        let configuration: Configuration = s.try_deserialize()?;
+
+        configuration
+            .gcode
+            .validate()
+            .map_err(|error| ConfigError::Message(error.to_string()))?;
+        configuration
+            .printer
+            .validate()
+            .map_err(|error| ConfigError::Message(error.to_string()))?;
+
+        Ok(configuration)
     }
 }
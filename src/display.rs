@@ -1,8 +1,13 @@
+use std::io::{Error, ErrorKind};
+
 use framebuffer::Framebuffer;
-use png::Decoder;
+use png::{BitDepth, ColorType, Decoder, Transformations};
 
 use crate::{
-    api_objects::DisplayTest, configuration::DisplayConfig, wrapped_framebuffer::WrappedFramebuffer,
+    api_objects::DisplayTest,
+    configuration::DisplayConfig,
+    encoder::{FrameEncoder, LevelMap, PixelLayout},
+    wrapped_framebuffer::WrappedFramebuffer,
 };
 
 #[derive(Clone)]
@@ -11,79 +16,119 @@ pub struct Frame {
     pub buffer: Vec<u8>,
     pub exposure_time: f64,
     pub bit_depth: u8,
+    pub width: u32,
+    pub height: u32,
 }
 
 impl Frame {
-    pub fn from_vec(name: String, exposure_time: f64, data: Vec<u8>) -> Frame {
-        let decoder = Decoder::new(data.as_slice());
+    /// Decode a PNG layer into a frame of 8-bit luminance values, regardless
+    /// of the colour type and bit depth it was saved with
+    pub fn from_vec(name: String, exposure_time: f64, data: Vec<u8>) -> Result<Frame, Error> {
+        let mut decoder = Decoder::new(data.as_slice());
+
+        // Expand palettes and packed sub-byte pixels, and strip 16-bit samples
+        // down to 8 bits, so only the colour type remains to be normalized
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+
+        let mut png_reader = decoder
+            .read_info()
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        let mut raw_buffer = vec![0; png_reader.output_buffer_size()];
+
+        let output_info = png_reader
+            .next_frame(raw_buffer.as_mut())
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        raw_buffer.truncate(output_info.buffer_size());
 
-        let mut png_reader = decoder.read_info().expect("Unable to read PNG metadata");
+        if output_info.bit_depth != BitDepth::Eight {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unsupported PNG bit depth {:?} in layer {}",
+                    output_info.bit_depth, name
+                ),
+            ));
+        }
+
+        let buffer = match output_info.color_type {
+            ColorType::Grayscale => raw_buffer,
+            ColorType::GrayscaleAlpha => raw_buffer
+                .as_chunks::<2>()
+                .0
+                .iter()
+                .map(|[level, alpha]| Frame::apply_alpha(*level, *alpha))
+                .collect(),
+            ColorType::Rgb => raw_buffer
+                .as_chunks::<3>()
+                .0
+                .iter()
+                .map(|[red, green, blue]| Frame::luminance(*red, *green, *blue))
+                .collect(),
+            ColorType::Rgba => raw_buffer
+                .as_chunks::<4>()
+                .0
+                .iter()
+                .map(|[red, green, blue, alpha]| {
+                    Frame::apply_alpha(Frame::luminance(*red, *green, *blue), *alpha)
+                })
+                .collect(),
+            ColorType::Indexed => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unable to expand indexed PNG in layer {}", name),
+                ))
+            }
+        };
 
-        let mut f = Frame {
+        Ok(Frame {
             file_name: name,
-            buffer: vec![0; png_reader.output_buffer_size()],
+            buffer,
             exposure_time,
-            bit_depth: png_reader.info().bit_depth as u8,
-        };
+            bit_depth: 8,
+            width: output_info.width,
+            height: output_info.height,
+        })
+    }
 
-        png_reader
-            .next_frame(f.buffer.as_mut())
-            .expect("Error reading PNG");
+    /// Rec. 601 luma of an RGB pixel
+    fn luminance(red: u8, green: u8, blue: u8) -> u8 {
+        ((77 * red as u32 + 150 * green as u32 + 29 * blue as u32) >> 8) as u8
+    }
 
-        f
+    /// Composite a grey level with the given alpha over a black background
+    fn apply_alpha(level: u8, alpha: u8) -> u8 {
+        ((level as u32 * alpha as u32) / 255) as u8
     }
 }
 
 pub struct PrintDisplay {
     pub frame_buffer: WrappedFramebuffer,
     pub config: DisplayConfig,
+    encoder: FrameEncoder,
+    level_map: Option<LevelMap>,
 }
 
 impl PrintDisplay {
-    fn re_encode(&self, buffer: Vec<u8>, bit_depth: u8) -> Vec<u8> {
-        if self.config.bit_depth.len() == 1 && self.config.bit_depth[0] == bit_depth {
-            return buffer;
-        }
-
-        let chunk_size: u8 = self.config.bit_depth.iter().sum(); //8
-        let pixels_per_chunk = self.config.bit_depth.len(); //1
-        log::info!("Re-encoding frame with bit-depth {} into {} pixels in {} bits, with the following bit layout: {:?}", bit_depth, pixels_per_chunk, chunk_size, self.config.bit_depth);
-
-        let mut new_buffer: Vec<u8> = Vec::new();
-
-        buffer
-            .chunks_exact(pixels_per_chunk)
-            .for_each(|pixel_chunk| {
-                // raw binary chunk of pixels, to be broken into bytes and repacked in the Vector later
-                let mut raw_chunk = 0b0;
-                let mut pos_shift = chunk_size;
-                for i in 0..pixels_per_chunk {
-                    let depth_difference = bit_depth - self.config.bit_depth[i];
-                    pos_shift -= self.config.bit_depth[i];
-
-                    // Truncate the pixel data to the display's bit depth, then shift it into place in the raw chunk
-                    let shifted_pixel: u64 =
-                        ((pixel_chunk[i] as u64) >> depth_difference) << (pos_shift);
-                    raw_chunk |= shifted_pixel;
-                }
-
-                for i in 0..(chunk_size / 8) {
-                    // pull the raw chunk back apart into bytes, for push into the new buffer
-                    let byte = ((raw_chunk >> (8 * i)) & 0xFF) as u8;
-                    new_buffer.push(byte);
-                }
-            });
-
-        new_buffer
+    pub fn display_frame(&mut self, frame: &Frame) {
+        self.encoder.set_level_map(self.level_map);
+        self.display_bytes(&frame.buffer, frame.bit_depth);
     }
 
-    pub fn display_frame(&mut self, frame: Frame) {
-        self.display_bytes(frame.buffer, frame.bit_depth);
-    }
+    fn display_bytes(&mut self, buffer: &[u8], bit_depth: u8) {
+        if !self.encoder.is_passthrough(bit_depth) {
+            log::info!(
+                "Re-encoding frame with bit-depth {} into {} pixels in {} bits, with the following bit layout: {:?}",
+                bit_depth,
+                self.encoder.pixels_per_chunk(),
+                self.encoder.chunk_bits(),
+                self.config.bit_depth
+            );
+        }
 
-    fn display_bytes(&mut self, buffer: Vec<u8>, bit_depth: u8) {
         self.frame_buffer
-            .write_frame(&self.re_encode(buffer, bit_depth));
+            .write_frame(self.encoder.encode(buffer, bit_depth));
     }
 
     pub fn display_test(&mut self, test: DisplayTest) {
@@ -93,7 +138,14 @@ impl PrintDisplay {
             _ => self.display_test_blank(),
         };
 
-        self.display_bytes(test_bytes, 8);
+        self.encoder.set_level_map(None);
+        self.display_bytes(&test_bytes, 8);
+    }
+
+    /// Clear the display, so no image remains on the LCD
+    pub fn blank(&mut self) {
+        log::debug!("Blanking display");
+        self.display_test(DisplayTest::Blank);
     }
 
     fn display_test_white(&mut self) -> Vec<u8> {
@@ -104,15 +156,148 @@ impl PrintDisplay {
         vec![0x00; (self.config.screen_width * self.config.screen_height) as usize]
     }
 
-    pub fn new(config: DisplayConfig) -> PrintDisplay {
+    pub fn new(mut config: DisplayConfig) -> PrintDisplay {
+        let frame_buffer = Framebuffer::new(config.frame_buffer.clone()).ok();
+
+        let device_layout = frame_buffer
+            .as_ref()
+            .and_then(|fb| PrintDisplay::read_device_layout(&mut config, fb));
+
+        let encoder = match device_layout {
+            Some((layout, row_stride)) => {
+                FrameEncoder::with_layout(layout, config.screen_width as usize, row_stride)
+            }
+            None => FrameEncoder::new(config.bit_depth.clone(), config.screen_width as usize),
+        };
+
         PrintDisplay {
             frame_buffer: WrappedFramebuffer {
-                frame_buffer: Framebuffer::new(config.frame_buffer.clone()).ok(),
+                frame_buffer,
                 fb_path: config.frame_buffer.clone(),
             },
+            encoder,
+            level_map: PrintDisplay::build_level_map(&config),
             config,
         }
     }
+
+    /// Build the grey level remapping for layer pixels from the configured
+    /// threshold, gamma and response curve, if any are set
+    fn build_level_map(config: &DisplayConfig) -> Option<LevelMap> {
+        let gamma = config.gamma.filter(|gamma| {
+            let valid = gamma.is_finite() && *gamma > 0.0;
+            if !valid {
+                log::warn!("Ignoring invalid display gamma {}", gamma);
+            }
+            valid
+        });
+        let grey_curve = config.grey_curve.as_ref().filter(|curve| {
+            let valid = curve.len() >= 2;
+            if !valid {
+                log::warn!("Ignoring display grey_curve with fewer than 2 points");
+            }
+            valid
+        });
+        let min_grey = config.min_grey.filter(|min_grey| *min_grey > 0);
+
+        if gamma.is_none() && grey_curve.is_none() && min_grey.is_none() {
+            return None;
+        }
+
+        let mut level_map: LevelMap = [0; 256];
+        for (input, level) in level_map.iter_mut().enumerate() {
+            if input < min_grey.unwrap_or(0) as usize {
+                continue;
+            }
+
+            let mut value = input as f64 / 255.0;
+
+            if let Some(gamma) = gamma {
+                value = value.powf(gamma);
+            }
+
+            if let Some(curve) = grey_curve {
+                // Interpolate between the two curve points either side
+                let position = value * (curve.len() - 1) as f64;
+                let lower = (position.floor() as usize).min(curve.len() - 2);
+                let fraction = position - lower as f64;
+                value = (curve[lower] as f64 * (1.0 - fraction)
+                    + curve[lower + 1] as f64 * fraction)
+                    / 255.0;
+            }
+
+            *level = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+
+        log::debug!("Remapping layer grey levels: {:?}", level_map);
+
+        Some(level_map)
+    }
+
+    /// Query the frame buffer device for its geometry and pixel format, and
+    /// derive the pixel layout and row stride from it. The config is updated
+    /// to match the device, with a warning for any values which disagreed.
+    fn read_device_layout(
+        config: &mut DisplayConfig,
+        frame_buffer: &Framebuffer,
+    ) -> Option<(PixelLayout, usize)> {
+        let var_info = &frame_buffer.var_screen_info;
+        let fix_info = &frame_buffer.fix_screen_info;
+
+        log::info!(
+            "Frame buffer reports {}x{} at {} bits per pixel, line length {} (red {}/{}, green {}/{}, blue {}/{})",
+            var_info.xres,
+            var_info.yres,
+            var_info.bits_per_pixel,
+            fix_info.line_length,
+            var_info.red.offset,
+            var_info.red.length,
+            var_info.green.offset,
+            var_info.green.length,
+            var_info.blue.offset,
+            var_info.blue.length,
+        );
+
+        let Some(layout) = PixelLayout::from_screen_info(var_info) else {
+            log::warn!(
+                "Unable to derive pixel layout from frame buffer, using configured bit_depth {:?}",
+                config.bit_depth
+            );
+            return None;
+        };
+
+        // Each packed chunk is a single device pixel, holding several
+        // monochrome pixels
+        let screen_width = var_info.xres * layout.pixels_per_chunk() as u32;
+        let screen_height = var_info.yres;
+
+        if config.bit_depth != layout.bit_depth {
+            log::warn!(
+                "Configured bit_depth {:?} does not match frame buffer layout {:?}, using frame buffer value",
+                config.bit_depth,
+                layout.bit_depth
+            );
+            config.bit_depth = layout.bit_depth.clone();
+        }
+        if config.screen_width != screen_width {
+            log::warn!(
+                "Configured screen_width {} does not match frame buffer width {}, using frame buffer value",
+                config.screen_width,
+                screen_width
+            );
+            config.screen_width = screen_width;
+        }
+        if config.screen_height != screen_height {
+            log::warn!(
+                "Configured screen_height {} does not match frame buffer height {}, using frame buffer value",
+                config.screen_height,
+                screen_height
+            );
+            config.screen_height = screen_height;
+        }
+
+        Some((layout, fix_info.line_length as usize))
+    }
 }
 
 impl Clone for PrintDisplay {
@@ -0,0 +1,252 @@
+use std::cmp::Reverse;
+
+use framebuffer::VarScreeninfo;
+use rayon::prelude::*;
+
+/// Number of distinct values an input pixel (at most 8 bits) can take
+const TABLE_SIZE: usize = 256;
+
+/// Mapping from each 8-bit input grey level to the level actually displayed
+pub type LevelMap = [u8; TABLE_SIZE];
+
+/// Describes how monochrome pixels are packed into the chunks of the display's
+/// frame buffer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PixelLayout {
+    /// Bit depth of each pixel in a chunk, most significant pixel first
+    pub bit_depth: Vec<u8>,
+    /// Offset of each pixel's least significant bit within the chunk
+    pub offsets: Vec<u8>,
+    /// Total size of a chunk in bits, including any unused padding bits
+    pub chunk_bits: u8,
+}
+
+impl PixelLayout {
+    /// Build a layout with the given pixels packed contiguously, most
+    /// significant first, as described by the `bit_depth` display config
+    pub fn from_bit_depth(bit_depth: Vec<u8>) -> PixelLayout {
+        let chunk_bits: u8 = bit_depth.iter().sum();
+        let mut pos_shift = chunk_bits;
+        let offsets = bit_depth
+            .iter()
+            .map(|depth| {
+                pos_shift -= depth;
+                pos_shift
+            })
+            .collect();
+
+        PixelLayout {
+            bit_depth,
+            offsets,
+            chunk_bits,
+        }
+    }
+
+    /// Derive the layout from the frame buffer's reported pixel format. Each
+    /// colour channel holds a separate monochrome pixel, so the channels are
+    /// ordered by their position in the chunk, most significant first.
+    pub fn from_screen_info(info: &VarScreeninfo) -> Option<PixelLayout> {
+        // Chunks are written out a whole byte at a time, so sub-byte pixel
+        // formats are left to the configured bit depth
+        let chunk_bits = u8::try_from(info.bits_per_pixel)
+            .ok()
+            .filter(|bits| *bits > 0 && *bits <= 64 && bits % 8 == 0)?;
+
+        if info.grayscale == 1 {
+            return Some(PixelLayout {
+                bit_depth: vec![chunk_bits],
+                offsets: vec![0],
+                chunk_bits,
+            });
+        }
+
+        // Bitfields too large for a chunk can't describe a valid layout
+        let mut channels: Vec<(u8, u8)> = [&info.red, &info.green, &info.blue]
+            .iter()
+            .filter(|field| field.length > 0)
+            .map(|field| {
+                Some((
+                    u8::try_from(field.offset).ok()?,
+                    u8::try_from(field.length).ok()?,
+                ))
+            })
+            .collect::<Option<_>>()?;
+        channels.sort_by_key(|(offset, _)| Reverse(*offset));
+
+        let fits = channels
+            .iter()
+            .all(|(offset, length)| (*offset as u32 + *length as u32) <= chunk_bits as u32);
+
+        (!channels.is_empty() && fits).then(|| PixelLayout {
+            bit_depth: channels.iter().map(|(_, length)| *length).collect(),
+            offsets: channels.iter().map(|(offset, _)| *offset).collect(),
+            chunk_bits,
+        })
+    }
+
+    /// Number of pixels packed into a single chunk
+    pub fn pixels_per_chunk(&self) -> usize {
+        self.bit_depth.len()
+    }
+
+    /// Number of bytes written out per packed chunk
+    pub fn bytes_per_chunk(&self) -> usize {
+        (self.chunk_bits / 8) as usize
+    }
+
+    /// Number of bytes needed to hold a row of the given number of pixels
+    pub fn row_bytes(&self, row_pixels: usize) -> usize {
+        (row_pixels / self.pixels_per_chunk()) * self.bytes_per_chunk()
+    }
+}
+
+/// Re-encodes one-byte-per-pixel frames into the packed pixel layout used by
+/// the display's frame buffer.
+///
+/// Each packed chunk holds one pixel per entry in the layout, and is written
+/// out little-endian. The per-channel truncation and shift is precomputed into
+/// lookup tables, and the output is written into a buffer which is reused
+/// between frames.
+pub struct FrameEncoder {
+    layout: PixelLayout,
+    row_pixels: usize,
+    row_stride: usize,
+    input_depth: u8,
+    level_map: Option<LevelMap>,
+    tables: Vec<[u64; TABLE_SIZE]>,
+    buffer: Vec<u8>,
+}
+
+impl FrameEncoder {
+    /// Create an encoder for the given bit layout, for frames `row_pixels` wide
+    pub fn new(bit_depth: Vec<u8>, row_pixels: usize) -> FrameEncoder {
+        let layout = PixelLayout::from_bit_depth(bit_depth);
+        let row_stride = layout.row_bytes(row_pixels);
+        FrameEncoder::with_layout(layout, row_pixels, row_stride)
+    }
+
+    /// Create an encoder for the given pixel layout, writing each row of
+    /// `row_pixels` into `row_stride` bytes of output, padding as necessary
+    pub fn with_layout(layout: PixelLayout, row_pixels: usize, row_stride: usize) -> FrameEncoder {
+        let mut encoder = FrameEncoder {
+            row_stride: row_stride.max(layout.row_bytes(row_pixels)),
+            layout,
+            row_pixels,
+            input_depth: 8,
+            level_map: None,
+            tables: Vec::new(),
+            buffer: Vec::new(),
+        };
+        encoder.build_tables();
+        encoder
+    }
+
+    /// Total number of bits in a single packed chunk
+    pub fn chunk_bits(&self) -> u8 {
+        self.layout.chunk_bits
+    }
+
+    /// Number of pixels packed into a single chunk
+    pub fn pixels_per_chunk(&self) -> usize {
+        self.layout.pixels_per_chunk()
+    }
+
+    /// Number of bytes written out per packed chunk
+    pub fn bytes_per_chunk(&self) -> usize {
+        self.layout.bytes_per_chunk()
+    }
+
+    /// Set the grey level remapping applied to each input pixel before it is
+    /// packed, or `None` to pack pixels unchanged
+    pub fn set_level_map(&mut self, level_map: Option<LevelMap>) {
+        if self.level_map != level_map {
+            self.level_map = level_map;
+            self.build_tables();
+        }
+    }
+
+    /// Whether frames of the given input depth can be written without repacking
+    pub fn is_passthrough(&self, input_depth: u8) -> bool {
+        self.level_map.is_none()
+            && self.layout.bit_depth == [input_depth]
+            && self.layout.chunk_bits == input_depth
+            && self.row_stride == self.layout.row_bytes(self.row_pixels)
+    }
+
+    /// Re-encode the given frame, returning a view of the packed output. If
+    /// the display layout matches the input, the input is returned untouched.
+    pub fn encode<'a>(&'a mut self, input: &'a [u8], input_depth: u8) -> &'a [u8] {
+        if self.is_passthrough(input_depth) {
+            return input;
+        }
+
+        if input_depth != self.input_depth {
+            self.input_depth = input_depth;
+            self.build_tables();
+        }
+
+        let pixels_per_chunk = self.pixels_per_chunk();
+        let bytes_per_chunk = self.bytes_per_chunk();
+
+        // Split the work into row-sized blocks of whole chunks, so each block
+        // can be packed independently of its neighbours
+        let chunks_per_row = (self.row_pixels / pixels_per_chunk).max(1);
+        let row_stride = self.row_stride.max(chunks_per_row * bytes_per_chunk);
+
+        let chunk_count = input.len() / pixels_per_chunk;
+        self.buffer.resize(
+            (chunk_count / chunks_per_row) * row_stride
+                + (chunk_count % chunks_per_row) * bytes_per_chunk,
+            0,
+        );
+
+        let tables = &self.tables;
+
+        input[..chunk_count * pixels_per_chunk]
+            .par_chunks(chunks_per_row * pixels_per_chunk)
+            .zip(self.buffer.par_chunks_mut(row_stride))
+            .for_each(|(input_row, output_row)| {
+                input_row
+                    .chunks_exact(pixels_per_chunk)
+                    .zip(output_row.chunks_exact_mut(bytes_per_chunk))
+                    .for_each(|(pixel_chunk, output_chunk)| {
+                        let raw_chunk = pixel_chunk
+                            .iter()
+                            .zip(tables)
+                            .fold(0u64, |raw, (pixel, table)| raw | table[*pixel as usize]);
+
+                        output_chunk.copy_from_slice(&raw_chunk.to_le_bytes()[..bytes_per_chunk]);
+                    });
+            });
+
+        &self.buffer
+    }
+
+    /// Precompute, for each channel, the truncated and shifted value of every
+    /// possible input pixel
+    fn build_tables(&mut self) {
+        let input_depth = self.input_depth;
+        let level_map = self.level_map;
+
+        self.tables = self
+            .layout
+            .bit_depth
+            .iter()
+            .zip(&self.layout.offsets)
+            .map(|(depth, offset)| {
+                let mut table = [0u64; TABLE_SIZE];
+                for (value, entry) in table.iter_mut().enumerate() {
+                    let value = level_map.map_or(value as u64, |map| map[value] as u64);
+                    // Truncate (or widen) the pixel to the display's bit depth
+                    let scaled = if input_depth >= *depth {
+                        value >> (input_depth - depth)
+                    } else {
+                        value << (depth - input_depth)
+                    };
+                    *entry = scaled << offset;
+                }
+                table
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,121 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use crate::api_objects::{FileMetadata, PrintMetadata, SortKey, SortOrder};
+
+/// An entry in a directory listing
+#[derive(Clone, Debug)]
+pub enum ListingEntry {
+    Directory(FileMetadata),
+    PrintFile(PrintMetadata),
+    /// A file named like a print file, which couldn't be loaded as one
+    Invalid(FileMetadata),
+}
+
+impl ListingEntry {
+    pub fn file_data(&self) -> &FileMetadata {
+        match self {
+            ListingEntry::Directory(file_data) | ListingEntry::Invalid(file_data) => file_data,
+            ListingEntry::PrintFile(metadata) => &metadata.file_data,
+        }
+    }
+
+    fn print_metadata(&self) -> Option<&PrintMetadata> {
+        match self {
+            ListingEntry::PrintFile(metadata) => Some(metadata),
+            _ => None,
+        }
+    }
+}
+
+/// How to filter and order the entries of a directory listing
+#[derive(Clone, Debug, Default)]
+pub struct ListingOptions {
+    pub sort: SortKey,
+    pub order: SortOrder,
+    /// Only list entries whose name contains this, ignoring case
+    pub search: Option<String>,
+    /// Only list files with this extension
+    pub format: Option<String>,
+    /// Only list valid, or only invalid, print files
+    pub valid: Option<bool>,
+}
+
+impl ListingOptions {
+    fn matches(&self, entry: &ListingEntry) -> bool {
+        let file_data = entry.file_data();
+
+        let search_matches = self.search.as_ref().is_none_or(|search| {
+            file_data
+                .name
+                .to_lowercase()
+                .contains(&search.to_lowercase())
+        });
+
+        let filters_match = match entry {
+            ListingEntry::Directory(_) => true,
+            _ => {
+                let format_matches = self.format.as_ref().is_none_or(|format| {
+                    Path::new(&file_data.name)
+                        .extension()
+                        .and_then(|extension| extension.to_str())
+                        .is_some_and(|extension| {
+                            extension.eq_ignore_ascii_case(format.trim_start_matches('.'))
+                        })
+                });
+                let valid = matches!(entry, ListingEntry::PrintFile(_));
+
+                format_matches && self.valid.is_none_or(|wanted| wanted == valid)
+            }
+        };
+
+        search_matches && filters_match
+    }
+
+    fn compare(&self, a: &ListingEntry, b: &ListingEntry) -> Ordering {
+        let (a_data, b_data) = (a.file_data(), b.file_data());
+
+        let by_key = match self.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Modified => a_data.last_modified.cmp(&b_data.last_modified),
+            SortKey::Size => a_data.file_size.cmp(&b_data.file_size),
+            SortKey::PrintTime => match (a.print_metadata(), b.print_metadata()) {
+                (Some(a), Some(b)) => a.print_time.total_cmp(&b.print_time),
+                _ => Ordering::Equal,
+            },
+            SortKey::LayerCount => match (a.print_metadata(), b.print_metadata()) {
+                (Some(a), Some(b)) => a.layer_count.cmp(&b.layer_count),
+                _ => Ordering::Equal,
+            },
+        };
+
+        by_key.then_with(|| a_data.name.to_lowercase().cmp(&b_data.name.to_lowercase()))
+    }
+
+    /// Filter and sort the entries, keeping directories before files, and
+    /// when sorting by print metadata, files without it after those with it
+    pub fn apply(&self, mut entries: Vec<ListingEntry>) -> Vec<ListingEntry> {
+        entries.retain(|entry| self.matches(entry));
+
+        let by_metadata = matches!(self.sort, SortKey::PrintTime | SortKey::LayerCount);
+
+        entries.sort_by(|a, b| {
+            let directories_first = matches!(b, ListingEntry::Directory(_))
+                .cmp(&matches!(a, ListingEntry::Directory(_)));
+            let metadata_first = if by_metadata {
+                a.print_metadata()
+                    .is_none()
+                    .cmp(&b.print_metadata().is_none())
+            } else {
+                Ordering::Equal
+            };
+            let ordering = match self.order {
+                SortOrder::Ascending => self.compare(a, b),
+                SortOrder::Descending => self.compare(b, a),
+            };
+            directories_first.then(metadata_first).then(ordering)
+        });
+
+        entries
+    }
+}
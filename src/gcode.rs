@@ -1,70 +1,338 @@
-use core::panic;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
+use std::sync::LazyLock;
 
 use async_trait::async_trait;
 use regex::Regex;
-use tokio::sync::broadcast;
-use tokio::time::{interval, sleep, Duration};
-
-use crate::api_objects::PhysicalState;
-use crate::configuration::{Configuration, GcodeConfig};
-use crate::printer::HardwareControl;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+use crate::api_objects::{ConnectionStatus, PhysicalState};
+use crate::configuration::{Configuration, GcodeConfig, GcodeProtocol};
+use crate::printer::{FirmwareRejection, HardwareControl};
+use crate::template::TemplateCache;
+
+/// Render the given gcode template with the given variables. A template
+/// which fails to render is reported as a `FirmwareRejection`, as the command
+/// was never sent, so it pauses a print rather than shutting down.
+pub fn render_gcode(
+    templates: &mut TemplateCache,
+    code: &str,
+    variables: &HashMap<String, String>,
+) -> std::io::Result<String> {
+    templates.render(code, variables).map_err(|error| {
+        FirmwareRejection::error(format!("Unable to render gcode {:?}: {}", code, error))
+    })
+}
 
 pub struct Gcode {
     pub config: GcodeConfig,
     pub state: PhysicalState,
     pub gcode_substitutions: HashMap<String, String>,
+    templates: TemplateCache,
     pub serial_receiver: broadcast::Receiver<String>,
     pub serial_sender: broadcast::Sender<String>,
+    pub error_patterns: Vec<Regex>,
+    pub position_pattern: Regex,
+    /// Status of the serial connection, if it is being monitored
+    pub connection_status: Option<watch::Receiver<ConnectionStatus>>,
+    line_number: i64,
+    sent_lines: VecDeque<(i64, String)>,
 }
 
+/// Number of sent lines kept, in case the firmware requests a resend
+const RESEND_HISTORY: usize = 32;
+
+/// Firmware request to resend from the given line number
+static RESEND_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:Resend:|rs)\s*N?:?\s*(?P<line>\d+)").unwrap());
+/// Number of resends allowed for a single line before giving up
+const MAX_RESENDS: usize = 5;
+/// Extracts Z from the standard `M114` position report
+const DEFAULT_POSITION_PATTERN: &str = r"Z:(?P<z>-?\d+(\.\d+)?)";
+
 impl Gcode {
     pub fn new(
         config: Configuration,
         serial_receiver: broadcast::Receiver<String>,
         serial_sender: broadcast::Sender<String>,
     ) -> Gcode {
+        let error_patterns = config
+            .gcode
+            .error_patterns
+            .iter()
+            .flatten()
+            .filter_map(|pattern| {
+                Regex::new(pattern)
+                    .inspect_err(|error| {
+                        log::error!(
+                            "Ignoring invalid gcode error pattern {}: {}",
+                            pattern,
+                            error
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+
+        let position_pattern = config
+            .gcode
+            .position_pattern
+            .as_deref()
+            .and_then(|pattern| {
+                Regex::new(pattern)
+                    .inspect_err(|error| {
+                        log::error!("Ignoring invalid position pattern {}: {}", pattern, error)
+                    })
+                    .ok()
+            })
+            .unwrap_or_else(|| Regex::new(DEFAULT_POSITION_PATTERN).unwrap());
+
         Gcode {
+            templates: TemplateCache::new(config.gcode.template_sources()),
             config: config.gcode,
             state: PhysicalState {
                 z: 0.0,
                 z_microns: 0,
                 curing: false,
+                homed: false,
             },
             gcode_substitutions: HashMap::new(),
             serial_receiver,
             serial_sender,
+            error_patterns,
+            position_pattern,
+            connection_status: None,
+            line_number: 0,
+            sent_lines: VecDeque::new(),
         }
     }
 
-    fn parse_gcode(&mut self, code: String) -> String {
-        let re: Regex = Regex::new(r"\{(?P<substitution>\w*)\}").unwrap();
-        let mut parsed_code = code.clone();
-
+    fn parse_gcode(&mut self, code: String) -> std::io::Result<String> {
         self.add_state_variables();
 
-        for caps in re.captures_iter(&code) {
-            let sub = &caps["substitution"].to_string();
-            if let Some(value) = self.gcode_substitutions.get(sub) {
-                parsed_code = parsed_code.replace(&format!("{{{sub}}}"), value)
-            } else {
-                panic!("Attempted to use gcode substitution {} in context where it was unavailable: {}", sub, code);
+        render_gcode(&mut self.templates, &code, &self.gcode_substitutions)
+    }
+
+    /// Render the given gcode template and send it to the firmware
+    async fn send_gcode(&mut self, code: String) -> std::io::Result<Vec<String>> {
+        let parsed_code = self.parse_gcode(code)?;
+        self.send_lines(&parsed_code).await
+    }
+
+    /// Send each line of the given gcode to the firmware in turn, waiting for
+    /// it to be acknowledged, and return all of the response lines received.
+    /// If any response matches a configured error pattern, the command fails.
+    async fn send_lines(&mut self, code: &str) -> std::io::Result<Vec<String>> {
+        self.check_reconnection().await?;
+        let mut responses = Vec::new();
+        // Comments would be included in the checksum, so are left off
+        let strip_comments = self.uses_line_numbers();
+
+        for line in code
+            .lines()
+            .map(|line| match line.split_once(';') {
+                Some((code, _)) if strip_comments => code,
+                _ => line,
+            })
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            responses.append(&mut self.send_line(line).await?);
+        }
+
+        Ok(responses)
+    }
+
+    async fn send_line(&mut self, line: &str) -> std::io::Result<Vec<String>> {
+        log::debug!("Executing gcode: {}", line);
+
+        let mut pending_lines = VecDeque::from([self.frame_line(line)]);
+        let mut responses = Vec::new();
+        let mut resends = 0;
+
+        while let Some(framed_line) = pending_lines.pop_front() {
+            let mut line_responses = self.transmit_line(&framed_line).await?;
+
+            // On a resend request, the firmware discarded everything from the
+            // requested line onwards, along with any errors about it
+            if let Some(resend_from) = self.resend_request(&line_responses) {
+                resends += 1;
+                if resends > MAX_RESENDS {
+                    return Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        format!("Firmware requested too many resends of '{}'", line),
+                    ));
+                }
+                log::warn!("Firmware requested resend from line {}", resend_from);
+                pending_lines = self.lines_from(resend_from)?;
+                continue;
+            }
+
+            if let Some(error) = line_responses
+                .iter()
+                .find(|response| self.is_error(response))
+            {
+                return Err(FirmwareRejection::error(format!(
+                    "Firmware reported error for '{}': {}",
+                    line, error
+                )));
             }
+
+            responses.append(&mut line_responses);
         }
-        parsed_code
+
+        Ok(responses)
     }
 
-    async fn send_gcode(&mut self, code: String) -> std::io::Result<()> {
-        let parsed_code = self.parse_gcode(code) + "\r\n";
-        log::debug!("Executing gcode: {}", parsed_code.trim_end());
+    /// Write a single line out to the firmware and collect its responses,
+    /// until it is acknowledged
+    async fn transmit_line(&mut self, framed_line: &str) -> std::io::Result<Vec<String>> {
+        if self.connection_status() == ConnectionStatus::Disconnected {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "Serial connection is down",
+            ));
+        }
+
+        self.flush_serial_input()?;
+
+        log::trace!("Sending line: {}", framed_line);
 
         self.serial_sender
-            .send(parsed_code)
+            .send(format!("{framed_line}\r\n"))
             .map_err(|error| Error::new(ErrorKind::BrokenPipe, error))?;
 
-        // Force a delay between commands
-        sleep(Duration::from_millis(100)).await;
+        let mut responses = Vec::new();
+
+        match self.config.ack_response.clone() {
+            Some(ack) => {
+                let deadline = Instant::now() + self.response_timeout();
+
+                // Firmware may report an error before acknowledging the line,
+                // so keep reading until the ack to stay in step with it
+                loop {
+                    let response = self.next_response(deadline).await?;
+                    responses.push(response.clone());
+                    if response.starts_with(ack.as_str()) {
+                        break;
+                    }
+                }
+            }
+            None => {
+                // Without an ack to wait for, force a delay between commands
+                sleep(Duration::from_millis(100)).await;
+                while let Some(response) = self.try_next_response()? {
+                    responses.push(response);
+                }
+            }
+        }
+
+        Ok(responses)
+    }
+
+    /// In the Marlin protocol, number each line and append its checksum, and
+    /// keep it for resending. Otherwise, lines are sent as they are.
+    fn frame_line(&mut self, line: &str) -> String {
+        if !self.uses_line_numbers() {
+            return line.to_string();
+        }
+
+        self.line_number += 1;
+        let framed_line = Gcode::checksum_line(self.line_number, line);
+
+        self.sent_lines
+            .push_back((self.line_number, framed_line.clone()));
+        while self.sent_lines.len() > RESEND_HISTORY {
+            self.sent_lines.pop_front();
+        }
+
+        framed_line
+    }
+
+    /// Format a numbered line, followed by the XOR checksum of its contents
+    fn checksum_line(line_number: i64, line: &str) -> String {
+        let numbered_line = format!("N{} {}", line_number, line);
+        let checksum = numbered_line
+            .bytes()
+            .fold(0u8, |checksum, byte| checksum ^ byte);
+        format!("{}*{}", numbered_line, checksum)
+    }
+
+    fn uses_line_numbers(&self) -> bool {
+        matches!(self.config.protocol, Some(GcodeProtocol::Marlin))
+    }
+
+    /// Find the line number the firmware asked to resend from, if any
+    fn resend_request(&self, responses: &[String]) -> Option<i64> {
+        if !self.uses_line_numbers() {
+            return None;
+        }
+
+        responses.iter().find_map(|response| {
+            RESEND_PATTERN
+                .captures(response)
+                .and_then(|caps| caps["line"].parse().ok())
+        })
+    }
+
+    /// Collect the previously sent lines, from the given line number onwards
+    fn lines_from(&self, line_number: i64) -> std::io::Result<VecDeque<String>> {
+        if !self
+            .sent_lines
+            .iter()
+            .any(|(number, _)| *number == line_number)
+        {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                format!("Unable to resend line {}, no longer available", line_number),
+            ));
+        }
+
+        Ok(self
+            .sent_lines
+            .iter()
+            .filter(|(number, _)| *number >= line_number)
+            .map(|(_, framed_line)| framed_line.clone())
+            .collect())
+    }
+
+    /// Once the serial connection comes back, the firmware may have been
+    /// restarted, so forget the homing and reset the line numbers
+    async fn check_reconnection(&mut self) -> std::io::Result<()> {
+        let reconnected = self.connection_status.as_mut().is_some_and(|status| {
+            status.has_changed().unwrap_or(false)
+                && *status.borrow_and_update() == ConnectionStatus::Connected
+        });
+
+        if reconnected {
+            log::info!("Serial connection established, resetting firmware state");
+            self.state.homed = false;
+            self.reset_line_number().await?;
+        }
+        Ok(())
+    }
+
+    /// Reset the firmware's expected line number with M110
+    async fn reset_line_number(&mut self) -> std::io::Result<()> {
+        if !self.uses_line_numbers() {
+            return Ok(());
+        }
+
+        log::debug!("Resetting line numbers");
+        self.line_number = 0;
+        self.sent_lines.clear();
+
+        let reset_line = Gcode::checksum_line(0, "M110 N0");
+        self.sent_lines.push_back((0, reset_line.clone()));
+
+        let responses = self.transmit_line(&reset_line).await?;
+        if let Some(error) = responses.iter().find(|response| self.is_error(response)) {
+            return Err(FirmwareRejection::error(format!(
+                "Firmware rejected line number reset: {}",
+                error
+            )));
+        }
         Ok(())
     }
 
@@ -72,63 +340,107 @@ impl Gcode {
         &mut self,
         response: String,
         timeout_seconds: usize,
-    ) -> std::io::Result<()> {
+    ) -> std::io::Result<Vec<String>> {
         log::trace!("Expecting response: {}", response);
-        let mut interval = interval(Duration::from_millis(100));
-        let intervals = 10 * timeout_seconds;
-
-        for _ in 0..intervals {
-            if self.check_response(&response).await {
+        let deadline = Instant::now() + Duration::from_secs(timeout_seconds as u64);
+        let mut responses = Vec::new();
+
+        loop {
+            let received = self.next_response(deadline).await?;
+            if self.is_error(&received) {
+                return Err(FirmwareRejection::error(format!(
+                    "Firmware reported error: {}",
+                    received
+                )));
+            }
+            let matched = received.contains(&response);
+            responses.push(received);
+            if matched {
                 log::trace!("Expected response received");
-                return Ok(());
-            } else {
-                interval.tick().await;
+                return Ok(responses);
             }
         }
-        Err(Error::new(
-            ErrorKind::TimedOut,
-            "Timed out awaiting gcode response",
-        ))
     }
 
-    // Consume all available responses in case of ack messages before desired
-    async fn check_response(&mut self, response: &String) -> bool {
-        self.serial_receiver
-            .recv()
-            .await
-            .expect("Unable to receive message from channel")
-            .contains(response)
+    /// Wait for the next non-empty line from the firmware, until the deadline
+    async fn next_response(&mut self, deadline: Instant) -> std::io::Result<String> {
+        loop {
+            let received = timeout_at(deadline, self.serial_receiver.recv())
+                .await
+                .map_err(|_| {
+                    Error::new(ErrorKind::TimedOut, "Timed out awaiting gcode response")
+                })?;
+
+            match received {
+                Ok(response) => {
+                    let response = response.trim();
+                    if !response.is_empty() {
+                        log::trace!("Received response: {}", response);
+                        return Ok(response.to_string());
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Dropped {} gcode responses", skipped)
+                }
+                Err(error) => return Err(Error::new(ErrorKind::BrokenPipe, error)),
+            }
+        }
+    }
+
+    /// Take the next line from the firmware if one is already available
+    fn try_next_response(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            match self.serial_receiver.try_recv() {
+                Ok(response) => {
+                    let response = response.trim();
+                    if !response.is_empty() {
+                        return Ok(Some(response.to_string()));
+                    }
+                }
+                Err(broadcast::error::TryRecvError::Empty) => return Ok(None),
+                Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                    log::warn!("Dropped {} gcode responses", skipped)
+                }
+                Err(error) => return Err(Error::new(ErrorKind::BrokenPipe, error)),
+            }
+        }
     }
 
     // Consume all responses from serial port, to ensure we'll get the correct corresponding
-    async fn flush_serial_input(&mut self) -> std::io::Result<()> {
-        while !self.serial_receiver.is_empty() {
-            let _ = self
-                .serial_receiver
-                .recv()
-                .await
-                .map_err(|error| Error::new(ErrorKind::BrokenPipe, error))?;
+    fn flush_serial_input(&mut self) -> std::io::Result<()> {
+        while let Some(response) = self.try_next_response()? {
+            log::trace!("Discarding response: {}", response);
         }
         Ok(())
     }
 
+    fn is_error(&self, response: &str) -> bool {
+        self.error_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(response))
+    }
+
+    fn response_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .response_timeout
+                .unwrap_or(self.config.move_timeout) as u64,
+        )
+    }
+
     async fn send_and_await_gcode(
         &mut self,
         code: String,
         expect: String,
         timeout_seconds: usize,
-    ) -> std::io::Result<()> {
-        self.flush_serial_input().await?;
-        self.send_gcode(code).await?;
-        self.await_response(expect, timeout_seconds).await?;
-        Ok(())
-    }
+    ) -> std::io::Result<Vec<String>> {
+        let mut responses = self.send_gcode(code).await?;
 
-    async fn send_and_check_gcode(&mut self, code: String, expect: String) -> bool {
-        if self.send_gcode(code).await.is_ok() {
-            return self.check_response(&expect).await;
+        // The expected response may have arrived before the ack
+        if !responses.iter().any(|response| response.contains(&expect)) {
+            responses.append(&mut self.await_response(expect, timeout_seconds).await?);
         }
-        false
+        Ok(responses)
     }
 
     /// Set the internally-stored position. Any method which uses a send_gcode
@@ -140,6 +452,33 @@ impl Gcode {
         self.state
     }
 
+    /// Query the firmware for the plate's actual position, if configured,
+    /// replacing the assumed position
+    async fn query_position(&mut self) -> std::io::Result<PhysicalState> {
+        let Some(query) = self.config.position_query.clone() else {
+            return Ok(self.state);
+        };
+
+        let responses = self.send_gcode(query).await?;
+
+        let position = responses.iter().find_map(|response| {
+            self.position_pattern
+                .captures(response)
+                .and_then(|caps| caps.name("z"))
+                .and_then(|z| z.as_str().parse::<f64>().ok())
+        });
+
+        match position {
+            Some(z) => {
+                self.state.z = z;
+                self.state.z_microns = (z.max(0.0) * 1000.0).round() as u32;
+            }
+            None => log::warn!("No position found in response to position query"),
+        }
+
+        Ok(self.state)
+    }
+
     /// Set the internally-stored curing state. Any method which uses a send_gcode
     /// method to enable or disable the LED array (or other curing method) should
     /// call this method to reflect that change
@@ -161,23 +500,48 @@ impl HardwareControl for Gcode {
     async fn initialize(&mut self) {}
 
     async fn is_ready(&mut self) -> bool {
-        self.send_and_check_gcode(
-            self.config.status_check.clone(),
-            self.config.status_desired.clone(),
-        )
-        .await
+        let status_check = self.config.status_check.clone();
+        let status_desired = self.config.status_desired.clone();
+
+        // With an ack, the status report arrives before it. Otherwise, wait
+        // a limited time for the status report itself
+        let responses = if self.config.ack_response.is_some() {
+            self.send_gcode(status_check).await
+        } else {
+            let timeout = self.response_timeout().as_secs() as usize;
+            self.send_and_await_gcode(status_check, status_desired.clone(), timeout)
+                .await
+        };
+
+        match responses {
+            Ok(responses) => responses
+                .iter()
+                .any(|response| response.contains(&status_desired)),
+            Err(e) => {
+                log::debug!("Hardware status check failed: {}", e);
+                false
+            }
+        }
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        self.connection_status
+            .as_ref()
+            .map_or(ConnectionStatus::Connected, |status| *status.borrow())
     }
 
     async fn home(&mut self) -> std::io::Result<PhysicalState> {
         self.send_gcode(self.config.home_command.clone()).await?;
 
-        Ok(self.state)
-    }
+        self.set_position(0);
+        self.state.homed = true;
 
-    async fn manual_command(&mut self, command: String) -> std::io::Result<PhysicalState> {
-        self.send_gcode(command).await?;
+        self.query_position().await
+    }
 
-        Ok(self.state)
+    async fn manual_command(&mut self, command: String) -> std::io::Result<Vec<String>> {
+        // Entered by hand, so sent as it is rather than as a template
+        self.send_lines(&command).await
     }
 
     async fn move_z(&mut self, z: u32, speed: f64) -> std::io::Result<PhysicalState> {
@@ -196,7 +560,7 @@ impl HardwareControl for Gcode {
 
         self.remove_print_variable("speed".to_string());
 
-        Ok(self.state)
+        self.query_position().await
     }
 
     async fn start_layer(&mut self, _layer: usize) -> std::io::Result<PhysicalState> {
@@ -232,12 +596,21 @@ impl HardwareControl for Gcode {
     }
 
     async fn boot(&mut self) -> std::io::Result<PhysicalState> {
+        // The firmware may have restarted, so the position isn't known yet
+        self.state.homed = false;
+        if let Some(status) = self.connection_status.as_mut() {
+            status.mark_unchanged();
+        }
+        self.reset_line_number().await?;
         self.send_gcode(self.config.boot.clone()).await?;
 
         Ok(self.state)
     }
 
     async fn shutdown(&mut self) -> std::io::Result<()> {
+        // The motors may be released, so the position is no longer known,
+        // even if the shutdown gcode can't be sent
+        self.state.homed = false;
         self.send_gcode(self.config.shutdown.clone()).await?;
 
         Ok(())
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+type SharedTask<T> = Shared<BoxFuture<'static, Result<T, Arc<Error>>>>;
+
+/// Blocking work, such as decoding every layer of a print file, run off the
+/// async workers and shared between concurrent requests for the same result
+pub struct InFlight<K, T> {
+    tasks: Arc<Mutex<HashMap<K, SharedTask<T>>>>,
+}
+
+impl<K, T> Clone for InFlight<K, T> {
+    fn clone(&self) -> Self {
+        InFlight {
+            tasks: self.tasks.clone(),
+        }
+    }
+}
+
+impl<K, T> Default for InFlight<K, T> {
+    fn default() -> Self {
+        InFlight {
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K, T> fmt::Debug for InFlight<K, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFlight")
+            .field("tasks", &self.tasks.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl<K, T> InFlight<K, T>
+where
+    K: Clone + Eq + Hash + Send + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    /// Run the work on the blocking thread pool, or if it is already running
+    /// under the same key, wait for that to finish instead. The work carries
+    /// on even if every request waiting on it is dropped.
+    pub async fn run<F>(&self, key: K, work: F) -> std::io::Result<T>
+    where
+        F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    {
+        let task = self
+            .tasks
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let tasks = self.tasks.clone();
+                tokio::spawn(async move {
+                    let result = tokio::task::spawn_blocking(work)
+                        .await
+                        .unwrap_or_else(|error| Err(Error::other(error)));
+                    tasks.lock().unwrap().remove(&key);
+                    result
+                })
+                .map(|joined| {
+                    joined
+                        .unwrap_or_else(|error| Err(Error::other(error)))
+                        .map_err(Arc::new)
+                })
+                .boxed()
+                .shared()
+            })
+            .clone();
+
+        task.await
+            .map_err(|error| Error::new(error.kind(), error.to_string()))
+    }
+}
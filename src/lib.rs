@@ -1,11 +1,26 @@
+pub mod analysis;
 pub mod api;
 pub mod api_objects;
 pub mod configuration;
 pub mod display;
+pub mod encoder;
+pub mod file_listing;
 pub mod gcode;
+pub mod in_flight;
+pub mod lift;
+pub mod local_files;
+pub mod metadata_cache;
+pub mod moonraker;
+pub mod paths;
+pub mod preview;
 pub mod printer;
 pub mod printfile;
 pub mod serial_handler;
 pub mod shutdown_handler;
+pub mod simulated_hardware;
 pub mod sl1;
+pub mod template;
+pub mod traffic_log;
+pub mod upload;
+pub mod usb;
 mod wrapped_framebuffer;
@@ -0,0 +1,33 @@
+use crate::configuration::LiftCurvePoint;
+
+/// Lift for a layer with the given lit area in mm², interpolated between the
+/// curve's nearest points, or that of its first or last point for areas
+/// outside the curve. The points may be given in any order.
+pub fn lift_for_area(curve: &[LiftCurvePoint], area: f64) -> Option<LiftCurvePoint> {
+    let mut points: Vec<&LiftCurvePoint> = curve.iter().collect();
+    points.sort_by(|a, b| a.area.total_cmp(&b.area));
+
+    let above = points.iter().position(|point| point.area >= area);
+    let (below, above) = match above {
+        Some(0) => (points[0], points[0]),
+        Some(index) => (points[index - 1], points[index]),
+        None => {
+            let last = points.last()?;
+            (*last, *last)
+        }
+    };
+
+    let fraction = if above.area > below.area {
+        (area - below.area) / (above.area - below.area)
+    } else {
+        0.0
+    };
+    let interpolate = |from: f64, to: f64| from + (to - from) * fraction;
+
+    Some(LiftCurvePoint {
+        area,
+        lift: interpolate(below.lift, above.lift),
+        up_speed: interpolate(below.up_speed, above.up_speed),
+        down_speed: interpolate(below.down_speed, above.down_speed),
+    })
+}
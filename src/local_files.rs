@@ -0,0 +1,134 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+use crate::paths::{resolve_existing, resolve_path};
+
+/// Files in local storage, as described by `ApiConfig::upload_path`.
+///
+/// Paths given by clients are relative to the upload directory, and may not
+/// leave it, even through symlinks, or refer to hidden files, such as
+/// incomplete uploads.
+#[derive(Clone, Debug)]
+pub struct LocalFiles {
+    root: PathBuf,
+}
+
+impl LocalFiles {
+    pub fn new(upload_path: &str) -> LocalFiles {
+        LocalFiles {
+            root: PathBuf::from(upload_path),
+        }
+    }
+
+    /// Directory local file paths are relative to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Full path of a local file, which need not exist yet
+    pub fn path(&self, file_path: &str) -> std::io::Result<PathBuf> {
+        resolve_path(&self.root, file_path)
+    }
+
+    /// Full path of an existing local file or directory
+    pub fn resolve(&self, file_path: &str) -> std::io::Result<PathBuf> {
+        resolve_existing(&self.root, file_path)
+    }
+
+    /// Full path of an existing local directory, or the upload directory
+    /// itself if none is given
+    pub fn directory(&self, directory: Option<&str>) -> std::io::Result<PathBuf> {
+        let path = self.resolve(directory.unwrap_or_default())?;
+
+        if !path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a directory", directory.unwrap_or_default()),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Full path for a new file or directory, which must not already exist
+    /// and whose parent directory must
+    pub fn destination(&self, file_path: &str) -> std::io::Result<PathBuf> {
+        let path = self.path(file_path)?;
+
+        if path == self.root {
+            return Err(Error::new(ErrorKind::InvalidInput, "No destination given"));
+        }
+        // Dangling symlinks count, as writing through one could leave the root
+        if path.symlink_metadata().is_ok() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} already exists", file_path),
+            ));
+        }
+        if !path.parent().is_some_and(Path::is_dir) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Destination directory for {:?} does not exist", file_path),
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Create a directory, within an existing one
+    pub async fn create_directory(&self, directory: &str) -> std::io::Result<PathBuf> {
+        let path = self.destination(directory)?;
+
+        fs::create_dir(&path).await?;
+        Ok(path)
+    }
+
+    /// Move a file or directory to a new path, which must not already exist
+    pub async fn move_file(&self, file_path: &str, destination: &str) -> std::io::Result<PathBuf> {
+        let source = self.resolve(file_path)?;
+        let destination = self.destination(destination)?;
+
+        if source == self.root {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Can't move the upload directory",
+            ));
+        }
+        if destination.starts_with(&source) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Can't move a directory into itself",
+            ));
+        }
+
+        fs::rename(&source, &destination).await?;
+        Ok(destination)
+    }
+
+    /// Give a file or directory a new name, keeping it in the same directory
+    pub async fn rename(&self, file_path: &str, name: &str) -> std::io::Result<PathBuf> {
+        if name.contains(['/', '\\']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name {:?}", name),
+            ));
+        }
+
+        let destination = Path::new(file_path).with_file_name(name);
+        self.move_file(file_path, &destination.to_string_lossy())
+            .await
+    }
+
+    /// Copy a file to a new path, which must not already exist
+    pub async fn copy_file(&self, file_path: &str, destination: &str) -> std::io::Result<PathBuf> {
+        let source = self.resolve(file_path)?;
+        let destination = self.destination(destination)?;
+
+        if source.is_dir() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Can only copy files"));
+        }
+
+        fs::copy(&source, &destination).await?;
+        Ok(destination)
+    }
+}
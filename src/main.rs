@@ -1,23 +1,30 @@
-use std::str::FromStr;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use clap::Parser;
 
-use serialport::{ClearBuffer, SerialPort};
 use simple_logger::SimpleLogger;
 use tokio::{
     runtime::{Builder, Runtime},
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
 };
+use tokio_util::sync::CancellationToken;
 
 use odyssey::{
     api,
-    api_objects::PrinterState,
-    configuration::Configuration,
+    api_objects::{ConnectionStatus, ConsoleResponse, PrinterState},
+    configuration::{Configuration, HardwareBackend},
     display::PrintDisplay,
     gcode::Gcode,
-    printer::{Operation, Printer},
-    serial_handler,
+    moonraker::Moonraker,
+    printer::{HardwareControl, Operation, Printer},
+    serial_handler::{self, Transport},
     shutdown_handler::ShutdownHandler,
+    simulated_hardware::SimulatedHardware,
+    traffic_log::{self, TrafficLog},
 };
 
 #[derive(Parser, Debug)]
@@ -28,6 +35,13 @@ struct Args {
     config: String,
     #[arg(default_value_t=String::from("DEBUG"), short, long)]
     loglevel: String,
+    /// Run against simulated hardware, rather than the configured backend
+    #[arg(long)]
+    simulate: bool,
+    /// Replay the commands recorded in a traffic log file against the
+    /// configured backend, then exit
+    #[arg(long)]
+    replay: Option<String>,
 }
 
 fn main() {
@@ -42,86 +56,182 @@ fn main() {
 
     log::info!("Starting Odyssey");
 
-    let configuration = parse_config(args.config);
+    let mut configuration = parse_config(args.config);
 
-    let mut serial = tokio_serial::new(
-        configuration.printer.serial.clone(),
-        configuration.printer.baudrate,
-    )
-    .open_native()
-    .expect("Unable to open serial port");
+    if args.simulate {
+        configuration.printer.backend = Some(HardwareBackend::Simulated);
+    }
 
-    serial
-        .set_exclusive(false)
-        .expect("Unable to set serial port exclusivity(false)");
-    serial
-        .clear(ClearBuffer::All)
-        .expect("Unable to clear serialport buffers");
+    let traffic_log = TrafficLog::new(configuration.traffic_log.clone().unwrap_or_default());
 
-    let (serial_read_sender, serial_read_receiver) = broadcast::channel(200);
-    let (serial_write_sender, serial_write_receiver) = broadcast::channel(200);
+    let runtime = build_runtime();
 
-    let gcode = Gcode::new(
-        configuration.clone(),
-        serial_read_receiver,
-        serial_write_sender,
-    );
+    if let Some(replay_path) = args.replay {
+        runtime.block_on(replay_session(
+            &configuration,
+            Path::new(&replay_path),
+            traffic_log,
+            &shutdown_handler,
+        ));
+        return;
+    }
 
     let display: PrintDisplay = PrintDisplay::new(configuration.display.clone());
 
     let operation_channel = mpsc::channel::<Operation>(100);
     let status_channel = broadcast::channel::<PrinterState>(100);
-
-    let runtime = build_runtime();
+    let console_channel = broadcast::channel::<ConsoleResponse>(100);
 
     runtime.block_on(async {
         let sender = operation_channel.0.clone();
         let receiver = status_channel.1.resubscribe();
 
-        let writer_serial = serial
-            .try_clone_native()
-            .expect("Unable to clone serial port handler");
-        let listener_serial = serial
-            .try_clone_native()
-            .expect("Unable to clone serial port handler");
-
-        let serial_read_handle = tokio::spawn(serial_handler::run_listener(
-            listener_serial,
-            serial_read_sender,
-            shutdown_handler.cancellation_token.clone(),
-        ));
-
-        let serial_write_handle = tokio::spawn(serial_handler::run_writer(
-            writer_serial,
-            serial_write_receiver,
-            shutdown_handler.cancellation_token.clone(),
-        ));
-
-        let statemachine_handle = tokio::spawn(Printer::start_printer(
-            configuration.printer.clone(),
-            display,
-            gcode,
-            operation_channel.1,
-            status_channel.0.clone(),
-            shutdown_handler.cancellation_token.clone(),
-        ));
+        let mut hardware_handles = Vec::new();
+
+        let statemachine_handle = match configuration.printer.backend {
+            Some(HardwareBackend::Moonraker) => tokio::spawn(Printer::start_printer(
+                configuration.printer.clone(),
+                display,
+                Moonraker::new(configuration.clone()),
+                operation_channel.1,
+                status_channel.0.clone(),
+                console_channel.0.clone(),
+                shutdown_handler.cancellation_token.clone(),
+            )),
+            Some(HardwareBackend::Simulated) => tokio::spawn(Printer::start_printer(
+                configuration.printer.clone(),
+                display,
+                SimulatedHardware::new(configuration.clone()),
+                operation_channel.1,
+                status_channel.0.clone(),
+                console_channel.0.clone(),
+                shutdown_handler.cancellation_token.clone(),
+            )),
+            Some(HardwareBackend::Serial) | None => {
+                let (gcode, handles) = start_serial(
+                    &configuration,
+                    traffic_log.clone(),
+                    &shutdown_handler.cancellation_token,
+                );
+                hardware_handles = handles;
+
+                tokio::spawn(Printer::start_printer(
+                    configuration.printer.clone(),
+                    display,
+                    gcode,
+                    operation_channel.1,
+                    status_channel.0.clone(),
+                    console_channel.0.clone(),
+                    shutdown_handler.cancellation_token.clone(),
+                ))
+            }
+        };
 
         let api_handle = tokio::spawn(api::start_api(
             configuration,
             sender,
             receiver,
+            console_channel.0.clone(),
+            traffic_log,
             shutdown_handler.cancellation_token.clone(),
         ));
 
         shutdown_handler.until_shutdown().await;
 
-        let _ = serial_read_handle.await;
-        let _ = serial_write_handle.await;
+        for handle in hardware_handles {
+            let _ = handle.await;
+        }
         let _ = statemachine_handle.await;
         let _ = api_handle.await;
     });
 }
 
+/// Spawn the task which keeps the configured serial connection open
+fn start_serial(
+    configuration: &Configuration,
+    traffic_log: TrafficLog,
+    cancellation_token: &CancellationToken,
+) -> (Gcode, Vec<JoinHandle<()>>) {
+    let (serial_read_sender, serial_read_receiver) = broadcast::channel(200);
+    let (serial_write_sender, serial_write_receiver) = broadcast::channel(200);
+    let (connection_sender, connection_receiver) = watch::channel(ConnectionStatus::Disconnected);
+
+    let mut gcode = Gcode::new(
+        configuration.clone(),
+        serial_read_receiver,
+        serial_write_sender,
+    );
+    gcode.connection_status = Some(connection_receiver);
+
+    let serial_handle = tokio::spawn(serial_handler::run_serial(
+        Transport::from_config(
+            &configuration.printer.serial,
+            configuration.printer.baudrate,
+        ),
+        serial_read_sender,
+        serial_write_receiver,
+        connection_sender,
+        traffic_log,
+        cancellation_token.clone(),
+    ));
+
+    (gcode, vec![serial_handle])
+}
+
+/// Replay a recorded session against the configured backend, shutting down
+/// once it is done
+async fn replay_session(
+    configuration: &Configuration,
+    replay_path: &Path,
+    traffic_log: TrafficLog,
+    shutdown_handler: &ShutdownHandler,
+) {
+    let cancellation_token = shutdown_handler.cancellation_token.clone();
+    let mut hardware_handles = Vec::new();
+
+    let replay_handle = match configuration.printer.backend {
+        Some(HardwareBackend::Moonraker) => tokio::spawn(run_replay(
+            Moonraker::new(configuration.clone()),
+            replay_path.to_path_buf(),
+            cancellation_token,
+        )),
+        Some(HardwareBackend::Simulated) => tokio::spawn(run_replay(
+            SimulatedHardware::new(configuration.clone()),
+            replay_path.to_path_buf(),
+            cancellation_token,
+        )),
+        Some(HardwareBackend::Serial) | None => {
+            let (gcode, handles) = start_serial(configuration, traffic_log, &cancellation_token);
+            hardware_handles = handles;
+
+            tokio::spawn(run_replay(
+                gcode,
+                replay_path.to_path_buf(),
+                cancellation_token,
+            ))
+        }
+    };
+
+    shutdown_handler.until_shutdown().await;
+
+    for handle in hardware_handles {
+        let _ = handle.await;
+    }
+    let _ = replay_handle.await;
+}
+
+async fn run_replay<T: HardwareControl>(
+    hardware: T,
+    replay_path: PathBuf,
+    cancellation_token: CancellationToken,
+) {
+    match traffic_log::replay_file(hardware, &replay_path, cancellation_token.clone()).await {
+        Ok(sent) => log::info!("Replayed {} commands from {}", sent, replay_path.display()),
+        Err(e) => log::error!("Unable to replay {}: {}", replay_path.display(), e),
+    }
+    cancellation_token.cancel();
+}
+
 fn build_runtime() -> Runtime {
     Builder::new_multi_thread()
         .worker_threads(4)
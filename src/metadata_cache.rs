@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::executor::block_on;
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::analysis;
+use crate::api_objects::{FileData, FileMetadata, PrintAnalysis, PrintMetadata, ThumbnailSize};
+use crate::in_flight::InFlight;
+use crate::preview;
+use crate::printfile::PrintFile;
+use crate::sl1::Sl1;
+
+const INDEX_FILE: &str = "metadata.json";
+const THUMBNAIL_DIRECTORY: &str = "thumbnails";
+
+/// What was read from a print file, valid as long as the file's modification
+/// time and size stay the same
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    modified: Duration,
+    size: u64,
+    /// The file's metadata, or why it couldn't be loaded
+    metadata: Result<PrintMetadata, String>,
+    /// Measurements of the file's layers, once it has been analysed
+    #[serde(default)]
+    analysis: Option<PrintAnalysis>,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    directory: Option<PathBuf>,
+    entries: HashMap<PathBuf, CacheEntry>,
+    changed: bool,
+}
+
+/// Print metadata and thumbnails of print files, kept in the given directory
+/// so listing files doesn't need to open every one of them, even after a
+/// restart. Entries are keyed by the file's path, and only used while its
+/// modification time and size are unchanged.
+#[derive(Clone, Debug)]
+pub struct MetadataCache {
+    inner: Arc<Mutex<CacheInner>>,
+    thumbnails: InFlight<ThumbnailKey, Vec<u8>>,
+    analyses: InFlight<PathBuf, PrintAnalysis>,
+}
+
+/// File, size and dimensions a thumbnail is made for
+type ThumbnailKey = (PathBuf, ThumbnailSize, Option<(u32, u32)>);
+
+fn full_path(file_data: &FileMetadata) -> PathBuf {
+    Path::new(&file_data.parent_path).join(&file_data.path)
+}
+
+/// Modification time and size identifying the current version of a file
+fn file_version(path: &Path) -> std::io::Result<(Duration, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok((modified, metadata.len()))
+}
+
+impl MetadataCache {
+    /// Load the cache kept in the given directory, or keep it only in memory
+    /// if there is none
+    pub fn new(directory: Option<PathBuf>) -> MetadataCache {
+        let directory = directory.filter(|directory| {
+            fs::create_dir_all(directory.join(THUMBNAIL_DIRECTORY))
+                .inspect_err(|e| {
+                    log::error!("Unable to create metadata cache {:?}: {}", directory, e)
+                })
+                .is_ok()
+        });
+
+        let entries = directory
+            .as_ref()
+            .and_then(|directory| fs::read(directory.join(INDEX_FILE)).ok())
+            .and_then(|index| {
+                serde_json::from_slice(&index)
+                    .inspect_err(|e| log::warn!("Discarding unreadable metadata cache: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        MetadataCache {
+            inner: Arc::new(Mutex::new(CacheInner {
+                directory,
+                entries,
+                changed: false,
+            })),
+            thumbnails: InFlight::default(),
+            analyses: InFlight::default(),
+        }
+    }
+
+    /// Directory the cache is kept in, if any
+    pub fn directory(&self) -> Option<PathBuf> {
+        self.inner.lock().unwrap().directory.clone()
+    }
+
+    /// Prefix of the names of every thumbnail cached for the given file
+    fn thumbnail_prefix(path: &Path) -> String {
+        let hash = Sha256::digest(path.as_os_str().as_encoded_bytes());
+        let name: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}-", name)
+    }
+
+    fn thumbnail_path(
+        directory: &Path,
+        path: &Path,
+        size: &ThumbnailSize,
+        (width, height): (u32, u32),
+    ) -> PathBuf {
+        directory.join(THUMBNAIL_DIRECTORY).join(format!(
+            "{}{:?}-{}x{}.png",
+            MetadataCache::thumbnail_prefix(path),
+            size,
+            width,
+            height
+        ))
+    }
+
+    fn remove_thumbnails(directory: Option<&PathBuf>, path: &Path) {
+        let Some(thumbnails) = directory
+            .map(|directory| directory.join(THUMBNAIL_DIRECTORY))
+            .and_then(|thumbnails| fs::read_dir(thumbnails).ok())
+        else {
+            return;
+        };
+
+        let prefix = MetadataCache::thumbnail_prefix(path);
+        for thumbnail in thumbnails.flatten() {
+            if thumbnail.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = fs::remove_file(thumbnail.path());
+            }
+        }
+    }
+
+    /// The cached entry for the file, if it is still current
+    fn current_entry(&self, path: &Path) -> std::io::Result<(Duration, u64, Option<CacheEntry>)> {
+        let (modified, size) = file_version(path)?;
+
+        let entry = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(path)
+            .filter(|entry| entry.modified == modified && entry.size == size)
+            .cloned();
+
+        Ok((modified, size, entry))
+    }
+
+    /// Metadata of the given print file, loading it only if it isn't cached
+    /// or has changed since it was
+    pub fn print_metadata(&self, file_data: FileMetadata) -> std::io::Result<PrintMetadata> {
+        let path = full_path(&file_data);
+        let (modified, size, entry) = self.current_entry(&path)?;
+
+        let metadata = match entry {
+            Some(entry) => entry.metadata,
+            None => {
+                let metadata = Sl1::from_file(file_data.clone())
+                    .map(|file| file.get_metadata())
+                    .map_err(|e| e.to_string());
+
+                let mut inner = self.inner.lock().unwrap();
+                MetadataCache::remove_thumbnails(inner.directory.as_ref(), &path);
+                inner.entries.insert(
+                    path,
+                    CacheEntry {
+                        modified,
+                        size,
+                        metadata: metadata.clone(),
+                        analysis: None,
+                    },
+                );
+                inner.changed = true;
+                metadata
+            }
+        };
+
+        // The file may have been found under a different name or location
+        // than it was cached with
+        metadata
+            .map(|metadata| PrintMetadata {
+                file_data,
+                ..metadata
+            })
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Thumbnail of the given print file, at the given dimensions or those
+    /// of the `ThumbnailSize`, as made by `preview::thumbnail`. It is only
+    /// made again if it isn't cached or the file has changed since it was.
+    pub async fn thumbnail(
+        &self,
+        file_data: FileMetadata,
+        size: ThumbnailSize,
+        dimensions: Option<(u32, u32)>,
+    ) -> std::io::Result<FileData> {
+        // Checks the file is valid, and clears old thumbnails if it changed
+        self.print_metadata(file_data.clone())?;
+
+        let path = full_path(&file_data);
+        let thumbnail_path = self.directory().map(|directory| {
+            MetadataCache::thumbnail_path(
+                &directory,
+                &path,
+                &size,
+                dimensions.unwrap_or(size.dimensions()),
+            )
+        });
+
+        let data = match thumbnail_path.as_ref().and_then(|path| fs::read(path).ok()) {
+            Some(data) => data,
+            None => {
+                let data = self
+                    .thumbnails
+                    .run((path, size.clone(), dimensions), move || {
+                        let mut file = Sl1::from_file(file_data)?;
+                        block_on(preview::thumbnail(&mut file, size, dimensions))
+                    })
+                    .await?;
+                if let Some(thumbnail_path) = thumbnail_path {
+                    if let Err(e) = fs::write(&thumbnail_path, &data) {
+                        log::warn!("Unable to cache thumbnail {:?}: {}", thumbnail_path, e);
+                    }
+                }
+                data
+            }
+        };
+
+        Ok(FileData {
+            name: "thumbnail.png".to_string(),
+            data,
+        })
+    }
+
+    /// Measurements of the given print file's layers, in pixels, analysing
+    /// it only if it isn't cached or has changed since it was
+    pub async fn analysis(&self, file_data: FileMetadata) -> std::io::Result<PrintAnalysis> {
+        // Checks the file is valid, and resets its entry if it changed
+        self.print_metadata(file_data.clone())?;
+
+        let path = full_path(&file_data);
+        let (modified, size, entry) = self.current_entry(&path)?;
+
+        let analysis = match entry.and_then(|entry| entry.analysis) {
+            Some(analysis) => analysis,
+            None => {
+                let analysed_file = file_data.clone();
+                let analysis = self
+                    .analyses
+                    .run(path.clone(), move || {
+                        let mut file = Sl1::from_file(analysed_file)?;
+                        block_on(analysis::analyse(&mut file))
+                    })
+                    .await?;
+
+                // Only keep it if the file didn't change while being analysed
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(entry) = inner
+                    .entries
+                    .get_mut(&path)
+                    .filter(|entry| entry.modified == modified && entry.size == size)
+                {
+                    entry.analysis = Some(analysis.clone());
+                    inner.changed = true;
+                }
+                analysis
+            }
+        };
+
+        Ok(PrintAnalysis {
+            file_data,
+            ..analysis
+        })
+    }
+
+    /// Forget everything cached for the given path, or anything under it
+    pub fn invalidate(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let removed: Vec<PathBuf> = inner
+            .entries
+            .keys()
+            .filter(|cached| cached.starts_with(path))
+            .cloned()
+            .collect();
+
+        for cached in removed {
+            log::debug!("Invalidating cached metadata for {:?}", cached);
+            inner.entries.remove(&cached);
+            MetadataCache::remove_thumbnails(inner.directory.as_ref(), &cached);
+            inner.changed = true;
+        }
+    }
+
+    /// Write the cache to its directory, if anything has changed
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(directory) = inner.directory.clone().filter(|_| inner.changed) else {
+            return Ok(());
+        };
+
+        let index = serde_json::to_vec(&inner.entries)?;
+        let temp_path = directory.join(format!(".{}", INDEX_FILE));
+        fs::write(&temp_path, index)?;
+        fs::rename(temp_path, directory.join(INDEX_FILE))?;
+
+        inner.changed = false;
+        Ok(())
+    }
+
+    /// Save the cache, logging rather than returning any error
+    pub fn save_or_log(&self) {
+        if let Err(e) = self.save() {
+            log::error!("Unable to save metadata cache: {}", e);
+        }
+    }
+}
+
+/// Watch the given directory for files changing, invalidating their cached
+/// metadata
+pub async fn watch_files(
+    cache: MetadataCache,
+    directory: PathBuf,
+    cancellation_token: CancellationToken,
+) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })
+    .and_then(|mut watcher| {
+        watcher.watch(&directory, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    });
+    let _watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            log::error!("Unable to watch {:?} for changes: {}", directory, e);
+            return;
+        }
+    };
+
+    let cache_directory = cache.directory();
+
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv() => event,
+            _ = cancellation_token.cancelled() => break,
+        };
+
+        match event {
+            Some(Ok(event)) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                event
+                    .paths
+                    .iter()
+                    // Ignore the cache's own writes, when kept in the watched
+                    // directory
+                    .filter(|path| {
+                        cache_directory
+                            .as_ref()
+                            .is_none_or(|cache_directory| !path.starts_with(cache_directory))
+                    })
+                    .for_each(|path| cache.invalidate(path));
+                cache.save_or_log();
+            }
+            Some(Err(e)) => log::warn!("Error watching {:?}: {}", directory, e),
+            None => break,
+        }
+    }
+}
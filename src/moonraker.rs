@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::api_objects::{ConnectionStatus, PhysicalState};
+use crate::configuration::{Configuration, GcodeConfig, MoonrakerConfig};
+use crate::gcode::render_gcode;
+use crate::printer::{FirmwareRejection, HardwareControl};
+use crate::template::TemplateCache;
+
+/// JSON-RPC error code Moonraker uses when Klipper rejects a gcode script
+const GCODE_ERROR_CODE: i64 = 400;
+/// Seconds to wait for requests which don't run gcode
+const REQUEST_TIMEOUT: u64 = 5;
+/// Number of uncollected gcode responses kept
+const GCODE_RESPONSE_HISTORY: usize = 100;
+
+/// Latest printer status reported by Moonraker, from subscription updates
+/// and explicit queries
+#[derive(Clone, Debug, Default)]
+pub struct MoonrakerStatus {
+    pub z: Option<f64>,
+    pub homed_axes: Option<String>,
+    pub print_stats_state: Option<String>,
+    pub klippy_state: Option<String>,
+    /// Responses to gcode not yet collected by a console command
+    pub gcode_responses: Vec<String>,
+}
+
+impl MoonrakerStatus {
+    /// Merge a (possibly partial) status object into the current status
+    fn update(&mut self, status: &Value) {
+        if let Some(z) = status["toolhead"]["position"]
+            .as_array()
+            .and_then(|position| position.get(2))
+            .and_then(Value::as_f64)
+        {
+            self.z = Some(z);
+        }
+        if let Some(homed_axes) = status["toolhead"]["homed_axes"].as_str() {
+            self.homed_axes = Some(homed_axes.to_string());
+        }
+        if let Some(state) = status["print_stats"]["state"].as_str() {
+            if self.print_stats_state.as_deref() != Some(state) {
+                log::debug!("Klipper print_stats state: {}", state);
+            }
+            self.print_stats_state = Some(state.to_string());
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    params: Value,
+    response: oneshot::Sender<std::io::Result<Value>>,
+}
+
+/// Hardware controller which drives Klipper through Moonraker's JSON-RPC
+/// websocket API, rather than through the klippy.serial pseudo-tty
+pub struct Moonraker {
+    pub config: GcodeConfig,
+    pub moonraker_config: MoonrakerConfig,
+    pub state: PhysicalState,
+    pub gcode_substitutions: HashMap<String, String>,
+    templates: TemplateCache,
+    pub status: Arc<Mutex<MoonrakerStatus>>,
+    requests: Option<mpsc::Sender<Request>>,
+}
+
+impl Moonraker {
+    pub fn new(config: Configuration) -> Moonraker {
+        Moonraker {
+            templates: TemplateCache::new(config.gcode.template_sources()),
+            config: config.gcode,
+            moonraker_config: config.moonraker.unwrap_or_default(),
+            state: PhysicalState {
+                z: 0.0,
+                z_microns: 0,
+                curing: false,
+                homed: false,
+            },
+            gcode_substitutions: HashMap::new(),
+            status: Arc::new(Mutex::new(MoonrakerStatus::default())),
+            requests: None,
+        }
+    }
+
+    /// Connect to Moonraker if not already connected, and subscribe to the
+    /// toolhead and print status
+    async fn connect(&mut self) -> std::io::Result<mpsc::Sender<Request>> {
+        if let Some(requests) = self.requests.as_ref().filter(|r| !r.is_closed()) {
+            return Ok(requests.clone());
+        }
+
+        log::info!("Connecting to Moonraker at {}", self.moonraker_config.url);
+
+        let (websocket, _) = connect_async(self.moonraker_config.url.as_str())
+            .await
+            .map_err(|error| Error::new(ErrorKind::NotConnected, error))?;
+
+        let (sender, receiver) = mpsc::channel(10);
+        tokio::spawn(run_connection(websocket, receiver, self.status.clone()));
+        self.requests = Some(sender.clone());
+
+        let subscription = self
+            .call(
+                "printer.objects.subscribe",
+                json!({
+                    "objects": {
+                        "toolhead": ["position", "homed_axes"],
+                        "print_stats": ["state"],
+                    }
+                }),
+                Duration::from_secs(REQUEST_TIMEOUT),
+            )
+            .await?;
+        self.update_status(&subscription["status"]);
+
+        Ok(sender)
+    }
+
+    /// Make a JSON-RPC request, and await its result
+    async fn call(
+        &mut self,
+        method: &str,
+        params: Value,
+        duration: Duration,
+    ) -> std::io::Result<Value> {
+        let requests = match self.requests.as_ref().filter(|r| !r.is_closed()) {
+            Some(requests) => requests.clone(),
+            None => Box::pin(self.connect()).await?,
+        };
+
+        let (response_sender, response_receiver) = oneshot::channel();
+
+        requests
+            .send(Request {
+                method: method.to_string(),
+                params,
+                response: response_sender,
+            })
+            .await
+            .map_err(|error| Error::new(ErrorKind::BrokenPipe, error))?;
+
+        timeout(duration, response_receiver)
+            .await
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Timed out awaiting Moonraker response to {}", method),
+                )
+            })?
+            .map_err(|error| Error::new(ErrorKind::BrokenPipe, error))?
+    }
+
+    /// Render the given gcode template and run it
+    async fn send_gcode(&mut self, code: String) -> std::io::Result<()> {
+        self.add_state_variables();
+        let script = render_gcode(&mut self.templates, &code, &self.gcode_substitutions)?;
+        self.run_script(script).await
+    }
+
+    /// Run the given gcode script, waiting for Klipper to complete it
+    async fn run_script(&mut self, script: String) -> std::io::Result<()> {
+        log::debug!("Executing gcode script: {}", script.trim_end());
+
+        self.call(
+            "printer.gcode.script",
+            json!({ "script": script }),
+            Duration::from_secs(self.config.move_timeout as u64),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Query the toolhead, to report its actual position
+    async fn query_toolhead(&mut self) -> std::io::Result<()> {
+        let result = self
+            .call(
+                "printer.objects.query",
+                json!({ "objects": { "toolhead": ["position", "homed_axes"] } }),
+                Duration::from_secs(REQUEST_TIMEOUT),
+            )
+            .await?;
+        self.update_status(&result["status"]);
+        Ok(())
+    }
+
+    fn update_status(&mut self, status: &Value) {
+        let (z, homed_axes) = {
+            let mut moonraker_status = self.status.lock().unwrap();
+            moonraker_status.update(status);
+            (moonraker_status.z, moonraker_status.homed_axes.clone())
+        };
+
+        if let Some(homed_axes) = homed_axes {
+            self.state.homed = homed_axes.contains('z');
+        }
+
+        if let Some(z) = z {
+            self.state.z = z;
+            self.state.z_microns = (z.max(0.0) * 1000.0).round() as u32;
+        }
+    }
+
+    fn add_state_variables(&mut self) {
+        self.gcode_substitutions
+            .insert("curing".to_string(), self.state.curing.to_string());
+        self.gcode_substitutions
+            .insert("z".to_string(), self.state.z.to_string());
+    }
+}
+
+/// Pass requests out over the websocket, and route responses and status
+/// notifications back, until the connection closes
+async fn run_connection(
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    mut requests: mpsc::Receiver<Request>,
+    status: Arc<Mutex<MoonrakerStatus>>,
+) {
+    let (mut write, mut read) = websocket.split();
+    let mut pending: HashMap<u64, oneshot::Sender<std::io::Result<Value>>> = HashMap::new();
+    let mut next_id: u64 = 0;
+
+    loop {
+        tokio::select! {
+            request = requests.recv() => match request {
+                Some(request) => {
+                    next_id += 1;
+                    let message = json!({
+                        "jsonrpc": "2.0",
+                        "method": request.method,
+                        "params": request.params,
+                        "id": next_id,
+                    });
+                    match write.send(Message::text(message.to_string())).await {
+                        Ok(_) => {
+                            pending.insert(next_id, request.response);
+                        }
+                        Err(error) => {
+                            let _ = request.response.send(Err(Error::new(ErrorKind::BrokenPipe, error)));
+                            break;
+                        }
+                    }
+                }
+                None => break,
+            },
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    handle_message(text.as_str(), &mut pending, &status);
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => (),
+                Some(Err(error)) => {
+                    log::error!("Error reading from Moonraker: {}", error);
+                    break;
+                }
+            },
+        }
+    }
+
+    log::warn!("Moonraker connection closed");
+    for (_, response) in pending.drain() {
+        let _ = response.send(Err(Error::new(
+            ErrorKind::BrokenPipe,
+            "Moonraker connection closed",
+        )));
+    }
+}
+
+fn handle_message(
+    text: &str,
+    pending: &mut HashMap<u64, oneshot::Sender<std::io::Result<Value>>>,
+    status: &Arc<Mutex<MoonrakerStatus>>,
+) {
+    let Ok(message) = serde_json::from_str::<Value>(text) else {
+        log::warn!("Unable to parse Moonraker message: {}", text);
+        return;
+    };
+
+    if let Some(id) = message["id"].as_u64() {
+        if let Some(response) = pending.remove(&id) {
+            let result = match message.get("error") {
+                Some(error) => {
+                    let message = format!(
+                        "Moonraker reported error: {}",
+                        error["message"].as_str().unwrap_or_default()
+                    );
+                    if error["code"].as_i64() == Some(GCODE_ERROR_CODE) {
+                        Err(FirmwareRejection::error(message))
+                    } else {
+                        Err(Error::other(message))
+                    }
+                }
+                None => Ok(message["result"].clone()),
+            };
+            let _ = response.send(result);
+        }
+        return;
+    }
+
+    match message["method"].as_str() {
+        Some("notify_status_update") => status.lock().unwrap().update(&message["params"][0]),
+        Some("notify_gcode_response") => {
+            let mut status = status.lock().unwrap();
+            if let Some(response) = message["params"][0].as_str() {
+                status.gcode_responses.push(response.to_string());
+            }
+            let overflow = status
+                .gcode_responses
+                .len()
+                .saturating_sub(GCODE_RESPONSE_HISTORY);
+            status.gcode_responses.drain(..overflow);
+        }
+        Some("notify_klippy_ready") => {
+            status.lock().unwrap().klippy_state = Some("ready".to_string())
+        }
+        Some("notify_klippy_shutdown") => {
+            status.lock().unwrap().klippy_state = Some("shutdown".to_string())
+        }
+        Some("notify_klippy_disconnected") => {
+            status.lock().unwrap().klippy_state = Some("disconnected".to_string())
+        }
+        _ => (),
+    }
+}
+
+#[async_trait]
+impl HardwareControl for Moonraker {
+    async fn initialize(&mut self) {
+        if let Err(e) = self.connect().await {
+            log::error!("Unable to connect to Moonraker: {}", e);
+        }
+    }
+
+    async fn is_ready(&mut self) -> bool {
+        match self
+            .call(
+                "server.info",
+                json!({}),
+                Duration::from_secs(REQUEST_TIMEOUT),
+            )
+            .await
+        {
+            Ok(info) => {
+                let klippy_state = info["klippy_state"].as_str().unwrap_or_default();
+                self.status.lock().unwrap().klippy_state = Some(klippy_state.to_string());
+                klippy_state == "ready"
+            }
+            Err(e) => {
+                log::debug!("Moonraker status check failed: {}", e);
+                false
+            }
+        }
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        match &self.requests {
+            Some(requests) if !requests.is_closed() => ConnectionStatus::Connected,
+            _ => ConnectionStatus::Disconnected,
+        }
+    }
+
+    async fn home(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.home_command.clone()).await?;
+        self.query_toolhead().await?;
+
+        Ok(self.state)
+    }
+
+    async fn manual_command(&mut self, command: String) -> std::io::Result<Vec<String>> {
+        // Klipper reports the script's output before completing it
+        self.status.lock().unwrap().gcode_responses.clear();
+        // Entered by hand, so run as it is rather than as a template
+        self.run_script(command).await?;
+        let responses = std::mem::take(&mut self.status.lock().unwrap().gcode_responses);
+
+        self.query_toolhead().await?;
+
+        Ok(responses)
+    }
+
+    async fn move_z(&mut self, z: u32, speed: f64) -> std::io::Result<PhysicalState> {
+        // Convert from mm/s to mm/min f value
+        let speed = speed * 60.0;
+
+        self.state.z = (z as f64) / 1000.0;
+        self.state.z_microns = z;
+        self.add_print_variable("speed".to_string(), speed.to_string());
+
+        // Klipper completes the script once the move is queued, so wait for
+        // the move itself to finish before reporting the position
+        self.send_gcode(format!("{}\nM400", self.config.move_command.trim_end()))
+            .await?;
+
+        self.remove_print_variable("speed".to_string());
+        self.query_toolhead().await?;
+
+        Ok(self.state)
+    }
+
+    async fn start_layer(&mut self, _layer: usize) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.layer_start.clone()).await?;
+
+        Ok(self.state)
+    }
+
+    async fn start_curing(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.cure_start.clone()).await?;
+        self.state.curing = true;
+
+        Ok(self.state)
+    }
+
+    async fn stop_curing(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.cure_end.clone()).await?;
+        self.state.curing = false;
+
+        Ok(self.state)
+    }
+
+    async fn start_print(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.print_start.clone()).await?;
+        self.query_toolhead().await?;
+
+        Ok(self.state)
+    }
+
+    async fn end_print(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.print_end.clone()).await?;
+        self.query_toolhead().await?;
+
+        Ok(self.state)
+    }
+
+    async fn boot(&mut self) -> std::io::Result<PhysicalState> {
+        self.send_gcode(self.config.boot.clone()).await?;
+        self.query_toolhead().await?;
+
+        Ok(self.state)
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.send_gcode(self.config.shutdown.clone()).await?;
+
+        Ok(())
+    }
+
+    fn get_physical_state(&self) -> std::io::Result<PhysicalState> {
+        Ok(self.state)
+    }
+
+    fn add_print_variable(&mut self, variable: String, value: String) {
+        self.gcode_substitutions.insert(variable, value);
+    }
+
+    fn remove_print_variable(&mut self, variable: String) {
+        self.gcode_substitutions.remove(&variable);
+    }
+
+    fn clear_variables(&mut self) {
+        self.gcode_substitutions.clear();
+    }
+}
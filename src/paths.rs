@@ -0,0 +1,64 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+fn escape_error() -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        "Paths must stay within their directory",
+    )
+}
+
+/// Full path of a client-provided path within the given root directory.
+///
+/// The path must be relative, can't contain `..` or hidden components, and
+/// once symlinks are followed must still lead inside the root. It need not
+/// exist, but whichever of its ancestors does is checked instead.
+pub fn resolve_path(root: &Path, file_path: &str) -> std::io::Result<PathBuf> {
+    let file_path = Path::new(file_path);
+
+    let allowed = file_path.components().all(|component| match component {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    if !allowed {
+        return Err(escape_error());
+    }
+
+    let path = root.join(file_path);
+
+    // Anything that exists, including dangling symlinks, must canonicalize
+    // to somewhere inside the root
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.symlink_metadata().is_ok())
+        .unwrap_or(root);
+
+    if !is_within(root, existing) {
+        return Err(escape_error());
+    }
+    Ok(path)
+}
+
+/// Full path of an existing file or directory within the given root, as
+/// checked by `resolve_path`
+pub fn resolve_existing(root: &Path, file_path: &str) -> std::io::Result<PathBuf> {
+    let path = resolve_path(root, file_path)?;
+
+    path.exists()
+        .then_some(path)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Unable to find file"))
+}
+
+/// Whether the path, with all symlinks followed, is inside the root
+pub fn is_within(root: &Path, path: &Path) -> bool {
+    let root = if root.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        root
+    };
+
+    match (root.canonicalize(), path.canonicalize()) {
+        (Ok(root), Ok(path)) => path.starts_with(root),
+        _ => false,
+    }
+}
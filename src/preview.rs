@@ -0,0 +1,362 @@
+use std::io::{Error, ErrorKind};
+
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+
+use crate::{
+    api_objects::{CrossSectionAxis, ThumbnailSize},
+    display::Frame,
+    printfile::PrintFile,
+};
+
+/// Largest width or height a thumbnail can be requested at
+pub const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Image of part of a print, for viewing in a browser. Layers are greyscale,
+/// with one byte per pixel, while thumbnails may be RGBA, with four.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preview {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Preview {
+    /// Decode a PNG, keeping greyscale images as they are and converting
+    /// anything else to RGBA
+    pub fn from_png(data: &[u8]) -> std::io::Result<Preview> {
+        let mut decoder = Decoder::new(data);
+        decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        buffer.truncate(info.buffer_size());
+
+        let (channels, pixels) = match info.color_type {
+            ColorType::Grayscale => (1, buffer),
+            ColorType::GrayscaleAlpha => (
+                4,
+                buffer
+                    .as_chunks::<2>()
+                    .0
+                    .iter()
+                    .flat_map(|&[level, alpha]| [level, level, level, alpha])
+                    .collect(),
+            ),
+            ColorType::Rgb => (
+                4,
+                buffer
+                    .as_chunks::<3>()
+                    .0
+                    .iter()
+                    .flat_map(|&[red, green, blue]| [red, green, blue, 255])
+                    .collect(),
+            ),
+            ColorType::Rgba => (4, buffer),
+            ColorType::Indexed => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unable to expand indexed PNG",
+                ))
+            }
+        };
+
+        Ok(Preview {
+            width: info.width,
+            height: info.height,
+            channels,
+            pixels,
+        })
+    }
+
+    /// Size of the image scaled to fit within the given size, keeping its
+    /// aspect ratio
+    fn fitted_size(&self, max_width: u32, max_height: u32) -> (u32, u32) {
+        // Compare width / height against max_width / max_height without
+        // rounding, to scale by whichever side is the tighter fit
+        if self.width as u64 * max_height as u64 >= self.height as u64 * max_width as u64 {
+            let height = self.height as u64 * max_width as u64 / self.width.max(1) as u64;
+            (max_width, (height as u32).max(1))
+        } else {
+            let width = self.width as u64 * max_height as u64 / self.height.max(1) as u64;
+            ((width as u32).max(1), max_height)
+        }
+    }
+
+    /// Scale the image to the given size. Each pixel averages the block of
+    /// source pixels it covers, or repeats the nearest one when enlarging.
+    pub fn resize(self, width: u32, height: u32) -> Preview {
+        if (width, height) == (self.width, self.height) {
+            return self;
+        }
+
+        let span = |index: u32, length: u32, scaled: u32| {
+            let start = (index as u64 * length as u64 / scaled as u64) as usize;
+            let end = ((index as u64 + 1) * length as u64 / scaled as u64) as usize;
+            start..end.max(start + 1)
+        };
+
+        let channels = self.channels;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * channels);
+        for y in 0..height {
+            let rows = span(y, self.height, height);
+            for x in 0..width {
+                let columns = span(x, self.width, width);
+
+                let mut totals = vec![0u64; channels];
+                for row in rows.clone() {
+                    let offset = row * self.width as usize;
+                    for column in columns.clone() {
+                        let pixel = (offset + column) * channels;
+                        for (total, &level) in
+                            totals.iter_mut().zip(&self.pixels[pixel..pixel + channels])
+                        {
+                            *total += level as u64;
+                        }
+                    }
+                }
+
+                let count = (rows.len() * columns.len()) as u64;
+                pixels.extend(totals.into_iter().map(|total| (total / count) as u8));
+            }
+        }
+
+        Preview {
+            width,
+            height,
+            channels,
+            pixels,
+        }
+    }
+
+    /// Shrink the image to fit within the given size. Images which already
+    /// fit are left as they are.
+    pub fn downscale(self, max_size: u32) -> Preview {
+        if max_size == 0 || self.width.max(self.height) <= max_size {
+            return self;
+        }
+
+        let (width, height) = self.fitted_size(max_size, max_size);
+        self.resize(width, height)
+    }
+
+    /// Scale the image to fit the given size, enlarging it if need be, and
+    /// center it on a transparent, or for greyscale images black, background
+    /// so it is exactly that size
+    pub fn letterbox(self, width: u32, height: u32) -> Preview {
+        let (scaled_width, scaled_height) = self.fitted_size(width, height);
+        let scaled = self.resize(scaled_width, scaled_height);
+        if (scaled_width, scaled_height) == (width, height) {
+            return scaled;
+        }
+
+        let channels = scaled.channels;
+        let left = ((width - scaled_width) / 2) as usize;
+        let top = ((height - scaled_height) / 2) as usize;
+        let row_length = width as usize * channels;
+        let scaled_row_length = scaled_width as usize * channels;
+
+        let mut pixels = vec![0; row_length * height as usize];
+        for (row, scaled_row) in scaled.pixels.chunks_exact(scaled_row_length).enumerate() {
+            let offset = (top + row) * row_length + left * channels;
+            pixels[offset..offset + scaled_row_length].copy_from_slice(scaled_row);
+        }
+
+        Preview {
+            width,
+            height,
+            channels,
+            pixels,
+        }
+    }
+
+    pub fn to_png(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        let mut encoder = Encoder::new(&mut data, self.width, self.height);
+        encoder.set_color(match self.channels {
+            1 => ColorType::Grayscale,
+            _ => ColorType::Rgba,
+        });
+        encoder.set_depth(BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.pixels))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(data)
+    }
+}
+
+impl From<Frame> for Preview {
+    fn from(frame: Frame) -> Preview {
+        Preview {
+            width: frame.width,
+            height: frame.height,
+            channels: 1,
+            pixels: frame.buffer,
+        }
+    }
+}
+
+async fn load_frame(
+    file: &mut (dyn PrintFile + Send),
+    layer: usize,
+) -> std::io::Result<Option<Frame>> {
+    match file.get_layer_data(layer).await {
+        Some(layer) => Frame::from_vec(layer.file_name, layer.exposure_time, layer.data).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// A single layer, as it would be shown on the printer's display
+pub async fn layer_preview(
+    file: &mut (dyn PrintFile + Send),
+    layer: usize,
+) -> std::io::Result<Preview> {
+    load_frame(file, layer)
+        .await?
+        .map(Preview::from)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Layer {} not found", layer)))
+}
+
+/// Side view of the print, with a row of pixels for each layer, the last at
+/// the top. Given a position along the other axis, the print is cut through
+/// there, otherwise each row shows the brightest pixel through the layer so
+/// the print's whole outline is visible.
+pub async fn cross_section(
+    file: &mut (dyn PrintFile + Send),
+    axis: CrossSectionAxis,
+    position: Option<u32>,
+) -> std::io::Result<Preview> {
+    let layer_count = file.get_layer_count();
+    let mut rows: Vec<Vec<u8>> = Vec::with_capacity(layer_count);
+
+    for layer in 0..layer_count {
+        let frame = load_frame(file, layer)
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Layer {} not found", layer)))?;
+        let (width, height) = (frame.width as usize, frame.height as usize);
+
+        // The other axis, which is collapsed into each row
+        let depth = match axis {
+            CrossSectionAxis::X => height,
+            CrossSectionAxis::Y => width,
+        };
+        let cut = match position {
+            Some(position) if position as usize >= depth => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Position {} is outside the layer", position),
+                ))
+            }
+            Some(position) => position as usize..position as usize + 1,
+            None => 0..depth,
+        };
+
+        let row: Vec<u8> = match axis {
+            CrossSectionAxis::X => (0..width)
+                .map(|x| {
+                    cut.clone()
+                        .map(|y| frame.buffer[y * width + x])
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect(),
+            CrossSectionAxis::Y => (0..height)
+                .map(|y| {
+                    frame.buffer[y * width..(y + 1) * width][cut.clone()]
+                        .iter()
+                        .copied()
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect(),
+        };
+
+        if rows.first().is_some_and(|first| first.len() != row.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Layer {} is a different size to the others", layer),
+            ));
+        }
+        rows.push(row);
+    }
+
+    Ok(Preview {
+        width: rows.first().map_or(0, |row| row.len()) as u32,
+        height: rows.len() as u32,
+        channels: 1,
+        pixels: rows.into_iter().rev().flatten().collect(),
+    })
+}
+
+/// Top-down view of the print, showing the brightest pixel through all of its
+/// layers, so everything that will be cured is visible
+pub async fn silhouette(file: &mut (dyn PrintFile + Send)) -> std::io::Result<Preview> {
+    let mut silhouette: Option<Preview> = None;
+
+    for layer in 0..file.get_layer_count() {
+        let frame = load_frame(file, layer)
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Layer {} not found", layer)))?;
+
+        match silhouette.as_mut() {
+            None => silhouette = Some(Preview::from(frame)),
+            Some(silhouette) => {
+                if (frame.width, frame.height) != (silhouette.width, silhouette.height) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Layer {} is a different size to the others", layer),
+                    ));
+                }
+                for (pixel, level) in silhouette.pixels.iter_mut().zip(frame.buffer) {
+                    *pixel = (*pixel).max(level);
+                }
+            }
+        }
+    }
+
+    silhouette.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Print file has no layers"))
+}
+
+/// Thumbnail of the print as a PNG, exactly the given size or that of the
+/// `ThumbnailSize`. The file's own thumbnail is used where it has one, scaled
+/// if need be, otherwise one is rendered from the print's silhouette.
+pub async fn thumbnail(
+    file: &mut (dyn PrintFile + Send),
+    size: ThumbnailSize,
+    dimensions: Option<(u32, u32)>,
+) -> std::io::Result<Vec<u8>> {
+    let (width, height) = dimensions.unwrap_or(size.dimensions());
+    if width == 0 || height == 0 || width.max(height) > MAX_THUMBNAIL_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Thumbnails must be between 1 and {} pixels in each dimension",
+                MAX_THUMBNAIL_SIZE
+            ),
+        ));
+    }
+
+    let embedded = match file.get_thumbnail(size.clone()) {
+        Ok(thumbnail) if (width, height) == size.dimensions() => return Ok(thumbnail.data),
+        Ok(thumbnail) => Preview::from_png(&thumbnail.data),
+        Err(e) => Err(e),
+    };
+
+    let image = match embedded {
+        Ok(image) => image,
+        Err(e) => {
+            log::debug!("Rendering thumbnail from layers instead: {}", e);
+            silhouette(file).await?
+        }
+    };
+
+    image.letterbox(width, height).to_png()
+}
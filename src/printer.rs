@@ -1,21 +1,71 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
+use crate::analysis::pixel_area;
+use crate::api_objects::ConnectionStatus;
+use crate::api_objects::ConsoleResponse;
 use crate::api_objects::DisplayTest;
 use crate::api_objects::FileMetadata;
+use crate::api_objects::LayerMovement;
 use crate::api_objects::PhysicalState;
 use crate::api_objects::PrintMetadata;
 use crate::api_objects::PrinterState;
 use crate::api_objects::PrinterStatus;
 use crate::configuration::*;
 use crate::display::*;
+use crate::lift::lift_for_area;
 use crate::printfile::Layer;
 use crate::printfile::PrintFile;
 use crate::sl1::*;
 use tokio::time::{interval, sleep, Duration};
 
+/// How the plate moves between layers, and how long it waits around curing
+#[derive(Clone, Debug)]
+struct Movement {
+    lift: u32,
+    up_speed: f64,
+    down_speed: f64,
+    wait_before_exposure: f64,
+    wait_after_exposure: f64,
+    /// Curve picking each layer's lift by its lit area instead, along with
+    /// the display's pixel pitch in mm
+    lift_curve: Option<(Vec<LiftCurvePoint>, f64)>,
+}
+
+impl Movement {
+    /// Lift move for the given layer
+    fn for_layer(&self, frame: &Frame) -> LayerMovement {
+        let (lift, up_speed, down_speed, area) = match &self.lift_curve {
+            Some((curve, pixel_pitch)) => {
+                let area = pixel_area(frame) * pixel_pitch * pixel_pitch;
+                match lift_for_area(curve, area) {
+                    Some(point) => (
+                        (point.lift * 1000.0).trunc() as u32,
+                        point.up_speed,
+                        point.down_speed,
+                        Some(area),
+                    ),
+                    None => (self.lift, self.up_speed, self.down_speed, None),
+                }
+            }
+            None => (self.lift, self.up_speed, self.down_speed, None),
+        };
+
+        LayerMovement {
+            lift: lift as f64 / 1000.0,
+            lift_microns: lift,
+            up_speed,
+            down_speed,
+            area,
+        }
+    }
+}
+
 pub struct Printer<T: HardwareControl> {
     pub config: PrinterConfig,
     pub display: PrintDisplay,
@@ -23,6 +73,7 @@ pub struct Printer<T: HardwareControl> {
     pub state: PrinterState,
     pub operation_receiver: mpsc::Receiver<Operation>,
     pub status_sender: broadcast::Sender<PrinterState>,
+    pub console_sender: broadcast::Sender<ConsoleResponse>,
 }
 
 impl<T: HardwareControl> Printer<T> {
@@ -32,6 +83,7 @@ impl<T: HardwareControl> Printer<T> {
         mut hardware_controller: T,
         operation_receiver: mpsc::Receiver<Operation>,
         status_sender: broadcast::Sender<PrinterState>,
+        console_sender: broadcast::Sender<ConsoleResponse>,
         cancellation_token: CancellationToken,
     ) {
         hardware_controller.add_print_variable("max_z".to_string(), config.max_z.to_string());
@@ -46,15 +98,20 @@ impl<T: HardwareControl> Printer<T> {
                 print_data: None,
                 paused: None,
                 layer: None,
+                movement: None,
+                error: None,
                 physical_state: PhysicalState {
                     z: 0.0,
                     z_microns: 0,
                     curing: false,
+                    homed: false,
                 },
                 status: PrinterStatus::Shutdown,
+                connection: ConnectionStatus::Disconnected,
             },
             operation_receiver,
             status_sender,
+            console_sender,
         };
 
         printer.start_statemachine(cancellation_token).await
@@ -62,25 +119,35 @@ impl<T: HardwareControl> Printer<T> {
 
     pub async fn print_event_loop(&mut self) {
         let mut file: Box<dyn PrintFile + Send> =
-            Box::new(Sl1::from_file(self.get_file_data().unwrap()));
+            match Sl1::from_file(self.get_file_data().unwrap()) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    log::error!("Unable to load print file, cancelling print: {}", e);
+                    self.state.error = Some(format!("Unable to load print file: {}", e));
+                    self.update_idle_state(self.state.physical_state).await;
+                    return;
+                }
+            };
 
         let layer_height = file.get_layer_height();
 
         // Get movement values from file, or configured defaults
-        let lift = file
-            .get_lift()
-            .unwrap_or((self.config.default_lift * 1000.0).trunc() as u32);
-        let up_speed = file.get_up_speed().unwrap_or(self.config.default_up_speed);
-        let down_speed = file
-            .get_down_speed()
-            .unwrap_or(self.config.default_down_speed);
-
-        let wait_before_exposure = file
-            .get_wait_before_exposure()
-            .unwrap_or(self.config.default_wait_before_exposure);
-        let wait_after_exposure = file
-            .get_wait_after_exposure()
-            .unwrap_or(self.config.default_wait_after_exposure);
+        let movement = Movement {
+            lift: file
+                .get_lift()
+                .unwrap_or((self.config.default_lift * 1000.0).trunc() as u32),
+            up_speed: file.get_up_speed().unwrap_or(self.config.default_up_speed),
+            down_speed: file
+                .get_down_speed()
+                .unwrap_or(self.config.default_down_speed),
+            wait_before_exposure: file
+                .get_wait_before_exposure()
+                .unwrap_or(self.config.default_wait_before_exposure),
+            wait_after_exposure: file
+                .get_wait_after_exposure()
+                .unwrap_or(self.config.default_wait_after_exposure),
+            lift_curve: self.lift_curve(),
+        };
 
         let mut pause_interv = interval(Duration::from_millis(100));
 
@@ -88,6 +155,7 @@ impl<T: HardwareControl> Printer<T> {
             "total_layers".to_string(),
             file.get_layer_count().to_string(),
         );
+        self.add_metadata_variables(file.get_metadata());
 
         // Execute start_print command, then report state
         self.wrapped_start_print().await;
@@ -109,7 +177,7 @@ impl<T: HardwareControl> Printer<T> {
                     } else {
                         match optional_frame {
                             // More frames exist, continue printing
-                            Some(cur_frame) => {
+                            Ok(Some(cur_frame)) => {
                                 self.hardware_controller
                                     .add_print_variable("layer".to_string(), layer.to_string());
                                 // Start a task to fetch and generate the next
@@ -120,27 +188,37 @@ impl<T: HardwareControl> Printer<T> {
 
                                 // Print the current frame by moving into
                                 // position and curing
-                                self.print_frame(
-                                    cur_frame,
-                                    layer,
-                                    layer_height,
-                                    lift,
-                                    up_speed,
-                                    down_speed,
-                                    wait_before_exposure,
-                                    wait_after_exposure,
-                                )
-                                .await;
-
-                                // Await generation of the next frame
-                                optional_frame =
-                                    gen_next_frame.await.expect("Layer generation task failed");
-
-                                // Bump current layer
-                                self.set_layer(layer + 1).await;
+                                let completed = self
+                                    .print_frame(&cur_frame, layer, layer_height, &movement)
+                                    .await;
+
+                                if completed {
+                                    // Await generation of the next frame
+                                    optional_frame =
+                                        gen_next_frame.await.expect("Layer generation task failed");
+
+                                    // Bump current layer
+                                    self.set_layer(layer + 1).await;
+                                } else {
+                                    // Retry the current frame once resumed
+                                    gen_next_frame.abort();
+                                    optional_frame = Ok(Some(cur_frame));
+                                }
                             }
                             // No more frames remain, end print
-                            None => self.end_print().await,
+                            Ok(None) => self.end_print().await,
+                            // The frame could not be decoded, so the print
+                            // can't continue until it can be, if ever
+                            Err(ref e) => {
+                                log::error!("Unable to load layer {}, pausing print: {}", layer, e);
+                                self.state.error =
+                                    Some(format!("Unable to load layer {}: {}", layer, e));
+                                self.pause_print().await;
+
+                                // Try again once resumed
+                                optional_frame =
+                                    Frame::from_layer(file.get_layer_data(layer).await).await;
+                            }
                         }
                     }
                 }
@@ -149,18 +227,43 @@ impl<T: HardwareControl> Printer<T> {
         }
     }
 
+    /// The configured lift curve, if it can be used
+    fn lift_curve(&self) -> Option<(Vec<LiftCurvePoint>, f64)> {
+        let curve = self.config.lift_curve.clone()?;
+
+        if curve.is_empty() {
+            log::warn!("Ignoring empty printer lift_curve");
+            return None;
+        }
+        match self.display.config.pixel_pitch.filter(|pitch| *pitch > 0.0) {
+            Some(pixel_pitch) => Some((curve, pixel_pitch)),
+            None => {
+                log::warn!("Ignoring printer lift_curve, as the display has no pixel_pitch");
+                None
+            }
+        }
+    }
+
+    /// Print a single frame, returning whether it completed. If the print was
+    /// paused or stopped partway through, the frame needs printing again.
     async fn print_frame(
         &mut self,
-        cur_frame: Frame,
+        cur_frame: &Frame,
         layer: usize,
         layer_height: u32,
-        lift: u32,
-        up_speed: f64,
-        down_speed: f64,
-        wait_before_exposure: f64,
-        wait_after_exposure: f64,
-    ) {
+        movement: &Movement,
+    ) -> bool {
         log::info!("Begin layer {}", layer);
+        let layer_movement = movement.for_layer(cur_frame);
+        if let Some(area) = layer_movement.area {
+            log::info!(
+                "Lifting {}mm at {} for {:.1}mm² layer",
+                layer_movement.lift,
+                layer_movement.up_speed,
+                area
+            );
+        }
+        self.state.movement = Some(layer_movement);
         self.wrapped_start_layer(layer).await;
         let layer_z = ((layer + 1) as u32) * layer_height;
         //let lift_z = layer_z+
@@ -170,86 +273,211 @@ impl<T: HardwareControl> Printer<T> {
         // Move the plate up first, then down into position
         log::info!("Moving to layer position {}", layer_z);
 
-        self.wrapped_move(layer_z + lift, up_speed).await;
-        self.wrapped_move(layer_z, down_speed).await;
+        self.wrapped_move(
+            layer_z + layer_movement.lift_microns,
+            layer_movement.up_speed,
+        )
+        .await;
+        self.wrapped_move(layer_z, layer_movement.down_speed).await;
+
+        if !self.is_actively_printing() {
+            return false;
+        }
+
+        // Display the current frame to the LCD once the pre-exposure wait is
+        // over, or already now if configured to
+        let mut pending_frame = Some(cur_frame);
+        if self.config.early_layer_display.unwrap_or(false) {
+            self.load_frame(&mut pending_frame);
+        }
 
         // Wait for configured time before curing
-        log::info!("Waiting for {}s before cure", wait_before_exposure);
-        sleep(Duration::from_secs_f64(wait_before_exposure)).await;
+        log::info!("Waiting for {}s before cure", movement.wait_before_exposure);
+        sleep(Duration::from_secs_f64(movement.wait_before_exposure)).await;
 
-        // Display the current frame to the LCD
-        log::info!("Loading layer to display");
-        self.display.display_frame(cur_frame);
+        self.load_frame(&mut pending_frame);
 
         // Activate the UV array for the prescribed length of time
         log::info!("Curing layer for {}s", exposure_time);
         self.wrapped_start_cure().await;
-        sleep(Duration::from_secs_f64(exposure_time)).await;
+        if self.is_actively_printing() {
+            sleep(Duration::from_secs_f64(exposure_time)).await;
+        }
+        // Always stop curing, even if starting it failed
         self.wrapped_stop_cure().await;
 
+        if !self.is_actively_printing() {
+            return false;
+        }
+
         // Wait for configured time after curing
-        log::info!("Waiting for {}s after cure", wait_after_exposure);
-        sleep(Duration::from_secs_f64(wait_after_exposure)).await;
+        log::info!("Waiting for {}s after cure", movement.wait_after_exposure);
+        sleep(Duration::from_secs_f64(movement.wait_after_exposure)).await;
+
+        true
     }
 
-    async fn wrapped_start_print(&mut self) {
-        if let Ok(physical_state) = self.hardware_controller.start_print().await {
-            self.update_physical_state(physical_state).await;
-        } else {
+    /// Firmware rejecting a command, or its gcode failing to render, during
+    /// an active print pauses it, so the operator can intervene, while outside
+    /// of an active print the command is simply reported as failed. Any other
+    /// hardware error shuts down.
+    async fn handle_hardware_error(&mut self, error: std::io::Error) {
+        if error.kind() == ErrorKind::NotConnected {
+            log::error!("Hardware error: {}", error);
+            self.handle_disconnect().await;
+        } else if !FirmwareRejection::is(&error) {
+            log::error!("Hardware error, shutting down: {}", error);
             self.shutdown().await;
+        } else if self.is_actively_printing() {
+            // Pause in place, as further moves may fail the same way
+            log::error!("Firmware error, pausing print: {}", error);
+            self.display.blank();
+            self.update_paused(true).await;
+        } else {
+            log::error!("Firmware error: {}", error);
+        }
+    }
+
+    /// Track the hardware's connection status, handling the loss of the
+    /// connection if not already shut down
+    async fn check_connection(&mut self) {
+        let connection = self.hardware_controller.connection_status();
+
+        if connection == ConnectionStatus::Disconnected
+            && !matches!(self.state.status, PrinterStatus::Shutdown)
+        {
+            self.handle_disconnect().await;
+        } else if connection != self.state.connection {
+            log::info!("Hardware connection status: {:?}", connection);
+            self.state.connection = connection;
+            self.send_status().await;
+        }
+    }
+
+    /// Pause any active print, then shut down until the hardware is back
+    async fn handle_disconnect(&mut self) {
+        log::error!("Lost connection to hardware, shutting down");
+        self.state.connection = ConnectionStatus::Disconnected;
+        self.display.blank();
+        if matches!(self.state.status, PrinterStatus::Printing) {
+            self.update_paused(true).await;
+        }
+        self.shutdown().await;
+    }
+
+    fn is_actively_printing(&self) -> bool {
+        matches!(self.state.status, PrinterStatus::Printing) && self.state.paused == Some(false)
+    }
+
+    // Push the frame to the display, if it hasn't been already
+    fn load_frame(&mut self, pending_frame: &mut Option<&Frame>) {
+        if let Some(frame) = pending_frame.take() {
+            log::info!("Loading layer to display");
+            self.display.display_frame(frame);
+        }
+    }
+
+    async fn wrapped_start_print(&mut self) {
+        match self.hardware_controller.start_print().await {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
     async fn wrapped_start_layer(&mut self, layer: usize) {
-        if let Ok(physical_state) = self.hardware_controller.start_layer(layer).await {
-            self.update_physical_state(physical_state).await;
-        } else {
-            self.shutdown().await;
+        match self.hardware_controller.start_layer(layer).await {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
-    // Execute command and update printer state
-    async fn wrapped_command(&mut self, command: String) {
-        if let Ok(physical_state) = self.hardware_controller.manual_command(command).await {
-            self.update_physical_state(physical_state).await;
-        } else {
-            self.shutdown().await;
+    // Execute command, pass its response on to the console and update
+    // printer state
+    async fn wrapped_command(&mut self, command: String, id: u64) {
+        let result = self
+            .hardware_controller
+            .manual_command(command.clone())
+            .await;
+
+        let _ = self.console_sender.send(ConsoleResponse {
+            id,
+            command,
+            lines: result.as_ref().cloned().unwrap_or_default(),
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+
+        match result.and_then(|_| self.hardware_controller.get_physical_state()) {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
+    // Answer a console command which can't be run in the current state
+    fn reject_command(&self, command: String, id: u64) {
+        log::warn!("Rejecting console command while not idle: {}", command);
+        let _ = self.console_sender.send(ConsoleResponse {
+            id,
+            command,
+            lines: Vec::new(),
+            error: Some("Printer must be idle to run console commands".to_string()),
+        });
+    }
+
     // Home and update printer state
     async fn wrapped_home(&mut self) {
-        if let Ok(physical_state) = self.hardware_controller.home().await {
-            self.update_physical_state(physical_state).await;
-        } else {
-            self.shutdown().await;
+        match self.hardware_controller.home().await {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
     // Move and update printer state
     async fn wrapped_move(&mut self, z: u32, speed: f64) {
-        if let Ok(physical_state) = self.hardware_controller.move_z(z, speed).await {
-            self.update_physical_state(physical_state).await;
-        } else {
-            self.shutdown().await;
+        match self.hardware_controller.move_z(z, speed).await {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
     // Start cure and update printer state
     async fn wrapped_start_cure(&mut self) {
-        if let Ok(physical_state) = self.hardware_controller.start_curing().await {
-            self.update_physical_state(physical_state).await;
-        } else {
-            self.shutdown().await;
+        match self.hardware_controller.start_curing().await {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) => self.handle_hardware_error(e).await,
         }
     }
 
-    // Stop cure and update printer state
+    // Stop cure, blank the display, and update printer state. The UV may
+    // still be on if curing can't be stopped, so retry once then shut down
     async fn wrapped_stop_cure(&mut self) {
-        if let Ok(physical_state) = self.hardware_controller.stop_curing().await {
-            self.update_physical_state(physical_state).await;
+        let stop_result = self.hardware_controller.stop_curing().await;
+        self.display.blank();
+
+        let stop_result = match stop_result {
+            Err(e) if e.kind() != ErrorKind::NotConnected => {
+                log::error!("Unable to stop curing, retrying: {}", e);
+                self.hardware_controller.stop_curing().await
+            }
+            result => result,
+        };
+
+        match stop_result {
+            Ok(physical_state) => self.update_physical_state(physical_state).await,
+            Err(e) if e.kind() == ErrorKind::NotConnected => self.handle_hardware_error(e).await,
+            Err(e) => {
+                log::error!("Unable to stop curing, shutting down: {}", e);
+                self.state.error = Some(format!("Unable to stop curing: {}", e));
+                self.shutdown().await;
+            }
+        }
+    }
+
+    // Move only once homed, as the plate's position is unknown before then
+    async fn manual_move(&mut self, z: u32) {
+        if self.state.physical_state.homed {
+            self.wrapped_move(z, self.config.default_up_speed).await;
         } else {
-            self.shutdown().await;
+            log::warn!("Refusing manual move to {}, plate has not been homed", z);
         }
     }
 
@@ -268,16 +496,26 @@ impl<T: HardwareControl> Printer<T> {
     pub async fn start_print(&mut self, file_data: FileMetadata) {
         log::info!("Starting Print");
 
-        let print_data = Sl1::from_file(file_data).get_metadata();
-        self.enter_printing_state(print_data).await;
+        match Sl1::from_file(file_data) {
+            Ok(file) => self.enter_printing_state(file.get_metadata()).await,
+            Err(e) => {
+                log::error!("Unable to start print, invalid print file: {}", e);
+                self.state.error = Some(format!("Invalid print file: {}", e));
+                self.send_status().await;
+            }
+        }
     }
 
     async fn end_print(&mut self) {
+        self.display.blank();
         if let Ok(physical_state) = self.hardware_controller.end_print().await {
-            self.hardware_controller
-                .remove_print_variable("total_layers".to_string());
-            self.hardware_controller
-                .remove_print_variable("layer".to_string());
+            for variable in ["total_layers", "layer"]
+                .into_iter()
+                .chain(METADATA_VARIABLES)
+            {
+                self.hardware_controller
+                    .remove_print_variable(variable.to_string());
+            }
             self.update_idle_state(physical_state).await;
             log::info!("Print complete.");
         } else {
@@ -285,7 +523,23 @@ impl<T: HardwareControl> Printer<T> {
         }
     }
 
+    /// Make the print's metadata available to the gcode templates
+    fn add_metadata_variables(&mut self, metadata: PrintMetadata) {
+        let values = [
+            metadata.file_data.name,
+            (metadata.layer_height_microns as f64 / 1000.0).to_string(),
+            metadata.print_time.to_string(),
+            metadata.used_material.to_string(),
+        ];
+
+        for (variable, value) in METADATA_VARIABLES.into_iter().zip(values) {
+            self.hardware_controller
+                .add_print_variable(variable.to_string(), value);
+        }
+    }
+
     async fn pause_print(&mut self) {
+        self.display.blank();
         self.update_paused(true).await;
         self.wrapped_move(
             ((self.config.max_z * 1000.0).trunc() as u32).min(
@@ -298,6 +552,7 @@ impl<T: HardwareControl> Printer<T> {
     }
 
     async fn resume_print(&mut self) {
+        self.state.error = None;
         self.update_paused(false).await;
     }
 
@@ -323,13 +578,26 @@ impl<T: HardwareControl> Printer<T> {
     }
 
     async fn display_file_layer(&mut self, file_data: FileMetadata, layer: usize) {
-        let mut file: Box<dyn PrintFile + Send> = Box::new(Sl1::from_file(file_data.clone()));
-
-        let optional_frame = Frame::from_layer(file.get_layer_data(layer).await).await;
+        let mut file: Box<dyn PrintFile + Send> = match Sl1::from_file(file_data.clone()) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                log::error!("Unable to load {} to display: {}", file_data.name, e);
+                return;
+            }
+        };
 
-        if let Some(frame) = optional_frame {
-            log::info!("Loading layer {} from {} to display", layer, file_data.name);
-            self.display.display_frame(frame);
+        match Frame::from_layer(file.get_layer_data(layer).await).await {
+            Ok(Some(frame)) => {
+                log::info!("Loading layer {} from {} to display", layer, file_data.name);
+                self.display.display_frame(&frame);
+            }
+            Ok(None) => log::warn!("Layer {} not found in {}", layer, file_data.name),
+            Err(e) => log::error!(
+                "Unable to load layer {} from {}: {}",
+                layer,
+                file_data.name,
+                e
+            ),
         }
     }
 
@@ -342,8 +610,11 @@ impl<T: HardwareControl> Printer<T> {
                     print_data: Some(print_data),
                     paused: Some(false),
                     layer: Some(0),
+                    movement: None,
+                    error: None,
                     physical_state: self.state.physical_state,
                     status: PrinterStatus::Printing,
+                    connection: self.state.connection,
                 };
             }
             PrinterStatus::Printing => {
@@ -363,7 +634,7 @@ impl<T: HardwareControl> Printer<T> {
             PrinterStatus::Idle => {
                 self.state.physical_state = new_physical_state;
             }
-            PrinterStatus::Shutdown {} => (),
+            PrinterStatus::Shutdown => (),
         }
         self.send_status().await;
     }
@@ -386,6 +657,7 @@ impl<T: HardwareControl> Printer<T> {
         /*if !self.verify_hardware().await {
             return;
         }*/
+        self.check_connection().await;
 
         let mut op_result = self.operation_receiver.try_recv();
 
@@ -399,6 +671,7 @@ impl<T: HardwareControl> Printer<T> {
                 Operation::ManualMove { z } => {
                     self.paused_move(z, self.config.default_up_speed).await
                 }
+                Operation::ManualCommand { command, id } => self.reject_command(command, id),
                 _ => (),
             };
             op_result = self.operation_receiver.try_recv();
@@ -428,6 +701,7 @@ impl<T: HardwareControl> Printer<T> {
 
     pub async fn shutdown(&mut self) {
         log::info!("Shutting down.");
+        self.display.blank();
         // If hardware still running, execute shutdown commands
         if self.hardware_controller.is_ready().await {
             if (self.hardware_controller.shutdown().await).is_ok() {
@@ -439,11 +713,14 @@ impl<T: HardwareControl> Printer<T> {
         self.state.status = PrinterStatus::Shutdown;
         self.state.paused = None;
         self.state.print_data = None;
+        self.state.movement = None;
         self.state.physical_state = PhysicalState {
             z: f64::MAX,
             z_microns: u32::MAX,
             curing: false,
-        }
+            homed: false,
+        };
+        self.send_status().await;
     }
 
     /*
@@ -475,6 +752,9 @@ impl<T: HardwareControl> Printer<T> {
                 PrinterStatus::Shutdown => self.shutdown_event_loop().await,
             }
         }
+
+        // Leave nothing on the LCD once Odyssey exits
+        self.display.blank();
     }
 
     async fn shutdown_event_loop(&mut self) {
@@ -482,6 +762,7 @@ impl<T: HardwareControl> Printer<T> {
 
         loop {
             self.shutdown_operation_handler().await;
+            self.check_connection().await;
 
             match self.state.status {
                 PrinterStatus::Shutdown => {
@@ -501,22 +782,27 @@ impl<T: HardwareControl> Printer<T> {
         let mut op_result = self.operation_receiver.try_recv();
 
         while let Ok(operation) = op_result {
-            if let Operation::QueryState = operation {
-                self.send_status().await
+            match operation {
+                Operation::QueryState => self.send_status().await,
+                Operation::ManualCommand { command, id } => self.reject_command(command, id),
+                _ => (),
             }
             op_result = self.operation_receiver.try_recv();
         }
     }
 
     async fn set_idle(&mut self) {
+        self.display.blank();
         self.state.status = PrinterStatus::Idle;
         self.state.layer = None;
         self.state.paused = None;
+        self.state.movement = None;
         self.send_status().await;
     }
 
     async fn update_idle_state(&mut self, physical_state: PhysicalState) {
         self.state.status = PrinterStatus::Idle;
+        self.state.movement = None;
         self.state.physical_state = physical_state;
         self.send_status().await;
     }
@@ -525,6 +811,10 @@ impl<T: HardwareControl> Printer<T> {
         /*if !self.verify_hardware().await {
             return;
         }*/
+        self.check_connection().await;
+        if matches!(self.state.status, PrinterStatus::Shutdown) {
+            return;
+        }
 
         let mut op_result = self.operation_receiver.try_recv();
 
@@ -532,11 +822,9 @@ impl<T: HardwareControl> Printer<T> {
             match operation {
                 Operation::QueryState => self.send_status().await,
                 Operation::StartPrint { file_data } => self.start_print(file_data).await,
-                Operation::ManualCommand { command } => self.wrapped_command(command).await,
+                Operation::ManualCommand { command, id } => self.wrapped_command(command, id).await,
                 Operation::ManualHome => self.wrapped_home().await,
-                Operation::ManualMove { z } => {
-                    self.wrapped_move(z, self.config.default_up_speed).await
-                }
+                Operation::ManualMove { z } => self.manual_move(z).await,
                 Operation::ManualCure { cure } => {
                     if cure {
                         self.wrapped_start_cure().await;
@@ -573,13 +861,12 @@ impl<T: HardwareControl> Printer<T> {
 }
 
 impl Frame {
-    async fn from_layer(layer: Option<Layer>) -> Option<Frame> {
-        if layer.is_some() {
-            let layer = layer.unwrap();
-            let frame = Frame::from_vec(layer.file_name, layer.exposure_time, layer.data);
-            return Some(frame);
+    async fn from_layer(layer: Option<Layer>) -> std::io::Result<Option<Frame>> {
+        if let Some(layer) = layer {
+            let frame = Frame::from_vec(layer.file_name, layer.exposure_time, layer.data)?;
+            return Ok(Some(frame));
         }
-        None
+        Ok(None)
     }
 }
 
@@ -600,6 +887,8 @@ pub enum Operation {
     ManualHome,
     ManualCommand {
         command: String,
+        /// Identifies the command's `ConsoleResponse`
+        id: u64,
     },
     ManualDisplayLayer {
         file_data: FileMetadata,
@@ -612,12 +901,41 @@ pub enum Operation {
     Shutdown,
 }
 
+/// Command rejected by the firmware, which hardware controllers report
+/// wrapped in a `std::io::Error` to tell it apart from other failures
+#[derive(Debug)]
+pub struct FirmwareRejection(pub String);
+
+impl FirmwareRejection {
+    pub fn error(message: impl Into<String>) -> Error {
+        Error::other(FirmwareRejection(message.into()))
+    }
+
+    pub fn is(error: &Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<FirmwareRejection>())
+    }
+}
+
+impl fmt::Display for FirmwareRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FirmwareRejection {}
+
+/// Hardware controllers should report commands rejected by the firmware with
+/// a `FirmwareRejection`, and any other failure as a plain `std::io::Error`
 #[async_trait]
 pub trait HardwareControl {
     async fn is_ready(&mut self) -> bool;
+    fn connection_status(&self) -> ConnectionStatus;
     async fn initialize(&mut self);
     async fn home(&mut self) -> std::io::Result<PhysicalState>;
-    async fn manual_command(&mut self, command: String) -> std::io::Result<PhysicalState>;
+    /// Run the given gcode, returning the lines the firmware responded with
+    async fn manual_command(&mut self, command: String) -> std::io::Result<Vec<String>>;
     async fn start_print(&mut self) -> std::io::Result<PhysicalState>;
     async fn end_print(&mut self) -> std::io::Result<PhysicalState>;
     async fn move_z(&mut self, z: u32, speed: f64) -> std::io::Result<PhysicalState>;
@@ -14,7 +14,8 @@ pub struct Layer {
 
 #[async_trait]
 pub trait PrintFile {
-    fn from_file(file_data: FileMetadata) -> Self
+    /// Load a print file, failing if it isn't a valid file of this type
+    fn from_file(file_data: FileMetadata) -> Result<Self, Error>
     where
         Self: Sized;
     async fn get_layer_data(&mut self, index: usize) -> Option<Layer>;
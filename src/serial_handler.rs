@@ -1,83 +1,282 @@
-use serialport::TTYPort;
-use std::io::{self, BufRead, BufReader, Write};
-use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::time::{interval, Duration};
+use std::fmt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+
+use serialport::{ClearBuffer, SerialPort};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::{interval, sleep, Duration};
+use tokio_serial::SerialPortBuilderExt;
 use tokio_util::sync::CancellationToken;
 
-pub async fn run_listener(
-    serial_port: TTYPort,
+use crate::api_objects::{ConnectionStatus, TrafficDirection};
+use crate::traffic_log::TrafficLog;
+
+/// Reading half of a connection to the printer's controller
+pub type SerialReader = Box<dyn AsyncRead + Send + Unpin>;
+/// Writing half of a connection to the printer's controller
+pub type SerialWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Delay before the first attempt to reopen a lost serial connection
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// Longest delay between attempts to reopen the serial connection
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Keep the connection over the given transport open, running its listener
+/// and writer, and reopening it with increasing backoff whenever it is lost.
+/// The state of the connection is published through `status`, and each line
+/// sent or received is recorded to `traffic_log`.
+pub async fn run_serial(
+    transport: Transport,
     sender: Sender<String>,
+    receiver: Receiver<String>,
+    status: watch::Sender<ConnectionStatus>,
+    traffic_log: TrafficLog,
     cancellation_token: CancellationToken,
 ) {
-    let mut buf_reader = BufReader::new(
-        serial_port
-            .try_clone_native()
-            .expect("Unable to clone serial port"),
-    );
-    let mut interval = interval(Duration::from_millis(100));
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
 
-    loop {
-        if cancellation_token.is_cancelled() {
-            break;
+    while !cancellation_token.is_cancelled() {
+        match transport.open().await {
+            Ok((reader, writer)) => {
+                log::info!("Connected to {}", transport);
+                reconnect_delay = MIN_RECONNECT_DELAY;
+
+                let result = run_connection(
+                    reader,
+                    writer,
+                    sender.clone(),
+                    // Anything queued while disconnected is stale, so drop it
+                    receiver.resubscribe(),
+                    &status,
+                    &traffic_log,
+                    &cancellation_token,
+                )
+                .await;
+
+                if let Err(e) = result {
+                    log::error!("Lost connection to {}: {}", transport, e);
+                }
+                status.send_replace(ConnectionStatus::Disconnected);
+            }
+            Err(e) => log::warn!(
+                "Unable to connect to {}, retrying in {:?}: {}",
+                transport,
+                reconnect_delay,
+                e
+            ),
         }
-        interval.tick().await;
+
+        tokio::select! {
+            _ = sleep(reconnect_delay) => (),
+            _ = cancellation_token.cancelled() => break,
+        }
+        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// The connection used to reach the printer's controller
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    /// A local serial device, or pseudo-terminal such as `klippy.serial`
+    Tty { path: String, baudrate: u32 },
+    /// A TCP socket, such as one exposed by ser2net or an ESP bridge
+    Tcp { address: String },
+    /// A unix domain socket
+    Unix { path: String },
+}
+
+impl Transport {
+    /// Interpret the configured `serial` value, which is either a device path,
+    /// a `tcp://host:port` URL, or a `unix://` URL or path to a socket
+    pub fn from_config(serial: &str, baudrate: u32) -> Transport {
+        if let Some(address) = serial.strip_prefix("tcp://") {
+            return Transport::Tcp {
+                address: address.trim_end_matches('/').to_string(),
+            };
+        }
+        if let Some(path) = serial.strip_prefix("unix://") {
+            return Transport::Unix {
+                path: path.to_string(),
+            };
+        }
+
+        let is_socket = std::fs::metadata(serial)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+
+        if is_socket {
+            Transport::Unix {
+                path: serial.to_string(),
+            }
+        } else {
+            Transport::Tty {
+                path: serial.to_string(),
+                baudrate,
+            }
+        }
+    }
+
+    async fn open(&self) -> io::Result<(SerialReader, SerialWriter)> {
+        match self {
+            Transport::Tty { path, baudrate } => {
+                let mut serial_port = tokio_serial::new(path, *baudrate).open_native_async()?;
+
+                serial_port.set_exclusive(false)?;
+                serial_port.clear(ClearBuffer::All)?;
+
+                let (reader, writer) = tokio::io::split(serial_port);
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Transport::Tcp { address } => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Transport::Unix { path } => {
+                let (reader, writer) = UnixStream::connect(path).await?.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tty { path, .. } => write!(f, "{}", path),
+            Transport::Tcp { address } => write!(f, "tcp://{}", address),
+            Transport::Unix { path } => write!(f, "unix://{}", path),
+        }
+    }
+}
+
+/// Run the listener and writer for an open connection, until either fails or
+/// Odyssey shuts down
+async fn run_connection(
+    reader: SerialReader,
+    writer: SerialWriter,
+    sender: Sender<String>,
+    receiver: Receiver<String>,
+    status: &watch::Sender<ConnectionStatus>,
+    traffic_log: &TrafficLog,
+    cancellation_token: &CancellationToken,
+) -> io::Result<()> {
+    let connection_token = cancellation_token.child_token();
+
+    let mut listener = tokio::spawn(run_listener(
+        reader,
+        sender,
+        traffic_log.clone(),
+        connection_token.clone(),
+    ));
+    let mut writer = tokio::spawn(run_writer(
+        writer,
+        receiver,
+        traffic_log.clone(),
+        connection_token.clone(),
+    ));
+
+    status.send_replace(ConnectionStatus::Connected);
+
+    // Stop whichever task is still running before the connection is reopened
+    let result = tokio::select! {
+        result = &mut listener => {
+            connection_token.cancel();
+            let _ = writer.await;
+            result
+        }
+        result = &mut writer => {
+            connection_token.cancel();
+            let _ = listener.await;
+            result
+        }
+    };
+
+    result.map_err(io::Error::other)?
+}
+
+/// Forward each line read from the connection to `sender`
+pub async fn run_listener(
+    reader: SerialReader,
+    sender: Sender<String>,
+    traffic_log: TrafficLog,
+    cancellation_token: CancellationToken,
+) -> io::Result<()> {
+    let mut buf_reader = BufReader::new(reader);
+
+    loop {
         let mut read_string = String::new();
-        match buf_reader.read_line(&mut read_string) {
-            Err(e) => match e.kind() {
-                io::ErrorKind::TimedOut => {
-                    continue;
-                }
-                // Broken Pipe here
-                other_error => panic!("Error reading from serial port: {:?}", other_error),
-            },
+
+        let read = tokio::select! {
+            read = buf_reader.read_line(&mut read_string) => read,
+            _ = cancellation_token.cancelled() => break,
+        };
+
+        match read {
+            // The other end closed the connection, such as when Klipper
+            // restarts or the network bridge resets
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed",
+                ))
+            }
             Ok(n) => {
-                if n > 0 {
-                    log::debug!("Read {} bytes from serial: {}", n, read_string.trim_end());
-                    sender
-                        .send(read_string)
-                        .expect("Unable to send message to channel");
-                }
+                log::debug!("Read {} bytes from serial: {}", n, read_string.trim_end());
+                traffic_log.record(TrafficDirection::Received, &read_string);
+                sender
+                    .send(read_string)
+                    .expect("Unable to send message to channel");
             }
-        };
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => continue,
+                _ => return Err(e),
+            },
+        }
     }
+    Ok(())
 }
 
+/// Write each message received from `receiver` out over the connection
 pub async fn run_writer(
-    mut serial_port: TTYPort,
+    mut writer: SerialWriter,
     mut receiver: Receiver<String>,
+    traffic_log: TrafficLog,
     cancellation_token: CancellationToken,
-) {
+) -> io::Result<()> {
     let mut interval = interval(Duration::from_millis(100));
 
     loop {
-        if cancellation_token.is_cancelled() {
-            break;
-        }
         interval.tick().await;
 
-        match receiver.recv().await {
+        let message = tokio::select! {
+            message = receiver.recv() => message,
+            _ = cancellation_token.cancelled() => break,
+        };
+
+        match message {
             Ok(message) => {
-                while let Err(e) = send_serial(&mut serial_port, message.clone()).await {
-                    match e.kind() {
-                        io::ErrorKind::Interrupted => {
-                            continue;
-                        }
-                        _ => break,
-                    }
-                }
+                send_serial(&mut writer, &message).await?;
+                traffic_log.record(TrafficDirection::Sent, &message);
             }
-            Err(_) => todo!(),
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Serial writer fell behind, dropped {} messages", skipped)
+            }
+            Err(RecvError::Closed) => break,
         }
     }
+    Ok(())
 }
 
-async fn send_serial(serial_port: &mut TTYPort, message: String) -> io::Result<usize> {
-    let n = serial_port.write(message.as_bytes())?;
-
-    serial_port
-        .flush()
-        .expect("Unable to flush serial connection");
+async fn send_serial(writer: &mut SerialWriter, message: &str) -> io::Result<()> {
+    writer.write_all(message.as_bytes()).await?;
+    writer.flush().await?;
 
-    log::trace!("Wrote {} bytes", n);
-    Ok(n)
+    log::trace!("Wrote {} bytes", message.len());
+    Ok(())
 }
@@ -27,3 +27,9 @@ impl ShutdownHandler {
         }));
     }
 }
+
+impl Default for ShutdownHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
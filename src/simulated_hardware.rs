@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use regex::Regex;
+use tokio::time::{sleep, Duration};
+
+use crate::api_objects::{ConnectionStatus, PhysicalState};
+use crate::configuration::{Configuration, GcodeConfig, SimulationConfig};
+use crate::gcode::render_gcode;
+use crate::printer::{FirmwareRejection, HardwareControl};
+use crate::template::TemplateCache;
+
+/// Speed, in mm/s, at which the simulated plate homes
+const HOME_SPEED: f64 = 10.0;
+
+#[derive(Debug, Default)]
+struct SimulationControl {
+    disconnected: bool,
+    ready: Option<bool>,
+    faults: VecDeque<ErrorKind>,
+    gcode_log: Vec<String>,
+}
+
+/// Shared handle for steering a running `SimulatedHardware` from outside the
+/// printer, such as from tests
+#[derive(Clone, Debug, Default)]
+pub struct SimulationHandle {
+    control: Arc<Mutex<SimulationControl>>,
+}
+
+impl SimulationHandle {
+    /// Force the readiness reported by the hardware, or `None` to fall back
+    /// to the configured behaviour
+    pub fn set_ready(&self, ready: Option<bool>) {
+        self.control.lock().unwrap().ready = ready;
+    }
+
+    /// Simulate losing, or regaining, the connection to the hardware
+    pub fn set_connected(&self, connected: bool) {
+        self.control.lock().unwrap().disconnected = !connected;
+    }
+
+    /// Fail the next hardware operation with an error of the given kind
+    pub fn inject_fault(&self, kind: ErrorKind) {
+        self.control.lock().unwrap().faults.push_back(kind);
+    }
+
+    /// Every line of gcode the hardware has executed so far
+    pub fn gcode_log(&self) -> Vec<String> {
+        self.control.lock().unwrap().gcode_log.clone()
+    }
+}
+
+/// Hardware controller which models the printer in-process, for running
+/// Odyssey without a board attached
+pub struct SimulatedHardware {
+    pub config: GcodeConfig,
+    pub simulation: SimulationConfig,
+    pub state: PhysicalState,
+    pub gcode_substitutions: HashMap<String, String>,
+    templates: TemplateCache,
+    rejected_gcode: Vec<Regex>,
+    ready_checks: usize,
+    handle: SimulationHandle,
+}
+
+impl SimulatedHardware {
+    pub fn new(config: Configuration) -> SimulatedHardware {
+        let simulation = config.simulation.unwrap_or_default();
+
+        let rejected_gcode = simulation
+            .rejected_gcode
+            .iter()
+            .flatten()
+            .filter_map(|pattern| {
+                Regex::new(pattern)
+                    .inspect_err(|error| {
+                        log::error!(
+                            "Ignoring invalid rejected gcode pattern {}: {}",
+                            pattern,
+                            error
+                        )
+                    })
+                    .ok()
+            })
+            .collect();
+
+        SimulatedHardware {
+            templates: TemplateCache::new(config.gcode.template_sources()),
+            config: config.gcode,
+            simulation,
+            state: PhysicalState {
+                z: 0.0,
+                z_microns: 0,
+                curing: false,
+                homed: false,
+            },
+            gcode_substitutions: HashMap::new(),
+            rejected_gcode,
+            ready_checks: 0,
+            handle: SimulationHandle::default(),
+        }
+    }
+
+    /// Handle for steering the simulation once the hardware has been handed
+    /// to the printer
+    pub fn handle(&self) -> SimulationHandle {
+        self.handle.clone()
+    }
+
+    /// Render the given gcode template and execute it
+    fn execute(&mut self, code: &str) -> std::io::Result<()> {
+        self.add_state_variables();
+        let rendered = render_gcode(&mut self.templates, code, &self.gcode_substitutions)?;
+        self.execute_lines(&rendered)
+    }
+
+    /// Record each line of the given gcode as executed, failing with any
+    /// injected fault, or if the simulated firmware rejects a line
+    fn execute_lines(&mut self, code: &str) -> std::io::Result<()> {
+        let mut control = self.handle.control.lock().unwrap();
+
+        if control.disconnected {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "Simulated hardware disconnected",
+            ));
+        }
+
+        if let Some(kind) = control.faults.pop_front() {
+            return Err(Error::new(kind, "Injected hardware fault"));
+        }
+
+        for line in code.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            log::debug!("Simulating gcode: {}", line);
+            control.gcode_log.push(line.to_string());
+
+            if self.rejected_gcode.iter().any(|re| re.is_match(line)) {
+                return Err(FirmwareRejection::error(format!(
+                    "Simulated firmware rejected gcode: {}",
+                    line
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait as long as the real hardware would take to move the given
+    /// distance, scaled by the configured time scale
+    async fn simulate_move(&self, distance_microns: u32, speed: f64) {
+        let time_scale = self.simulation.time_scale.unwrap_or(1.0);
+
+        if time_scale > 0.0 && speed > 0.0 {
+            let seconds = (distance_microns as f64 / 1000.0) / speed * time_scale;
+            sleep(Duration::from_secs_f64(seconds)).await;
+        }
+    }
+
+    fn set_position(&mut self, z: u32) {
+        self.state.z = (z as f64) / 1000.0;
+        self.state.z_microns = z;
+    }
+
+    fn add_state_variables(&mut self) {
+        self.gcode_substitutions
+            .insert("curing".to_string(), self.state.curing.to_string());
+        self.gcode_substitutions
+            .insert("z".to_string(), self.state.z.to_string());
+    }
+}
+
+#[async_trait]
+impl HardwareControl for SimulatedHardware {
+    async fn initialize(&mut self) {
+        log::info!("Using simulated hardware");
+    }
+
+    async fn is_ready(&mut self) -> bool {
+        self.ready_checks += 1;
+
+        let control = self.handle.control.lock().unwrap();
+        if control.disconnected {
+            return false;
+        }
+        if let Some(ready) = control.ready {
+            return ready;
+        }
+        drop(control);
+
+        let startup_checks = self.simulation.startup_checks.unwrap_or(0);
+        let flapping = self
+            .simulation
+            .flap_interval
+            .filter(|interval| *interval > 0)
+            .is_some_and(|interval| self.ready_checks.is_multiple_of(interval));
+
+        self.ready_checks > startup_checks && !flapping
+    }
+
+    fn connection_status(&self) -> ConnectionStatus {
+        if self.handle.control.lock().unwrap().disconnected {
+            ConnectionStatus::Disconnected
+        } else {
+            ConnectionStatus::Connected
+        }
+    }
+
+    async fn home(&mut self) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.home_command.clone())?;
+        self.simulate_move(self.state.z_microns, HOME_SPEED).await;
+        self.set_position(0);
+        self.state.homed = true;
+
+        Ok(self.state)
+    }
+
+    async fn manual_command(&mut self, command: String) -> std::io::Result<Vec<String>> {
+        // Entered by hand, so executed as it is rather than as a template
+        self.execute_lines(&command)?;
+
+        // Acknowledge each line, as firmware would
+        Ok(command
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|_| "ok".to_string())
+            .collect())
+    }
+
+    async fn move_z(&mut self, z: u32, speed: f64) -> std::io::Result<PhysicalState> {
+        let distance = z.abs_diff(self.state.z_microns);
+        let start_z = self.state.z_microns;
+
+        // Render the move with its target position and mm/min feed rate
+        self.set_position(z);
+        self.add_print_variable("speed".to_string(), (speed * 60.0).to_string());
+        let result = self.execute(&self.config.move_command.clone());
+        self.remove_print_variable("speed".to_string());
+
+        if let Err(e) = result {
+            self.set_position(start_z);
+            return Err(e);
+        }
+
+        self.simulate_move(distance, speed).await;
+
+        Ok(self.state)
+    }
+
+    async fn start_layer(&mut self, _layer: usize) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.layer_start.clone())?;
+
+        Ok(self.state)
+    }
+
+    async fn start_curing(&mut self) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.cure_start.clone())?;
+        self.state.curing = true;
+
+        Ok(self.state)
+    }
+
+    async fn stop_curing(&mut self) -> std::io::Result<PhysicalState> {
+        // The LED is switched off even if the command reports a failure
+        self.state.curing = false;
+        self.execute(&self.config.cure_end.clone())?;
+
+        Ok(self.state)
+    }
+
+    async fn start_print(&mut self) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.print_start.clone())?;
+
+        Ok(self.state)
+    }
+
+    async fn end_print(&mut self) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.print_end.clone())?;
+
+        Ok(self.state)
+    }
+
+    async fn boot(&mut self) -> std::io::Result<PhysicalState> {
+        self.execute(&self.config.boot.clone())?;
+
+        Ok(self.state)
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.state.curing = false;
+        self.state.homed = false;
+        self.execute(&self.config.shutdown.clone())
+    }
+
+    fn get_physical_state(&self) -> std::io::Result<PhysicalState> {
+        Ok(self.state)
+    }
+
+    fn add_print_variable(&mut self, variable: String, value: String) {
+        self.gcode_substitutions.insert(variable, value);
+    }
+
+    fn remove_print_variable(&mut self, variable: String) {
+        self.gcode_substitutions.remove(&variable);
+    }
+
+    fn clear_variables(&mut self) {
+        self.gcode_substitutions.clear();
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     fs::File,
-    io::{Error, Read},
+    io::{Error, ErrorKind, Read},
     path::Path,
 };
 
@@ -77,24 +77,23 @@ pub struct Sl1 {
 #[async_trait]
 impl PrintFile for Sl1 {
     /// Instantiate the Sl1 from the given file
-    fn from_file(file_data: FileMetadata) -> Sl1 {
+    fn from_file(file_data: FileMetadata) -> Result<Sl1, Error> {
         log::info!("Loading PrintFile from SL1 {:?}", file_data);
 
         let full_path = Path::new(file_data.parent_path.as_str()).join(file_data.path.as_str());
 
-        let file = File::open(full_path).unwrap();
+        let file = File::open(full_path)?;
 
-        let mut archive = ZipArchive::new(file).unwrap();
+        let mut archive = ZipArchive::new(file)?;
 
         let mut config_contents = String::new();
 
         archive
-            .by_name(CONFIG_FILE)
-            .unwrap()
-            .read_to_string(&mut config_contents)
-            .expect("Unable to read print config.ini");
+            .by_name(CONFIG_FILE)?
+            .read_to_string(&mut config_contents)?;
 
-        let config = PrintConfig::from_string(config_contents).unwrap();
+        let config = PrintConfig::from_string(config_contents)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
 
         let frame_list: Vec<String> = archive
             .file_names()
@@ -112,12 +111,12 @@ impl PrintFile for Sl1 {
             layer_count: frame_list.len(),
         };
 
-        Sl1 {
+        Ok(Sl1 {
             frame_list,
             archive,
             config,
             metadata,
-        }
+        })
     }
 
     async fn get_layer_data(&mut self, index: usize) -> Option<Layer> {
@@ -0,0 +1,703 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Error raised while parsing or rendering a gcode template
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateError {
+    /// The template is malformed
+    Syntax(String),
+    /// The template uses a variable which is not available, without a default
+    MissingVariable(String),
+    /// A value could not be used in the way the template requires
+    Type(String),
+    /// An error in the named gcode template of the configuration
+    Gcode(String, Box<TemplateError>),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Syntax(message) => write!(f, "Template syntax error: {}", message),
+            TemplateError::MissingVariable(name) => {
+                write!(f, "Template variable {} is unavailable", name)
+            }
+            TemplateError::Type(message) => write!(f, "Template type error: {}", message),
+            TemplateError::Gcode(name, error) => write!(f, "gcode.{}: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A gcode template, made up of literal text, `{expression}` substitutions
+/// and `{% if %}` blocks.
+///
+/// Expressions support arithmetic (`{z + 5}`), comparisons, `and`/`or`/`not`,
+/// fixed precision formatting (`{z:.3}`) and defaults for missing variables
+/// (`{speed ?? 300}`).
+#[derive(Clone, Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Text(String),
+    Substitution {
+        expr: Expr,
+        precision: Option<usize>,
+    },
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    Variable(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Default(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+}
+
+impl Value {
+    fn as_number(&self) -> Result<f64, TemplateError> {
+        match self {
+            Value::Number(number) => Ok(*number),
+            Value::Text(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| TemplateError::Type(format!("{:?} is not a number", text))),
+            Value::Bool(_) => Err(TemplateError::Type(format!("{} is not a number", self))),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(value) => *value,
+            Value::Number(number) => *number != 0.0,
+            Value::Text(text) => match text.as_str() {
+                "true" => true,
+                "false" | "" => false,
+                _ => text.parse::<f64>().ok().is_none_or(|number| number != 0.0),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Bool(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Template {
+    /// Parse the given template text
+    pub fn parse(source: &str) -> Result<Template, TemplateError> {
+        let mut parser = BlockParser {
+            source,
+            position: 0,
+        };
+        let (nodes, end) = parser.parse_nodes()?;
+
+        match end {
+            None => Ok(Template { nodes }),
+            Some(tag) => Err(TemplateError::Syntax(format!("Unexpected {{% {} %}}", tag))),
+        }
+    }
+
+    /// Render the template, looking variables up in the given map
+    pub fn render(&self, variables: &HashMap<String, String>) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        render_nodes(&self.nodes, variables, &mut output)?;
+        Ok(output)
+    }
+
+    /// Every variable the template may need, excluding those given defaults
+    pub fn required_variables(&self) -> HashSet<String> {
+        let mut variables = HashSet::new();
+        nodes_variables(&self.nodes, &mut variables);
+        variables
+    }
+}
+
+/// Render the given template text in one step
+pub fn render(source: &str, variables: &HashMap<String, String>) -> Result<String, TemplateError> {
+    Template::parse(source)?.render(variables)
+}
+
+/// Parsed templates, keyed by their source text, so each is parsed only once
+#[derive(Clone, Debug, Default)]
+pub struct TemplateCache {
+    templates: HashMap<String, Template>,
+}
+
+impl TemplateCache {
+    /// Parse the given templates up front. Any which fail to parse are left
+    /// to report their error when rendered.
+    pub fn new<'a>(sources: impl IntoIterator<Item = &'a str>) -> TemplateCache {
+        let templates = sources
+            .into_iter()
+            .filter_map(|source| Some((source.to_string(), Template::parse(source).ok()?)))
+            .collect();
+
+        TemplateCache { templates }
+    }
+
+    /// Render the given template text, parsing it if not already cached
+    pub fn render(
+        &mut self,
+        source: &str,
+        variables: &HashMap<String, String>,
+    ) -> Result<String, TemplateError> {
+        if !self.templates.contains_key(source) {
+            self.templates
+                .insert(source.to_string(), Template::parse(source)?);
+        }
+        self.templates[source].render(variables)
+    }
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    variables: &HashMap<String, String>,
+    output: &mut String,
+) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Substitution { expr, precision } => {
+                let value = evaluate(expr, variables)?;
+                match precision {
+                    Some(precision) => {
+                        output.push_str(&format!("{:.*}", precision, value.as_number()?))
+                    }
+                    None => output.push_str(&value.to_string()),
+                }
+            }
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                let mut body = otherwise;
+                for (condition, branch) in branches {
+                    if evaluate(condition, variables)?.is_truthy() {
+                        body = branch;
+                        break;
+                    }
+                }
+                render_nodes(body, variables, output)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn evaluate(expr: &Expr, variables: &HashMap<String, String>) -> Result<Value, TemplateError> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Variable(name) => variables
+            .get(name)
+            .map(|value| Value::Text(value.clone()))
+            .ok_or_else(|| TemplateError::MissingVariable(name.clone())),
+        Expr::Not(inner) => Ok(Value::Bool(!evaluate(inner, variables)?.is_truthy())),
+        Expr::Negate(inner) => Ok(Value::Number(-evaluate(inner, variables)?.as_number()?)),
+        Expr::Default(inner, default) => match evaluate(inner, variables) {
+            Err(TemplateError::MissingVariable(_)) => evaluate(default, variables),
+            result => result,
+        },
+        Expr::Binary(op, left, right) => {
+            let left = evaluate(left, variables)?;
+
+            // Short circuit, so the other side may rely on the first
+            match op {
+                BinaryOp::And if !left.is_truthy() => return Ok(Value::Bool(false)),
+                BinaryOp::Or if left.is_truthy() => return Ok(Value::Bool(true)),
+                _ => (),
+            }
+
+            let right = evaluate(right, variables)?;
+            apply(*op, &left, &right)
+        }
+    }
+}
+
+fn apply(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, TemplateError> {
+    let value = match op {
+        BinaryOp::Or | BinaryOp::And => Value::Bool(right.is_truthy()),
+        BinaryOp::Equal => Value::Bool(compare(left, right)? == std::cmp::Ordering::Equal),
+        BinaryOp::NotEqual => Value::Bool(compare(left, right)? != std::cmp::Ordering::Equal),
+        BinaryOp::Less => Value::Bool(compare(left, right)?.is_lt()),
+        BinaryOp::LessEqual => Value::Bool(compare(left, right)?.is_le()),
+        BinaryOp::Greater => Value::Bool(compare(left, right)?.is_gt()),
+        BinaryOp::GreaterEqual => Value::Bool(compare(left, right)?.is_ge()),
+        BinaryOp::Add => Value::Number(left.as_number()? + right.as_number()?),
+        BinaryOp::Subtract => Value::Number(left.as_number()? - right.as_number()?),
+        BinaryOp::Multiply => Value::Number(left.as_number()? * right.as_number()?),
+        BinaryOp::Divide => Value::Number(left.as_number()? / right.as_number()?),
+        BinaryOp::Remainder => Value::Number(left.as_number()? % right.as_number()?),
+    };
+    Ok(value)
+}
+
+/// Compare numerically where both sides are numbers, and as text otherwise
+fn compare(left: &Value, right: &Value) -> Result<std::cmp::Ordering, TemplateError> {
+    if let (Ok(left), Ok(right)) = (left.as_number(), right.as_number()) {
+        return left
+            .partial_cmp(&right)
+            .ok_or_else(|| TemplateError::Type("Unable to compare NaN".to_string()));
+    }
+    Ok(left.to_string().cmp(&right.to_string()))
+}
+
+fn nodes_variables(nodes: &[Node], variables: &mut HashSet<String>) {
+    for node in nodes {
+        match node {
+            Node::Text(_) => (),
+            Node::Substitution { expr, .. } => expr_variables(expr, variables),
+            Node::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, branch) in branches {
+                    expr_variables(condition, variables);
+                    nodes_variables(branch, variables);
+                }
+                nodes_variables(otherwise, variables);
+            }
+        }
+    }
+}
+
+fn expr_variables(expr: &Expr, variables: &mut HashSet<String>) {
+    match expr {
+        Expr::Literal(_) => (),
+        Expr::Variable(name) => {
+            variables.insert(name.clone());
+        }
+        Expr::Not(inner) | Expr::Negate(inner) => expr_variables(inner, variables),
+        Expr::Binary(_, left, right) => {
+            expr_variables(left, variables);
+            expr_variables(right, variables);
+        }
+        // Only the default can be needed, as the value falls back to it
+        Expr::Default(_, default) => expr_variables(default, variables),
+    }
+}
+
+/// Splits template text into literal text, substitutions and block tags
+struct BlockParser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl BlockParser<'_> {
+    /// Parse nodes until the end of the template, or an `elif`, `else` or
+    /// `endif` tag, which is returned for the enclosing block to handle
+    fn parse_nodes(&mut self) -> Result<(Vec<Node>, Option<String>), TemplateError> {
+        let mut nodes = Vec::new();
+
+        while self.position < self.source.len() {
+            let rest = &self.source[self.position..];
+
+            let Some(start) = rest.find('{') else {
+                nodes.push(Node::Text(rest.to_string()));
+                self.position = self.source.len();
+                break;
+            };
+
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            self.position += start;
+
+            if self.source[self.position..].starts_with("{%") {
+                let tag = self.read_until("%}")?;
+                let (keyword, argument) = tag
+                    .split_once(char::is_whitespace)
+                    .map_or((tag.as_str(), ""), |(keyword, argument)| {
+                        (keyword, argument.trim())
+                    });
+
+                match keyword {
+                    "if" => nodes.push(self.parse_if(argument)?),
+                    "elif" | "else" | "endif" => return Ok((nodes, Some(tag))),
+                    _ => {
+                        return Err(TemplateError::Syntax(format!(
+                            "Unknown tag {{% {} %}}",
+                            tag
+                        )))
+                    }
+                }
+            } else {
+                let contents = self.read_substitution()?;
+                let (expression, precision) = split_format(&contents)?;
+                nodes.push(Node::Substitution {
+                    expr: parse_expression(expression)?,
+                    precision,
+                });
+            }
+        }
+
+        Ok((nodes, None))
+    }
+
+    fn parse_if(&mut self, condition: &str) -> Result<Node, TemplateError> {
+        let mut branches = Vec::new();
+        let mut condition = parse_expression(condition)?;
+
+        loop {
+            let (body, end) = self.parse_nodes()?;
+            let end = end.ok_or_else(|| {
+                TemplateError::Syntax("Missing {% endif %} for {% if %}".to_string())
+            })?;
+            branches.push((condition, body));
+
+            match end.split_once(char::is_whitespace) {
+                Some(("elif", next)) => condition = parse_expression(next.trim())?,
+                _ if end == "else" => {
+                    let (otherwise, end) = self.parse_nodes()?;
+                    return match end.as_deref() {
+                        Some("endif") => Ok(Node::If {
+                            branches,
+                            otherwise,
+                        }),
+                        _ => Err(TemplateError::Syntax(
+                            "Missing {% endif %} after {% else %}".to_string(),
+                        )),
+                    };
+                }
+                _ if end == "endif" => {
+                    return Ok(Node::If {
+                        branches,
+                        otherwise: Vec::new(),
+                    })
+                }
+                _ => {
+                    return Err(TemplateError::Syntax(format!(
+                        "Malformed tag {{% {} %}}",
+                        end
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Read a `{% tag %}`, returning its trimmed contents
+    fn read_until(&mut self, terminator: &str) -> Result<String, TemplateError> {
+        let contents_start = self.position + 2;
+        let end = self.source[contents_start..]
+            .find(terminator)
+            .ok_or_else(|| {
+                TemplateError::Syntax(format!("Unclosed tag, missing {}", terminator))
+            })?;
+
+        self.position = contents_start + end + terminator.len();
+        Ok(self.source[contents_start..contents_start + end]
+            .trim()
+            .to_string())
+    }
+
+    /// Read a `{substitution}`, skipping over any braces in string literals
+    fn read_substitution(&mut self) -> Result<String, TemplateError> {
+        let contents_start = self.position + 1;
+        let mut quote = None;
+
+        for (offset, character) in self.source[contents_start..].char_indices() {
+            match (quote, character) {
+                (Some(open), _) if character == open => quote = None,
+                (Some(_), _) => (),
+                (None, '\'' | '"') => quote = Some(character),
+                (None, '}') => {
+                    self.position = contents_start + offset + 1;
+                    return Ok(self.source[contents_start..contents_start + offset].to_string());
+                }
+                (None, '{') => break,
+                _ => (),
+            }
+        }
+
+        Err(TemplateError::Syntax(format!(
+            "Unclosed substitution in {:?}",
+            &self.source[self.position..]
+        )))
+    }
+}
+
+/// Split a trailing `:.N` precision off of a substitution
+fn split_format(contents: &str) -> Result<(&str, Option<usize>), TemplateError> {
+    let mut quote = None;
+    for (offset, character) in contents.char_indices() {
+        match (quote, character) {
+            (Some(open), _) if character == open => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(character),
+            (None, ':') => {
+                let spec = contents[offset + 1..].trim();
+                let precision = spec
+                    .strip_prefix('.')
+                    .and_then(|digits| digits.parse().ok())
+                    .ok_or_else(|| {
+                        TemplateError::Syntax(format!("Unsupported format {:?}", spec))
+                    })?;
+                return Ok((&contents[..offset], Some(precision)));
+            }
+            _ => (),
+        }
+    }
+    Ok((contents, None))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 15] = [
+    "??", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "(", ")", "!",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+
+    while !rest.is_empty() {
+        let character = rest.chars().next().unwrap();
+
+        let length = if character.is_ascii_digit() || character == '.' {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let number = rest[..length].parse().map_err(|_| {
+                TemplateError::Syntax(format!("Invalid number {}", &rest[..length]))
+            })?;
+            tokens.push(Token::Number(number));
+            length
+        } else if character.is_alphabetic() || character == '_' {
+            let length = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..length].to_string()));
+            length
+        } else if character == '\'' || character == '"' {
+            let end = rest[1..].find(character).ok_or_else(|| {
+                TemplateError::Syntax(format!("Unterminated string in {:?}", source))
+            })?;
+            tokens.push(Token::Text(rest[1..end + 1].to_string()));
+            end + 2
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else {
+            return Err(TemplateError::Syntax(format!(
+                "Unexpected {:?} in {:?}",
+                character, source
+            )));
+        };
+
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_expression(source: &str) -> Result<Expr, TemplateError> {
+    let tokens = tokenize(source)?;
+    if tokens.is_empty() {
+        return Err(TemplateError::Syntax("Empty expression".to_string()));
+    }
+
+    let mut parser = ExprParser {
+        tokens,
+        position: 0,
+    };
+    let expr = parser.parse_default()?;
+
+    match parser.tokens.get(parser.position) {
+        None => Ok(expr),
+        Some(token) => Err(TemplateError::Syntax(format!(
+            "Unexpected {:?} in {:?}",
+            token, source
+        ))),
+    }
+}
+
+/// Recursive descent parser, from the loosest binding operator to the tightest
+struct ExprParser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let matches = matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol);
+        if matches {
+            self.position += 1;
+        }
+        matches
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let matches = matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword);
+        if matches {
+            self.position += 1;
+        }
+        matches
+    }
+
+    fn parse_default(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_or()?;
+        while self.eat_symbol("??") {
+            expr = Expr::Default(Box::new(expr), Box::new(self.parse_or()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Binary(BinaryOp::Or, Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            expr = Expr::Binary(BinaryOp::And, Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, TemplateError> {
+        if self.eat_keyword("not") || self.eat_symbol("!") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, TemplateError> {
+        let expr = self.parse_sum()?;
+
+        let op = match self.peek() {
+            Some(Token::Symbol("==")) => BinaryOp::Equal,
+            Some(Token::Symbol("!=")) => BinaryOp::NotEqual,
+            Some(Token::Symbol("<")) => BinaryOp::Less,
+            Some(Token::Symbol("<=")) => BinaryOp::LessEqual,
+            Some(Token::Symbol(">")) => BinaryOp::Greater,
+            Some(Token::Symbol(">=")) => BinaryOp::GreaterEqual,
+            _ => return Ok(expr),
+        };
+        self.position += 1;
+
+        Ok(Expr::Binary(
+            op,
+            Box::new(expr),
+            Box::new(self.parse_sum()?),
+        ))
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Subtract,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_product()?));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Multiply,
+                Some(Token::Symbol("/")) => BinaryOp::Divide,
+                Some(Token::Symbol("%")) => BinaryOp::Remainder,
+                _ => return Ok(expr),
+            };
+            self.position += 1;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, TemplateError> {
+        if self.eat_symbol("-") {
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, TemplateError> {
+        match self.advance() {
+            Some(Token::Number(number)) => Ok(Expr::Literal(Value::Number(number))),
+            Some(Token::Text(text)) => Ok(Expr::Literal(Value::Text(text))),
+            Some(Token::Ident(ident)) => Ok(match ident.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                _ => Expr::Variable(ident),
+            }),
+            Some(Token::Symbol("(")) => {
+                let expr = self.parse_default()?;
+                if self.eat_symbol(")") {
+                    Ok(expr)
+                } else {
+                    Err(TemplateError::Syntax(
+                        "Missing closing parenthesis".to_string(),
+                    ))
+                }
+            }
+            Some(token) => Err(TemplateError::Syntax(format!("Unexpected {:?}", token))),
+            None => Err(TemplateError::Syntax(
+                "Expression ended unexpectedly".to_string(),
+            )),
+        }
+    }
+}
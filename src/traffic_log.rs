@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::time::{interval, sleep_until, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::api_objects::{TrafficDirection, TrafficEntry};
+use crate::configuration::TrafficLogConfig;
+use crate::printer::HardwareControl;
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 3;
+const DEFAULT_HISTORY: usize = 500;
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write(&mut self, record: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(record.as_bytes())?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Shift each rotated file up by one, dropping the oldest, and start a
+    /// new file at the configured path
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Path of the nth rotated copy of a log file
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+#[derive(Debug)]
+struct TrafficLogInner {
+    recent: VecDeque<TrafficEntry>,
+    history: usize,
+    file: Option<LogFile>,
+}
+
+/// Record of the lines sent to and received from the printer's controller,
+/// keeping recent lines in memory and optionally writing all of them to a
+/// rotating log file
+#[derive(Clone, Debug)]
+pub struct TrafficLog {
+    inner: Arc<Mutex<TrafficLogInner>>,
+}
+
+impl TrafficLog {
+    pub fn new(config: TrafficLogConfig) -> TrafficLog {
+        let file = config.path.and_then(|path| {
+            LogFile::open(
+                PathBuf::from(&path),
+                config.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            )
+            .inspect_err(|e| log::error!("Unable to open traffic log {}: {}", path, e))
+            .ok()
+        });
+
+        TrafficLog {
+            inner: Arc::new(Mutex::new(TrafficLogInner {
+                recent: VecDeque::new(),
+                history: config.history.unwrap_or(DEFAULT_HISTORY),
+                file,
+            })),
+        }
+    }
+
+    pub fn record(&self, direction: TrafficDirection, line: &str) {
+        let entry = TrafficEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            direction,
+            line: line.trim_end_matches(['\r', '\n']).to_string(),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(file) = inner.file.as_mut() {
+            if let Err(e) = file.write(&format_entry(&entry)) {
+                log::error!("Unable to write traffic log, disabling it: {}", e);
+                inner.file = None;
+            }
+        }
+
+        inner.recent.push_back(entry);
+        while inner.recent.len() > inner.history {
+            inner.recent.pop_front();
+        }
+    }
+
+    /// The most recent entries, oldest first
+    pub fn recent(&self, count: Option<usize>) -> Vec<TrafficEntry> {
+        let inner = self.inner.lock().unwrap();
+        let count = count.unwrap_or(inner.recent.len()).min(inner.recent.len());
+
+        inner
+            .recent
+            .iter()
+            .skip(inner.recent.len() - count)
+            .cloned()
+            .collect()
+    }
+}
+
+fn format_entry(entry: &TrafficEntry) -> String {
+    let direction = match entry.direction {
+        TrafficDirection::Sent => "TX",
+        TrafficDirection::Received => "RX",
+    };
+    format!("{:.6} {} {}\n", entry.timestamp, direction, entry.line)
+}
+
+fn parse_entry(record: &str) -> Option<TrafficEntry> {
+    let mut fields = record.splitn(3, ' ');
+
+    let timestamp = fields.next()?.parse().ok()?;
+    let direction = match fields.next()? {
+        "TX" => TrafficDirection::Sent,
+        "RX" => TrafficDirection::Received,
+        _ => return None,
+    };
+
+    Some(TrafficEntry {
+        timestamp,
+        direction,
+        line: fields.next().unwrap_or_default().to_string(),
+    })
+}
+
+/// Read the entries recorded in a traffic log file
+pub fn read_log(path: &Path) -> io::Result<Vec<TrafficEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for (index, record) in reader.lines().enumerate() {
+        let record = record?;
+        if record.is_empty() {
+            continue;
+        }
+
+        entries.push(parse_entry(&record).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed traffic log entry on line {}", index + 1),
+            )
+        })?);
+    }
+    Ok(entries)
+}
+
+/// Strip the line number and checksum from a line sent with the Marlin
+/// protocol, returning the line number along with the bare command
+fn unframe_line(line: &str) -> (Option<i64>, &str) {
+    let Some((number, command)) = line
+        .strip_prefix('N')
+        .and_then(|numbered| numbered.split_once(' '))
+    else {
+        return (None, line);
+    };
+    let Ok(number) = number.parse() else {
+        return (None, line);
+    };
+
+    let command = command
+        .rsplit_once('*')
+        .map_or(command, |(command, _)| command);
+    (Some(number), command)
+}
+
+/// Send the lines from a recorded session to the given hardware, keeping the
+/// original delays between them. Line numbering is left to the hardware, so
+/// resent and line number reset commands are skipped. Returns the number of
+/// commands sent.
+pub async fn replay<T: HardwareControl>(
+    hardware: &mut T,
+    entries: &[TrafficEntry],
+    cancellation_token: &CancellationToken,
+) -> io::Result<usize> {
+    let Some(first) = entries.first() else {
+        return Ok(0);
+    };
+    let start = Instant::now();
+    let mut last_line_number = None;
+    let mut sent = 0;
+
+    for entry in entries {
+        let offset = Duration::from_secs_f64((entry.timestamp - first.timestamp).max(0.0));
+
+        if entry.direction == TrafficDirection::Received {
+            log::debug!("Recorded response at {:?}: {}", offset, entry.line);
+            continue;
+        }
+
+        let (line_number, command) = unframe_line(&entry.line);
+        if line_number.is_some() {
+            if line_number <= last_line_number || command.starts_with("M110") {
+                continue;
+            }
+            last_line_number = line_number;
+        }
+
+        tokio::select! {
+            _ = sleep_until(start + offset) => (),
+            _ = cancellation_token.cancelled() => break,
+        }
+
+        log::info!("Replaying at {:?}: {}", offset, command);
+        match hardware.manual_command(command.to_string()).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => return Err(e),
+            Err(e) => log::warn!("Replayed command {} failed: {}", command, e),
+        }
+        sent += 1;
+    }
+
+    Ok(sent)
+}
+
+/// Wait for the hardware to report ready, then boot it and replay the session
+/// recorded in the given log file
+pub async fn replay_file<T: HardwareControl>(
+    mut hardware: T,
+    path: &Path,
+    cancellation_token: CancellationToken,
+) -> io::Result<usize> {
+    let entries = read_log(path)?;
+
+    hardware.initialize().await;
+
+    let mut interval = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = cancellation_token.cancelled() => return Ok(0),
+        }
+        if hardware.is_ready().await {
+            break;
+        }
+        log::info!("Waiting for hardware to be ready before replaying");
+    }
+
+    hardware.boot().await?;
+    replay(&mut hardware, &entries, &cancellation_token).await
+}
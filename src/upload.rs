@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+use crate::api_objects::{UploadProgress, UploadSession, UploadState};
+use crate::local_files::LocalFiles;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes written between progress updates
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+/// Extension of partially uploaded files, which are hidden until complete
+const PART_EXTENSION: &str = "part";
+/// How long a chunked upload may go without a chunk before it is abandoned
+pub const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static NEXT_UPLOAD_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_upload_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    format!(
+        "{:x}{:x}",
+        nanos,
+        NEXT_UPLOAD_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Reduce a client-provided file name to a plain name safe to create in the
+/// upload directory
+pub fn sanitize_file_name(file_name: &str) -> std::io::Result<String> {
+    // Clients may send a full path, of which only the last part is kept
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+
+    if name.is_empty() || name.len() > 255 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid file name {:?}", file_name),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn verify_checksum(expected: Option<&str>, hasher: Sha256) -> std::io::Result<()> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let actual = to_hex(&hasher.finalize());
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("SHA-256 mismatch, expected {} but got {}", expected, actual),
+        ));
+    }
+    Ok(())
+}
+
+async fn hash_file(path: &Path) -> std::io::Result<Sha256> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hasher);
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ChunkedUpload {
+    file_name: String,
+    destination: PathBuf,
+    part_path: PathBuf,
+    total_size: u64,
+    sha256: Option<String>,
+    received: u64,
+    writing: bool,
+    last_activity: Instant,
+}
+
+impl ChunkedUpload {
+    fn session(&self, id: &str) -> UploadSession {
+        UploadSession {
+            id: id.to_string(),
+            file_name: self.file_name.clone(),
+            received: self.received,
+            total_size: self.total_size,
+        }
+    }
+}
+
+/// A whole file streamed to a hidden part file, which has yet to be moved
+/// into place or discarded
+#[derive(Debug)]
+pub struct ReceivedUpload {
+    file_name: String,
+    destination: PathBuf,
+    part_path: PathBuf,
+    written: u64,
+    total_size: Option<u64>,
+}
+
+/// Streams uploaded files to disk, either whole or in resumable chunks,
+/// keeping them hidden until they are complete and verified
+#[derive(Clone, Debug)]
+pub struct Uploads {
+    directory: PathBuf,
+    local_files: LocalFiles,
+    sessions: Arc<Mutex<HashMap<String, ChunkedUpload>>>,
+    progress_sender: broadcast::Sender<UploadProgress>,
+}
+
+impl Uploads {
+    /// Manage uploads into the given directory, removing any parts left
+    /// over from uploads interrupted by a restart
+    pub fn new(directory: &str) -> Uploads {
+        if let Ok(entries) = std::fs::read_dir(directory) {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| Uploads::is_part_file(path))
+                .for_each(|path| {
+                    log::info!("Removing incomplete upload {:?}", path);
+                    let _ = std::fs::remove_file(path);
+                });
+        }
+
+        Uploads {
+            directory: PathBuf::from(directory),
+            local_files: LocalFiles::new(directory),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            progress_sender: broadcast::channel(100).0,
+        }
+    }
+
+    fn is_part_file(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == PART_EXTENSION)
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with('.'))
+    }
+
+    /// Progress updates for all uploads
+    pub fn subscribe(&self) -> broadcast::Receiver<UploadProgress> {
+        self.progress_sender.subscribe()
+    }
+
+    fn send_progress(
+        &self,
+        file_name: &str,
+        received: u64,
+        total_size: Option<u64>,
+        state: UploadState,
+    ) {
+        let _ = self.progress_sender.send(UploadProgress {
+            file_name: file_name.to_string(),
+            received,
+            total_size,
+            state,
+        });
+    }
+
+    /// Copy everything from the reader into the file, hashing it as it goes
+    /// and reporting progress from the given starting point
+    async fn write_stream<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+        file: &mut File,
+        hasher: &mut Option<Sha256>,
+        file_name: &str,
+        start: u64,
+        total_size: Option<u64>,
+    ) -> std::io::Result<u64> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut written = 0;
+        let mut last_progress = 0;
+
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            file.write_all(&buffer[..read]).await?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..read]);
+            }
+            written += read as u64;
+
+            if written - last_progress >= PROGRESS_INTERVAL {
+                last_progress = written;
+                self.send_progress(
+                    file_name,
+                    start + written,
+                    total_size,
+                    UploadState::InProgress,
+                );
+            }
+        }
+        file.flush().await?;
+
+        Ok(written)
+    }
+
+    /// Full path an uploaded file will be moved to, in the given
+    /// subdirectory of the upload directory
+    fn destination(&self, subdirectory: Option<&str>, file_name: &str) -> std::io::Result<PathBuf> {
+        Ok(self.local_files.directory(subdirectory)?.join(file_name))
+    }
+
+    /// Stream a whole file into the upload directory, or one of its
+    /// subdirectories, verifying it against the given SHA-256 digest before it
+    /// replaces any file of the same name
+    pub async fn upload<R: AsyncRead + Unpin>(
+        &self,
+        subdirectory: Option<&str>,
+        file_name: &str,
+        reader: R,
+        total_size: Option<u64>,
+        sha256: Option<&str>,
+    ) -> std::io::Result<PathBuf> {
+        let received = self
+            .receive(subdirectory, file_name, reader, total_size, sha256)
+            .await?;
+        self.complete(received).await
+    }
+
+    /// Stream a whole file to disk, verifying it against the given SHA-256
+    /// digest, without yet replacing any file of the same name
+    pub async fn receive<R: AsyncRead + Unpin>(
+        &self,
+        subdirectory: Option<&str>,
+        file_name: &str,
+        reader: R,
+        total_size: Option<u64>,
+        sha256: Option<&str>,
+    ) -> std::io::Result<ReceivedUpload> {
+        let file_name = sanitize_file_name(file_name)?;
+        let destination = self.destination(subdirectory, &file_name)?;
+        let part_path = self.directory.join(format!(
+            ".{}.{}.{}",
+            file_name,
+            next_upload_id(),
+            PART_EXTENSION
+        ));
+
+        let result = async {
+            let mut file = File::create(&part_path).await?;
+            let mut hasher = sha256.map(|_| Sha256::new());
+
+            let written = self
+                .write_stream(reader, &mut file, &mut hasher, &file_name, 0, total_size)
+                .await?;
+            file.sync_all().await?;
+
+            if let Some(hasher) = hasher {
+                verify_checksum(sha256, hasher)?;
+            }
+            Ok(written)
+        }
+        .await;
+
+        let received = ReceivedUpload {
+            file_name,
+            destination,
+            part_path,
+            written: 0,
+            total_size,
+        };
+        match result {
+            Ok(written) => Ok(ReceivedUpload {
+                written,
+                ..received
+            }),
+            Err(e) => Err(self.fail(received, e).await),
+        }
+    }
+
+    /// Move a received file into place, replacing any file of the same name
+    pub async fn complete(&self, received: ReceivedUpload) -> std::io::Result<PathBuf> {
+        match fs::rename(&received.part_path, &received.destination).await {
+            Ok(()) => {
+                self.send_progress(
+                    &received.file_name,
+                    received.written,
+                    received.total_size,
+                    UploadState::Complete,
+                );
+                Ok(received.destination)
+            }
+            Err(e) => Err(self.fail(received, e).await),
+        }
+    }
+
+    /// Remove a received file which isn't wanted after all
+    pub async fn discard(&self, received: ReceivedUpload) {
+        let error = Error::new(ErrorKind::Interrupted, "Upload was discarded");
+        self.fail(received, error).await;
+    }
+
+    async fn fail(&self, received: ReceivedUpload, error: Error) -> Error {
+        log::error!("Upload of {} failed: {}", received.file_name, error);
+        let _ = fs::remove_file(&received.part_path).await;
+        self.send_progress(
+            &received.file_name,
+            0,
+            received.total_size,
+            UploadState::Failed,
+        );
+        error
+    }
+
+    /// Begin a chunked upload of a file of the given size, into the upload
+    /// directory or one of its subdirectories
+    pub async fn start_chunked(
+        &self,
+        subdirectory: Option<&str>,
+        file_name: &str,
+        total_size: u64,
+        sha256: Option<String>,
+    ) -> std::io::Result<UploadSession> {
+        let file_name = sanitize_file_name(file_name)?;
+        let destination = self.destination(subdirectory, &file_name)?;
+        let id = next_upload_id();
+        let part_path = self.directory.join(format!(".{}.{}", id, PART_EXTENSION));
+
+        File::create(&part_path).await?;
+
+        let upload = ChunkedUpload {
+            file_name,
+            destination,
+            part_path,
+            total_size,
+            sha256,
+            received: 0,
+            writing: false,
+            last_activity: Instant::now(),
+        };
+        let session = upload.session(&id);
+        self.sessions.lock().unwrap().insert(id, upload);
+
+        Ok(session)
+    }
+
+    /// Current state of a chunked upload, such as to find where to resume it
+    pub fn session(&self, id: &str) -> std::io::Result<UploadSession> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|upload| upload.session(id))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such upload"))
+    }
+
+    /// Append a chunk to a chunked upload. The offset must match the number
+    /// of bytes received so far, so a chunk is never written twice.
+    pub async fn write_chunk<R: AsyncRead + Unpin>(
+        &self,
+        id: &str,
+        offset: u64,
+        mut reader: R,
+    ) -> std::io::Result<UploadSession> {
+        let upload = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let upload = sessions
+                .get_mut(id)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such upload"))?;
+
+            if upload.writing {
+                return Err(Error::new(
+                    ErrorKind::ResourceBusy,
+                    "A chunk is already being written to this upload",
+                ));
+            }
+            if offset != upload.received {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Chunk offset {} does not match the {} bytes received",
+                        offset, upload.received
+                    ),
+                ));
+            }
+
+            upload.writing = true;
+            upload.clone()
+        };
+
+        let result = async {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(&upload.part_path)
+                .await?;
+            self.write_stream(
+                (&mut reader).take(upload.total_size - upload.received),
+                &mut file,
+                &mut None,
+                &upload.file_name,
+                upload.received,
+                Some(upload.total_size),
+            )
+            .await?;
+
+            // Anything beyond the upload's total size is refused unwritten
+            if reader.read(&mut [0]).await? > 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Chunk runs past the upload's total size",
+                ));
+            }
+            Ok(())
+        }
+        .await;
+
+        // Whatever made it to disk counts, so an interrupted chunk can be
+        // resumed from where it stopped
+        let received = fs::metadata(&upload.part_path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(upload.received);
+
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let upload = sessions
+                .get_mut(id)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "Upload was aborted"))?;
+            upload.writing = false;
+            upload.received = received;
+            upload.last_activity = Instant::now();
+            upload.session(id)
+        };
+
+        self.send_progress(
+            &session.file_name,
+            session.received,
+            Some(session.total_size),
+            UploadState::InProgress,
+        );
+
+        result?;
+        Ok(session)
+    }
+
+    /// Verify a fully received chunked upload, and move it into place
+    pub async fn complete_chunked(&self, id: &str) -> std::io::Result<PathBuf> {
+        let upload = {
+            let sessions = self.sessions.lock().unwrap();
+            let upload = sessions
+                .get(id)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such upload"))?;
+
+            if upload.writing {
+                return Err(Error::new(
+                    ErrorKind::ResourceBusy,
+                    "A chunk is still being written to this upload",
+                ));
+            }
+            if upload.received != upload.total_size {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Upload is incomplete, {} of {} bytes received",
+                        upload.received, upload.total_size
+                    ),
+                ));
+            }
+            upload.clone()
+        };
+
+        if let Some(expected) = upload.sha256.as_deref() {
+            let hasher = hash_file(&upload.part_path).await?;
+            if let Err(e) = verify_checksum(Some(expected), hasher) {
+                // The received data is corrupt, so it can't be resumed
+                self.abort_chunked(id).await?;
+                return Err(e);
+            }
+        }
+
+        fs::rename(&upload.part_path, &upload.destination).await?;
+        self.sessions.lock().unwrap().remove(id);
+
+        self.send_progress(
+            &upload.file_name,
+            upload.received,
+            Some(upload.total_size),
+            UploadState::Complete,
+        );
+        Ok(upload.destination)
+    }
+
+    /// Abandon chunked uploads which haven't had a chunk written for the
+    /// given time, discarding what they received
+    pub async fn expire_sessions(&self, max_idle: Duration) {
+        let expired: Vec<String> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, upload)| !upload.writing && upload.last_activity.elapsed() >= max_idle)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            log::info!("Expiring abandoned upload {}", id);
+            if let Err(e) = self.abort_chunked(&id).await {
+                log::warn!("Unable to remove abandoned upload {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Abandon a chunked upload, discarding what was received
+    pub async fn abort_chunked(&self, id: &str) -> std::io::Result<()> {
+        let upload = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such upload"))?;
+
+        fs::remove_file(&upload.part_path).await?;
+        self.send_progress(
+            &upload.file_name,
+            upload.received,
+            Some(upload.total_size),
+            UploadState::Failed,
+        );
+        Ok(())
+    }
+}
+
+/// Periodically expire chunked uploads left idle for longer than the TTL
+pub async fn expire_sessions(uploads: Uploads, cancellation_token: CancellationToken) {
+    let mut interval = interval(EXPIRY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = cancellation_token.cancelled() => break,
+        }
+
+        uploads.expire_sessions(SESSION_TTL).await;
+    }
+}
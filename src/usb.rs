@@ -0,0 +1,170 @@
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+use glob::{glob, Pattern};
+use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::api_objects::UsbEvent;
+use crate::paths::resolve_existing;
+
+/// How often mounted drives are checked for changes
+const MOUNT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// USB drive mounts and print files, as described by `ApiConfig::usb_glob`.
+///
+/// The glob is split into the directory the drives are mounted under, the
+/// pattern matching each mount point within it, and the pattern matching print
+/// file names. For `/media/usb*/*.sl1`, drives mounted at `/media/usb0` and
+/// `/media/usb1` are named `usb0` and `usb1`, and hold `*.sl1` files.
+#[derive(Clone, Debug)]
+pub struct UsbDrives {
+    root: PathBuf,
+    mount_pattern: String,
+    file_pattern: Pattern,
+}
+
+impl UsbDrives {
+    pub fn new(usb_glob: &str) -> std::io::Result<UsbDrives> {
+        let usb_glob = Path::new(usb_glob);
+
+        let file_pattern = usb_glob
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "USB glob has no file pattern"))?;
+        let file_pattern =
+            Pattern::new(file_pattern).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let directories: Vec<Component> = usb_glob
+            .parent()
+            .map(|parent| parent.components().collect())
+            .unwrap_or_default();
+
+        if directories.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "USB glob has no mount directory",
+            ));
+        }
+
+        // Mount points are the first wildcard directory, or the file's own
+        // directory if none of them are wildcards
+        let mount_index = directories
+            .iter()
+            .position(|component| {
+                component
+                    .as_os_str()
+                    .to_str()
+                    .is_some_and(|name| name.contains(['*', '?', '[']))
+            })
+            .unwrap_or(directories.len().saturating_sub(1));
+
+        let root: PathBuf = directories[..mount_index].iter().collect();
+        let mount_pattern: PathBuf = directories[..=mount_index].iter().collect();
+
+        Ok(UsbDrives {
+            root,
+            mount_pattern: mount_pattern.to_string_lossy().to_string(),
+            file_pattern,
+        })
+    }
+
+    /// Directory the drives are mounted under, to which USB file paths are
+    /// relative
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Names of the currently mounted drives
+    pub fn mounts(&self) -> BTreeSet<String> {
+        glob(&self.mount_pattern)
+            .map(|paths| {
+                paths
+                    .flatten()
+                    .filter(|path| path.is_dir())
+                    .filter_map(|path| {
+                        path.strip_prefix(&self.root)
+                            .ok()
+                            .and_then(|name| name.to_str())
+                            .map(str::to_string)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Whether the given file's name matches the print file pattern
+    pub fn is_print_file(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| self.file_pattern.matches(name))
+    }
+
+    /// Full path of a file on a mounted drive, given its path starting with
+    /// the drive's name. The path can't leave the drive, as checked by
+    /// `paths::resolve_path`.
+    pub fn resolve(&self, file_path: &str) -> std::io::Result<PathBuf> {
+        let mut components = Path::new(file_path).components();
+
+        let mount = match components.next() {
+            Some(Component::Normal(mount)) => mount.to_str().unwrap_or_default(),
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "USB paths must be relative to the drive",
+                ))
+            }
+            None => return Err(Error::new(ErrorKind::NotFound, "No USB drive given")),
+        };
+
+        if !self.mounts().contains(mount) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("USB drive {} is not mounted", mount),
+            ));
+        }
+
+        resolve_existing(
+            &self.root.join(mount),
+            &components.as_path().to_string_lossy(),
+        )
+    }
+}
+
+/// Poll the mounted drives, reporting each one mounted or unmounted
+pub async fn watch_mounts(
+    drives: UsbDrives,
+    sender: broadcast::Sender<UsbEvent>,
+    cancellation_token: CancellationToken,
+) {
+    let mut interval = interval(MOUNT_POLL_INTERVAL);
+    let mut mounts = drives.mounts();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = cancellation_token.cancelled() => break,
+        }
+
+        let current = drives.mounts();
+
+        for mount in current.difference(&mounts) {
+            log::info!("USB drive mounted: {}", mount);
+            let _ = sender.send(UsbEvent {
+                mount: mount.clone(),
+                mounted: true,
+            });
+        }
+        for mount in mounts.difference(&current) {
+            log::info!("USB drive unmounted: {}", mount);
+            let _ = sender.send(UsbEvent {
+                mount: mount.clone(),
+                mounted: false,
+            });
+        }
+
+        mounts = current;
+    }
+}
@@ -14,7 +14,19 @@ impl WrappedFramebuffer {
     ///Writes a frame to the Framebuffer, or to the fb_path if not a real buffer
     pub fn write_frame(&mut self, frame: &[u8]) {
         match self.frame_buffer.as_mut() {
-            Some(fb) => fb.write_frame(frame),
+            Some(fb) => {
+                // Write directly into the mapped memory, as the frame may not
+                // cover the buffer exactly
+                if frame.len() != fb.frame.len() {
+                    log::warn!(
+                        "Frame size {} does not match frame buffer size {}",
+                        frame.len(),
+                        fb.frame.len()
+                    );
+                }
+                let length = frame.len().min(fb.frame.len());
+                fb.frame[..length].copy_from_slice(&frame[..length]);
+            }
             None => {
                 log::info!("Writing layer to path: {}", self.fb_path);
                 match OpenOptions::new()
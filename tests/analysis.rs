@@ -0,0 +1,94 @@
+use odyssey::{
+    analysis::{analyse, with_pixel_pitch},
+    api_objects::BoundingBox,
+    printfile::PrintFile,
+    sl1::Sl1,
+};
+
+mod common;
+
+fn layer(lit: &[(usize, usize, u8)]) -> Vec<u8> {
+    let mut pixels = vec![0; 256];
+    for &(x, y, level) in lit {
+        pixels[y * 16 + x] = level;
+    }
+    pixels
+}
+
+/// A 2x2 column, with a dim pixel floating beside it in the second layer and
+/// a diagonal line, touching at the corners, on top
+fn layers() -> Vec<Vec<u8>> {
+    let square = [(1, 1, 255), (2, 1, 255), (1, 2, 255), (2, 2, 255)];
+    vec![
+        layer(&square),
+        layer(&[square.as_slice(), &[(10, 10, 51)]].concat()),
+        layer(&[(1, 1, 255), (2, 2, 255), (3, 3, 255)]),
+    ]
+}
+
+#[tokio::test]
+async fn layers_are_measured_in_pixels() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(directory.path(), "cube.sl1", 1.0, &layers());
+    let mut file = Sl1::from_file(file_data).unwrap();
+
+    let analysis = analyse(&mut file).await.unwrap();
+
+    let areas: Vec<f64> = analysis
+        .layers
+        .iter()
+        .map(|layer| layer.pixel_area)
+        .collect();
+    assert_eq!(areas, vec![4.0, 4.2, 3.0]);
+    let islands: Vec<(usize, usize)> = analysis
+        .layers
+        .iter()
+        .map(|layer| (layer.islands, layer.unsupported_islands))
+        .collect();
+    assert_eq!(islands, vec![(1, 0), (2, 1), (1, 0)]);
+    assert_eq!(analysis.unsupported_islands, 1);
+    assert_eq!(analysis.largest_layer, Some(1));
+    assert_eq!(
+        analysis.bounding_box,
+        Some(BoundingBox {
+            min_x: 1,
+            min_y: 1,
+            max_x: 10,
+            max_y: 10,
+        })
+    );
+
+    assert!(analysis.layers.iter().all(|layer| layer.area.is_none()));
+    assert_eq!(analysis.volume, None);
+}
+
+#[tokio::test]
+async fn pixel_pitch_gives_physical_sizes() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(directory.path(), "cube.sl1", 1.0, &layers());
+    let mut file = Sl1::from_file(file_data).unwrap();
+
+    let analysis = with_pixel_pitch(analyse(&mut file).await.unwrap(), Some(0.5));
+
+    assert_eq!(analysis.layers[0].area, Some(1.0));
+    assert_eq!(analysis.width, Some(5.0));
+    assert_eq!(analysis.depth, Some(5.0));
+    assert!((analysis.height - 0.15).abs() < 1e-9);
+    // 11.2 pixels of 0.25mm² at 0.05mm each
+    assert!((analysis.volume.unwrap() - 11.2 * 0.25 * 0.05 / 1000.0).abs() < 1e-12);
+}
+
+#[tokio::test]
+async fn empty_prints_have_no_bounds() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data =
+        common::write_print_file(directory.path(), "empty.sl1", 1.0, &vec![vec![0; 256]; 2]);
+    let mut file = Sl1::from_file(file_data).unwrap();
+
+    let analysis = with_pixel_pitch(analyse(&mut file).await.unwrap(), Some(0.05));
+
+    assert_eq!(analysis.bounding_box, None);
+    assert_eq!(analysis.largest_layer, None);
+    assert_eq!(analysis.width, None);
+    assert_eq!(analysis.volume, Some(0.0));
+}
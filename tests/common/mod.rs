@@ -1,10 +1,21 @@
-use odyssey::configuration::{ApiConfig, Configuration, DisplayConfig, GcodeConfig, PrinterConfig};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use odyssey::{
+    api_objects::{FileMetadata, LocationCategory},
+    configuration::{ApiConfig, Configuration, DisplayConfig, GcodeConfig, PrinterConfig},
+};
+use zip::{write::SimpleFileOptions, ZipWriter};
 
 #[allow(unused_variables)]
 pub static TEST_RESOURCE_DIR: &str = "tests/resources";
 pub static UPLOAD_DIR: &str = "uploads";
 pub static CARGO_DIR: &str = env!("CARGO_MANIFEST_DIR");
 
+#[allow(dead_code)]
 pub fn default_test_configuration() -> Configuration {
     Configuration {
         printer: PrinterConfig {
@@ -17,6 +28,9 @@ pub fn default_test_configuration() -> Configuration {
             default_wait_before_exposure: 2.2,
             default_wait_after_exposure: 1.5,
             pause_lift: 100.0,
+            early_layer_display: None,
+            backend: None,
+            lift_curve: None,
         },
         gcode: GcodeConfig {
             boot: String::from("G90"),
@@ -32,18 +46,32 @@ pub fn default_test_configuration() -> Configuration {
             move_timeout: 60,
             status_check: String::from("STATUS_GCODE"),
             status_desired: String::from("READY STATUS RESPONSE"),
+            ack_response: Some(String::from("ok")),
+            error_patterns: Some(vec![String::from("^!!"), String::from("^Error:")]),
+            response_timeout: Some(5),
+            protocol: None,
+            position_query: None,
+            position_pattern: None,
         },
         api: ApiConfig {
             upload_path: upload_path(),
             usb_glob: upload_path(),
             port: 12357,
+            metadata_cache: None,
         },
         display: DisplayConfig {
             frame_buffer: "/dev/null".to_owned(),
             bit_depth: vec![5, 6, 5],
             screen_width: 1920,
             screen_height: 1080,
+            gamma: None,
+            grey_curve: None,
+            min_grey: None,
+            pixel_pitch: None,
         },
+        moonraker: None,
+        simulation: None,
+        traffic_log: None,
     }
 }
 
@@ -52,6 +80,89 @@ pub fn test_resource_path(resource_file: String) -> String {
     format!("{CARGO_DIR}/{TEST_RESOURCE_DIR}/{resource_file}")
 }
 
+#[allow(dead_code)]
 pub fn upload_path() -> String {
     format!("{CARGO_DIR}/{UPLOAD_DIR}")
 }
+
+fn print_config(print_time: f64) -> String {
+    format!(
+        "action = print
+expTime = 0.1
+expTimeFirst = 0.1
+expUserProfile = 0
+fileCreationTimestamp = 2024-01-01 at 00:00:00 UTC
+hollow = 0
+jobDir = simulated
+layerHeight = 0.05
+materialName = Test
+numFade = 0
+numFast = 2
+numSlow = 0
+printProfile = Test
+printTime = {print_time}
+printerModel = Test
+printerProfile = Test
+printerVariant = default
+prusaSlicerVersion = Test
+usedMaterial = 1
+"
+    )
+}
+
+/// Write a small .sl1 file into the given directory, with a 16x16 greyscale
+/// layer for each of the given pixel buffers
+#[allow(dead_code)]
+pub fn write_print_file(
+    directory: &Path,
+    name: &str,
+    print_time: f64,
+    layers: &[Vec<u8>],
+) -> FileMetadata {
+    let mut zip = ZipWriter::new(File::create(directory.join(name)).unwrap());
+    let options = SimpleFileOptions::default();
+
+    zip.start_file("config.ini", options).unwrap();
+    zip.write_all(print_config(print_time).as_bytes()).unwrap();
+
+    for (layer, pixels) in layers.iter().enumerate() {
+        let mut layer_png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut layer_png, 16, 16);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(pixels)
+            .unwrap();
+
+        zip.start_file(format!("simulated{layer:05}.png"), options)
+            .unwrap();
+        zip.write_all(&layer_png).unwrap();
+    }
+    zip.finish().unwrap();
+
+    FileMetadata {
+        path: name.to_string(),
+        name: name.to_string(),
+        last_modified: None,
+        file_size: None,
+        location_category: LocationCategory::Local,
+        parent_path: directory.to_str().unwrap().to_string(),
+    }
+}
+
+/// Add a file, such as a thumbnail, to an existing print file
+#[allow(dead_code)]
+pub fn add_to_print_file(path: &Path, name: &str, data: &[u8]) {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut zip = ZipWriter::new_append(file).unwrap();
+
+    zip.start_file(name, SimpleFileOptions::default()).unwrap();
+    zip.write_all(data).unwrap();
+    zip.finish().unwrap();
+}
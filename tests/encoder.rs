@@ -0,0 +1,144 @@
+use framebuffer::{Bitfield, VarScreeninfo};
+use odyssey::encoder::{FrameEncoder, PixelLayout};
+
+#[test]
+fn packs_565_layout_little_endian() {
+    let mut encoder = FrameEncoder::new(vec![5, 6, 5], 3);
+
+    // 0xFF -> 0b11111, 0x80 -> 0b100000, 0x08 -> 0b00001
+    let packed = encoder.encode(&[0xFF, 0x80, 0x08], 8).to_vec();
+
+    let raw: u16 = (0b11111 << 11) | (0b100000 << 5) | 0b00001;
+    assert_eq!(packed, raw.to_le_bytes().to_vec());
+}
+
+#[test]
+fn drops_incomplete_trailing_chunk() {
+    let mut encoder = FrameEncoder::new(vec![5, 6, 5], 4);
+
+    let packed = encoder.encode(&[0xFF; 4], 8);
+
+    assert_eq!(packed, &[0xFF, 0xFF]);
+}
+
+#[test]
+fn passes_matching_layout_through() {
+    let mut encoder = FrameEncoder::new(vec![8], 4);
+    let frame = vec![1, 2, 3, 4];
+
+    assert_eq!(encoder.encode(&frame, 8), frame.as_slice());
+}
+
+#[test]
+fn reuses_buffer_between_frames() {
+    let mut encoder = FrameEncoder::new(vec![4, 4], 4);
+
+    assert_eq!(encoder.encode(&[0xF0, 0x0F, 0xA0, 0xB0], 8), &[0xF0, 0xAB]);
+    assert_eq!(encoder.encode(&[0x10, 0x20], 8), &[0x12]);
+}
+
+#[test]
+fn pads_rows_to_stride() {
+    let layout = PixelLayout::from_bit_depth(vec![4, 4]);
+    let mut encoder = FrameEncoder::with_layout(layout, 4, 3);
+
+    assert_eq!(
+        encoder.encode(&[0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80], 8),
+        &[0x12, 0x34, 0x00, 0x56, 0x78, 0x00]
+    );
+}
+
+#[test]
+fn derives_layout_from_rgb565_screen_info() {
+    let info = VarScreeninfo {
+        bits_per_pixel: 16,
+        red: Bitfield {
+            offset: 11,
+            length: 5,
+            msb_right: 0,
+        },
+        green: Bitfield {
+            offset: 5,
+            length: 6,
+            msb_right: 0,
+        },
+        blue: Bitfield {
+            offset: 0,
+            length: 5,
+            msb_right: 0,
+        },
+        ..Default::default()
+    };
+
+    let layout = PixelLayout::from_screen_info(&info).unwrap();
+
+    assert_eq!(layout, PixelLayout::from_bit_depth(vec![5, 6, 5]));
+}
+
+#[test]
+fn keeps_padding_bits_from_screen_info() {
+    let bitfield = |offset| Bitfield {
+        offset,
+        length: 8,
+        msb_right: 0,
+    };
+    let info = VarScreeninfo {
+        bits_per_pixel: 32,
+        red: bitfield(16),
+        green: bitfield(8),
+        blue: bitfield(0),
+        ..Default::default()
+    };
+
+    let layout = PixelLayout::from_screen_info(&info).unwrap();
+    let mut encoder = FrameEncoder::with_layout(layout, 3, 4);
+
+    assert_eq!(
+        encoder.encode(&[0x11, 0x22, 0x33], 8),
+        &[0x33, 0x22, 0x11, 0x00]
+    );
+}
+
+#[test]
+fn rejects_out_of_range_screen_info() {
+    let info = VarScreeninfo {
+        bits_per_pixel: 32,
+        red: Bitfield {
+            // Would wrap around to 16 if truncated to a byte
+            offset: 272,
+            length: 8,
+            msb_right: 0,
+        },
+        ..Default::default()
+    };
+
+    assert_eq!(PixelLayout::from_screen_info(&info), None);
+}
+
+#[test]
+fn rejects_sub_byte_screen_info() {
+    let info = VarScreeninfo {
+        bits_per_pixel: 1,
+        grayscale: 1,
+        ..Default::default()
+    };
+
+    assert_eq!(PixelLayout::from_screen_info(&info), None);
+}
+
+#[test]
+fn applies_level_map_before_packing() {
+    let mut encoder = FrameEncoder::new(vec![8], 2);
+    let mut level_map = [0u8; 256];
+    level_map[0x80] = 0x40;
+    level_map[0xFF] = 0xFF;
+
+    encoder.set_level_map(Some(level_map));
+    assert_eq!(
+        encoder.encode(&[0x80, 0xFF, 0x10, 0x00], 8),
+        &[0x40, 0xFF, 0x00, 0x00]
+    );
+
+    encoder.set_level_map(None);
+    assert_eq!(encoder.encode(&[0x80, 0xFF], 8), &[0x80, 0xFF]);
+}
@@ -0,0 +1,180 @@
+use std::fs;
+
+use odyssey::{
+    api_objects::{FileMetadata, LocationCategory, SortKey, SortOrder},
+    file_listing::{ListingEntry, ListingOptions},
+    printfile::PrintFile,
+    sl1::Sl1,
+};
+
+mod common;
+
+fn file_data(name: &str, last_modified: u64, file_size: u64) -> FileMetadata {
+    FileMetadata {
+        path: name.to_string(),
+        name: name.to_string(),
+        last_modified: Some(last_modified),
+        file_size: Some(file_size),
+        location_category: LocationCategory::Local,
+        parent_path: "/uploads".to_string(),
+    }
+}
+
+/// A directory of print files, each loaded from a generated .sl1
+fn entries(directory: &tempfile::TempDir) -> Vec<ListingEntry> {
+    let mut entries = vec![
+        ListingEntry::Directory(file_data("resin", 5, 0)),
+        ListingEntry::Invalid(file_data("broken.sl1", 4, 10)),
+        ListingEntry::Directory(file_data("Archive", 1, 0)),
+    ];
+
+    for (name, print_time, layers, modified) in [
+        ("cube.sl1", 30.0, 3, 3),
+        ("Boat.sl1", 90.0, 1, 1),
+        ("tower.sl1", 60.0, 2, 2),
+    ] {
+        let file = common::write_print_file(
+            directory.path(),
+            name,
+            print_time,
+            &vec![vec![0; 256]; layers],
+        );
+        let mut metadata = Sl1::from_file(file).unwrap().get_metadata();
+        metadata.file_data.last_modified = Some(modified);
+        metadata.file_data.file_size = Some(100 - modified);
+        entries.push(ListingEntry::PrintFile(metadata));
+    }
+    entries
+}
+
+fn names(entries: &[ListingEntry]) -> Vec<&str> {
+    entries
+        .iter()
+        .map(|entry| entry.file_data().name.as_str())
+        .collect()
+}
+
+#[test]
+fn sorts_directories_then_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let entries = entries(&directory);
+
+    let sorted = ListingOptions::default().apply(entries.clone());
+    assert_eq!(
+        names(&sorted),
+        [
+            "Archive",
+            "resin",
+            "Boat.sl1",
+            "broken.sl1",
+            "cube.sl1",
+            "tower.sl1"
+        ]
+    );
+
+    let sorted = ListingOptions {
+        sort: SortKey::Modified,
+        order: SortOrder::Descending,
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(
+        names(&sorted),
+        [
+            "resin",
+            "Archive",
+            "broken.sl1",
+            "cube.sl1",
+            "tower.sl1",
+            "Boat.sl1"
+        ]
+    );
+
+    let sorted = ListingOptions {
+        sort: SortKey::PrintTime,
+        valid: Some(true),
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(
+        names(&sorted),
+        ["Archive", "resin", "cube.sl1", "tower.sl1", "Boat.sl1"]
+    );
+
+    let sorted = ListingOptions {
+        sort: SortKey::LayerCount,
+        order: SortOrder::Descending,
+        valid: Some(true),
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(names(&sorted)[2..], ["cube.sl1", "tower.sl1", "Boat.sl1"]);
+
+    // Files without print metadata come last, whichever the order
+    for order in [SortOrder::Ascending, SortOrder::Descending] {
+        let sorted = ListingOptions {
+            sort: SortKey::PrintTime,
+            order,
+            ..Default::default()
+        }
+        .apply(entries.clone());
+        assert_eq!(names(&sorted)[5], "broken.sl1");
+    }
+
+    let sorted = ListingOptions {
+        sort: SortKey::Size,
+        ..Default::default()
+    }
+    .apply(entries);
+    assert_eq!(
+        names(&sorted)[2..],
+        ["broken.sl1", "cube.sl1", "tower.sl1", "Boat.sl1"]
+    );
+}
+
+#[test]
+fn filters_by_name_format_and_validity() {
+    let directory = tempfile::tempdir().unwrap();
+    let entries = entries(&directory);
+
+    let filtered = ListingOptions {
+        search: Some("O".to_string()),
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(names(&filtered), ["Boat.sl1", "broken.sl1", "tower.sl1"]);
+
+    let filtered = ListingOptions {
+        valid: Some(false),
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(names(&filtered), ["Archive", "resin", "broken.sl1"]);
+
+    let filtered = ListingOptions {
+        format: Some(".SL1".to_string()),
+        ..Default::default()
+    }
+    .apply(entries.clone());
+    assert_eq!(filtered.len(), entries.len());
+
+    let filtered = ListingOptions {
+        format: Some("ctb".to_string()),
+        ..Default::default()
+    }
+    .apply(entries);
+    assert_eq!(names(&filtered), ["Archive", "resin"]);
+}
+
+#[test]
+fn invalid_print_file_is_an_error() {
+    let directory = tempfile::tempdir().unwrap();
+    fs::write(directory.path().join("broken.sl1"), "not a zip").unwrap();
+
+    let file_data = FileMetadata {
+        parent_path: directory.path().to_str().unwrap().to_string(),
+        ..file_data("broken.sl1", 0, 9)
+    };
+
+    assert!(Sl1::from_file(file_data).is_err());
+}
@@ -0,0 +1,120 @@
+use odyssey::display::Frame;
+use png::{BitDepth, ColorType, Encoder};
+
+fn encode_png(
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+    palette: Option<Vec<u8>>,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut png_data, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(bit_depth);
+        if let Some(palette) = palette {
+            encoder.set_palette(palette);
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+    }
+    png_data
+}
+
+fn decode(png_data: Vec<u8>) -> Frame {
+    Frame::from_vec("layer.png".to_string(), 1.0, png_data).unwrap()
+}
+
+#[test]
+fn decodes_8_bit_grayscale() {
+    let frame = decode(encode_png(
+        2,
+        2,
+        ColorType::Grayscale,
+        BitDepth::Eight,
+        None,
+        &[0, 64, 128, 255],
+    ));
+
+    assert_eq!(frame.buffer, vec![0, 64, 128, 255]);
+    assert_eq!((frame.width, frame.height, frame.bit_depth), (2, 2, 8));
+}
+
+#[test]
+fn strips_16_bit_grayscale() {
+    let frame = decode(encode_png(
+        2,
+        1,
+        ColorType::Grayscale,
+        BitDepth::Sixteen,
+        None,
+        &[0xFF, 0xFF, 0x80, 0x00],
+    ));
+
+    assert_eq!(frame.buffer, vec![0xFF, 0x80]);
+}
+
+#[test]
+fn expands_1_bit_grayscale() {
+    let frame = decode(encode_png(
+        8,
+        1,
+        ColorType::Grayscale,
+        BitDepth::One,
+        None,
+        &[0b1010_0000],
+    ));
+
+    assert_eq!(frame.buffer, vec![255, 0, 255, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn expands_indexed_palette() {
+    let frame = decode(encode_png(
+        3,
+        1,
+        ColorType::Indexed,
+        BitDepth::Two,
+        Some(vec![0, 0, 0, 255, 255, 255, 128, 128, 128]),
+        &[0b0001_1000],
+    ));
+
+    assert_eq!(frame.buffer, vec![0, 255, 128]);
+}
+
+#[test]
+fn converts_rgb_to_luminance() {
+    let frame = decode(encode_png(
+        2,
+        1,
+        ColorType::Rgb,
+        BitDepth::Eight,
+        None,
+        &[255, 255, 255, 0, 0, 0],
+    ));
+
+    assert_eq!(frame.buffer, vec![255, 0]);
+}
+
+#[test]
+fn composites_alpha_over_black() {
+    let frame = decode(encode_png(
+        2,
+        1,
+        ColorType::Rgba,
+        BitDepth::Eight,
+        None,
+        &[255, 255, 255, 255, 255, 255, 255, 0],
+    ));
+
+    assert_eq!(frame.buffer, vec![255, 0]);
+}
+
+#[test]
+fn rejects_invalid_data() {
+    let result = Frame::from_vec("layer.png".to_string(), 1.0, vec![1, 2, 3, 4]);
+
+    assert!(result.is_err());
+}
@@ -0,0 +1,229 @@
+use std::{
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use odyssey::{
+    api_objects::ConnectionStatus,
+    configuration::{Configuration, GcodeProtocol},
+    gcode::Gcode,
+    printer::{FirmwareRejection, HardwareControl},
+};
+use tokio::sync::{
+    broadcast::{self, Receiver, Sender},
+    watch,
+};
+
+mod common;
+
+/// Stand-in for the firmware, answering each line it receives using the
+/// given responder
+fn spawn_firmware(
+    mut commands: Receiver<String>,
+    responses: Sender<String>,
+    responder: fn(&str) -> Vec<&'static str>,
+) {
+    tokio::spawn(async move {
+        while let Ok(command) = commands.recv().await {
+            for response in responder(command.trim()) {
+                let _ = responses.send(format!("{response}\r\n"));
+            }
+        }
+    });
+}
+
+fn test_gcode(configuration: Configuration, responder: fn(&str) -> Vec<&'static str>) -> Gcode {
+    let (read_sender, read_receiver) = broadcast::channel(200);
+    let (write_sender, write_receiver) = broadcast::channel(200);
+
+    spawn_firmware(write_receiver, read_sender, responder);
+
+    Gcode::new(configuration, read_receiver, write_sender)
+}
+
+#[tokio::test]
+async fn acknowledged_command_succeeds() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |_| vec!["ok"]);
+
+    assert!(gcode.manual_command("G90".to_string()).await.is_ok());
+}
+
+#[tokio::test]
+async fn firmware_error_fails_command() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |command| {
+        if command.starts_with("G1") {
+            vec!["!! Move out of range", "ok"]
+        } else {
+            vec!["ok"]
+        }
+    });
+
+    let error = gcode
+        .manual_command("G1 Z500".to_string())
+        .await
+        .unwrap_err();
+    assert!(FirmwareRejection::is(&error));
+
+    // The trailing ack was consumed, so the next command stays in step
+    assert!(gcode.manual_command("G90".to_string()).await.is_ok());
+}
+
+#[tokio::test]
+async fn move_waits_for_sync_response() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |command| {
+        if command.starts_with("MOVE_PLATE") {
+            vec!["ok", "MOVE COMPLETE RESPONSE"]
+        } else {
+            vec!["ok"]
+        }
+    });
+
+    let state = gcode.move_z(10000, 5.0).await.unwrap();
+    assert_eq!(state.z_microns, 10000);
+}
+
+#[tokio::test]
+async fn home_zeroes_position() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |_| vec!["ok"]);
+    assert!(!gcode.get_physical_state().unwrap().homed);
+
+    gcode.state.z_microns = 25000;
+    let state = gcode.home().await.unwrap();
+
+    assert!(state.homed);
+    assert_eq!(state.z_microns, 0);
+
+    gcode.shutdown().await.unwrap();
+    assert!(!gcode.get_physical_state().unwrap().homed);
+}
+
+#[tokio::test]
+async fn failed_shutdown_forgets_homing() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |command| {
+        if command == "M84" {
+            vec!["!! Shutdown failed", "ok"]
+        } else {
+            vec!["ok"]
+        }
+    });
+
+    gcode.home().await.unwrap();
+    assert!(gcode.shutdown().await.is_err());
+    assert!(!gcode.get_physical_state().unwrap().homed);
+
+    gcode.home().await.unwrap();
+    gcode.boot().await.unwrap();
+    assert!(!gcode.get_physical_state().unwrap().homed);
+}
+
+#[tokio::test]
+async fn move_reports_queried_position() {
+    let mut configuration = common::default_test_configuration();
+    configuration.gcode.position_query = Some(String::from("M114"));
+    let mut gcode = test_gcode(configuration, |command| match command {
+        "M114" => vec!["X:0.00 Y:0.00 Z:9.98 E:0.00 Count X:0 Y:0 Z:3992", "ok"],
+        _ if command.starts_with("MOVE_PLATE") => vec!["ok", "MOVE COMPLETE RESPONSE"],
+        _ => vec!["ok"],
+    });
+
+    let state = gcode.move_z(10000, 5.0).await.unwrap();
+    assert_eq!(state.z_microns, 9980);
+}
+
+#[tokio::test]
+async fn unacknowledged_command_times_out() {
+    let mut configuration = common::default_test_configuration();
+    configuration.gcode.response_timeout = Some(1);
+    let mut gcode = test_gcode(configuration, |_| vec![]);
+
+    let error = gcode.manual_command("G90".to_string()).await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn ready_when_status_matches() {
+    let mut gcode = test_gcode(common::default_test_configuration(), |_| {
+        vec!["// READY STATUS RESPONSE", "ok"]
+    });
+
+    assert!(gcode.is_ready().await);
+}
+
+static MARLIN_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static MARLIN_CORRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[tokio::test]
+async fn marlin_lines_are_numbered_and_resent() {
+    let mut configuration = common::default_test_configuration();
+    configuration.gcode.protocol = Some(GcodeProtocol::Marlin);
+
+    let mut gcode = test_gcode(configuration, |command| {
+        MARLIN_LINES.lock().unwrap().push(command.to_string());
+
+        // Report the first numbered line as corrupted
+        if command.starts_with("N1 ") && !MARLIN_CORRUPTED.swap(true, Ordering::SeqCst) {
+            vec!["Error:checksum mismatch, Last Line: 0", "Resend: 1", "ok"]
+        } else {
+            vec!["ok"]
+        }
+    });
+
+    gcode.boot().await.unwrap();
+
+    assert_eq!(
+        *MARLIN_LINES.lock().unwrap(),
+        vec!["N0 M110 N0*125", "N1 G90*17", "N1 G90*17"]
+    );
+}
+
+static RECONNECT_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[tokio::test]
+async fn reconnection_resets_firmware_state() {
+    let mut configuration = common::default_test_configuration();
+    configuration.gcode.protocol = Some(GcodeProtocol::Marlin);
+
+    let mut gcode = test_gcode(configuration, |command| {
+        RECONNECT_LINES.lock().unwrap().push(command.to_string());
+        vec!["ok"]
+    });
+    let (connection_sender, connection_receiver) = watch::channel(ConnectionStatus::Connected);
+    gcode.connection_status = Some(connection_receiver);
+
+    gcode.boot().await.unwrap();
+    gcode.home().await.unwrap();
+    RECONNECT_LINES.lock().unwrap().clear();
+
+    connection_sender.send_replace(ConnectionStatus::Disconnected);
+    connection_sender.send_replace(ConnectionStatus::Connected);
+    gcode.manual_command("G90".to_string()).await.unwrap();
+
+    assert!(!gcode.get_physical_state().unwrap().homed);
+    assert_eq!(
+        *RECONNECT_LINES.lock().unwrap(),
+        vec!["N0 M110 N0*125", "N1 G90*17"]
+    );
+}
+
+static COMMENTED_LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[tokio::test]
+async fn marlin_comments_are_left_out_of_checksum() {
+    let mut configuration = common::default_test_configuration();
+    configuration.gcode.protocol = Some(GcodeProtocol::Marlin);
+
+    let mut gcode = test_gcode(configuration, |command| {
+        COMMENTED_LINES.lock().unwrap().push(command.to_string());
+        vec!["ok"]
+    });
+
+    gcode
+        .manual_command("G90 ; absolute\n; only a comment".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(*COMMENTED_LINES.lock().unwrap(), vec!["N1 G90*17"]);
+}
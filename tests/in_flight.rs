@@ -0,0 +1,55 @@
+use std::{
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use odyssey::in_flight::InFlight;
+
+#[tokio::test]
+async fn concurrent_work_for_a_key_runs_once() {
+    let in_flight: InFlight<&str, usize> = InFlight::default();
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    let work = |runs: Arc<AtomicUsize>| {
+        move || {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(runs.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+    };
+
+    let (first, second, other) = tokio::join!(
+        in_flight.run("cube.sl1", work(runs.clone())),
+        in_flight.run("cube.sl1", work(runs.clone())),
+        in_flight.run("boat.sl1", work(runs.clone())),
+    );
+
+    assert_eq!(first.unwrap(), second.unwrap());
+    assert_ne!(other.unwrap(), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    // Once finished, the work is run again for later requests
+    assert_eq!(in_flight.run("cube.sl1", work(runs)).await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn errors_are_shared() {
+    let in_flight: InFlight<&str, ()> = InFlight::default();
+
+    let (first, second) = tokio::join!(
+        in_flight.run("cube.sl1", || {
+            std::thread::sleep(Duration::from_millis(100));
+            Err(std::io::Error::new(ErrorKind::InvalidData, "Corrupt layer"))
+        }),
+        in_flight.run("cube.sl1", || Ok(())),
+    );
+
+    for result in [first, second] {
+        let error = result.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Corrupt layer");
+    }
+}
@@ -0,0 +1,69 @@
+use odyssey::{configuration::LiftCurvePoint, lift::lift_for_area};
+
+mod common;
+
+fn point(area: f64, lift: f64, up_speed: f64, down_speed: f64) -> LiftCurvePoint {
+    LiftCurvePoint {
+        area,
+        lift,
+        up_speed,
+        down_speed,
+    }
+}
+
+#[test]
+fn lift_is_interpolated_between_points() {
+    let curve = [point(1000.0, 8.0, 1.0, 2.0), point(0.0, 4.0, 5.0, 6.0)];
+
+    assert_eq!(
+        lift_for_area(&curve, 250.0),
+        Some(point(250.0, 5.0, 4.0, 5.0))
+    );
+    assert_eq!(
+        lift_for_area(&curve, 1000.0),
+        Some(point(1000.0, 8.0, 1.0, 2.0))
+    );
+}
+
+#[test]
+fn lift_is_clamped_to_the_curve() {
+    let curve = [point(100.0, 4.0, 5.0, 6.0), point(500.0, 8.0, 1.0, 2.0)];
+
+    assert_eq!(
+        lift_for_area(&curve, 10.0),
+        Some(point(10.0, 4.0, 5.0, 6.0))
+    );
+    assert_eq!(
+        lift_for_area(&curve, 2000.0),
+        Some(point(2000.0, 8.0, 1.0, 2.0))
+    );
+    assert_eq!(
+        lift_for_area(&curve[..1], 300.0),
+        Some(point(300.0, 4.0, 5.0, 6.0))
+    );
+    assert_eq!(lift_for_area(&[], 300.0), None);
+}
+
+#[test]
+fn invalid_curve_points_are_rejected() {
+    let mut configuration = common::default_test_configuration();
+    configuration.printer.lift_curve =
+        Some(vec![point(0.0, 4.0, 5.0, 6.0), point(500.0, 8.0, 1.0, 2.0)]);
+    assert!(configuration.printer.validate().is_ok());
+
+    for invalid in [
+        point(-1.0, 4.0, 5.0, 6.0),
+        point(100.0, f64::NAN, 5.0, 6.0),
+        point(100.0, -2.0, 5.0, 6.0),
+        point(100.0, 4.0, 0.0, 6.0),
+        point(100.0, 4.0, 5.0, -1.0),
+        point(100.0, 4.0, f64::INFINITY, 6.0),
+    ] {
+        configuration.printer.lift_curve = Some(vec![point(0.0, 4.0, 5.0, 6.0), invalid.clone()]);
+        let error = configuration.printer.validate().unwrap_err();
+        assert!(
+            error.to_string().starts_with("printer.lift_curve[1]."),
+            "{invalid:?} should be rejected"
+        );
+    }
+}
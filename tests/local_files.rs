@@ -0,0 +1,159 @@
+use std::{fs, io::ErrorKind};
+
+use odyssey::local_files::LocalFiles;
+
+fn local_files(directory: &tempfile::TempDir) -> LocalFiles {
+    fs::create_dir(directory.path().join("prints")).unwrap();
+    fs::write(directory.path().join("cube.sl1"), "cube").unwrap();
+    LocalFiles::new(directory.path().to_str().unwrap())
+}
+
+#[test]
+fn paths_stay_within_upload_directory() {
+    let directory = tempfile::tempdir().unwrap();
+    let files = local_files(&directory);
+
+    assert_eq!(
+        files.resolve("cube.sl1").unwrap(),
+        directory.path().join("cube.sl1")
+    );
+    assert_eq!(
+        files.directory(None).unwrap(),
+        directory.path().to_path_buf()
+    );
+    assert_eq!(
+        files.directory(Some("cube.sl1")).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        files.resolve("missing.sl1").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+
+    for path in [
+        "/etc/passwd",
+        "../cube.sl1",
+        "prints/../cube.sl1",
+        ".cube.part",
+    ] {
+        assert_eq!(
+            files.path(path).unwrap_err().kind(),
+            ErrorKind::PermissionDenied,
+            "{path:?} should be rejected"
+        );
+    }
+}
+
+#[tokio::test]
+async fn creates_directories() {
+    let directory = tempfile::tempdir().unwrap();
+    let files = local_files(&directory);
+
+    let path = files.create_directory("prints/resin").await.unwrap();
+    assert!(path.is_dir());
+
+    assert_eq!(
+        files.create_directory("prints").await.unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        files
+            .create_directory("missing/resin")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        files
+            .create_directory("../outside")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::PermissionDenied
+    );
+}
+
+#[tokio::test]
+async fn moves_and_renames_files() {
+    let directory = tempfile::tempdir().unwrap();
+    let files = local_files(&directory);
+
+    let moved = files
+        .move_file("cube.sl1", "prints/cube.sl1")
+        .await
+        .unwrap();
+    assert_eq!(moved, directory.path().join("prints/cube.sl1"));
+    assert!(!directory.path().join("cube.sl1").exists());
+
+    let renamed = files.rename("prints/cube.sl1", "box.sl1").await.unwrap();
+    assert_eq!(renamed, directory.path().join("prints/box.sl1"));
+    assert_eq!(fs::read_to_string(renamed).unwrap(), "cube");
+
+    let renamed = files.rename("prints", "archive").await.unwrap();
+    assert!(renamed.join("box.sl1").exists());
+
+    assert_eq!(
+        files
+            .rename("archive/box.sl1", "../box.sl1")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        files
+            .move_file("archive", "archive/nested")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        files
+            .move_file("archive/box.sl1", "/tmp/box.sl1")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::PermissionDenied
+    );
+}
+
+#[tokio::test]
+async fn copies_files_without_overwriting() {
+    let directory = tempfile::tempdir().unwrap();
+    let files = local_files(&directory);
+
+    let copy = files
+        .copy_file("cube.sl1", "prints/cube.sl1")
+        .await
+        .unwrap();
+    assert_eq!(fs::read_to_string(copy).unwrap(), "cube");
+    assert!(directory.path().join("cube.sl1").exists());
+
+    assert_eq!(
+        files
+            .copy_file("cube.sl1", "prints/cube.sl1")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::AlreadyExists
+    );
+    std::os::unix::fs::symlink("/tmp/odyssey-missing", directory.path().join("link.sl1")).unwrap();
+    assert_eq!(
+        files
+            .copy_file("cube.sl1", "link.sl1")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        files
+            .copy_file("prints", "copied")
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+}
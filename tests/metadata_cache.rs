@@ -0,0 +1,176 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    time::Duration,
+};
+
+use odyssey::{
+    api_objects::ThumbnailSize,
+    metadata_cache::{watch_files, MetadataCache},
+};
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+/// Replace a file's contents without changing its size or modification time
+fn overwrite_in_place(path: &std::path::Path) {
+    let metadata = fs::metadata(path).unwrap();
+    fs::write(path, vec![0; metadata.len() as usize]).unwrap();
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(metadata.modified().unwrap())
+        .unwrap();
+}
+
+#[test]
+fn metadata_is_cached_across_restarts() {
+    let uploads = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(uploads.path(), "cube.sl1", 42.0, &[vec![0; 256]]);
+
+    let cache = MetadataCache::new(Some(cache_directory.path().to_path_buf()));
+    assert_eq!(
+        cache.print_metadata(file_data.clone()).unwrap().print_time,
+        42.0
+    );
+    cache.save().unwrap();
+
+    // Unchanged files are never reopened, so this goes unnoticed
+    overwrite_in_place(&uploads.path().join("cube.sl1"));
+
+    let cache = MetadataCache::new(Some(cache_directory.path().to_path_buf()));
+    let metadata = cache.print_metadata(file_data.clone()).unwrap();
+    assert_eq!(metadata.print_time, 42.0);
+    assert_eq!(metadata.layer_count, 1);
+
+    // Once its size changes, the file is loaded again
+    fs::write(uploads.path().join("cube.sl1"), "corrupted").unwrap();
+    assert_eq!(
+        cache.print_metadata(file_data).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn invalidated_entries_are_reloaded() {
+    let uploads = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(uploads.path(), "cube.sl1", 42.0, &[vec![0; 256]]);
+
+    let cache = MetadataCache::new(None);
+    cache.print_metadata(file_data.clone()).unwrap();
+
+    overwrite_in_place(&uploads.path().join("cube.sl1"));
+    assert!(cache.print_metadata(file_data.clone()).is_ok());
+
+    cache.invalidate(uploads.path());
+    assert!(cache.print_metadata(file_data).is_err());
+}
+
+#[tokio::test]
+async fn thumbnails_are_cached() {
+    let uploads = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+    let path = uploads.path().join("cube.sl1");
+    let file_data = common::write_print_file(uploads.path(), "cube.sl1", 42.0, &[vec![0; 256]]);
+    common::add_to_print_file(&path, "thumbnail/thumbnail400x400.png", b"small");
+
+    let cache = MetadataCache::new(Some(cache_directory.path().to_path_buf()));
+    let thumbnail = cache
+        .thumbnail(file_data.clone(), ThumbnailSize::Small, None)
+        .await
+        .unwrap();
+    assert_eq!(thumbnail.data, b"small");
+    assert_eq!(
+        fs::read_dir(cache_directory.path().join("thumbnails"))
+            .unwrap()
+            .count(),
+        1
+    );
+
+    // Other sizes are cached separately, and cleared along with the rest
+    cache
+        .thumbnail(file_data.clone(), ThumbnailSize::Small, Some((64, 32)))
+        .await
+        .unwrap();
+    assert_eq!(
+        fs::read_dir(cache_directory.path().join("thumbnails"))
+            .unwrap()
+            .count(),
+        2
+    );
+
+    overwrite_in_place(&path);
+    let thumbnail = cache
+        .thumbnail(file_data, ThumbnailSize::Small, None)
+        .await
+        .unwrap();
+    assert_eq!(thumbnail.data, b"small");
+
+    cache.invalidate(&path);
+    assert_eq!(
+        fs::read_dir(cache_directory.path().join("thumbnails"))
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn watcher_invalidates_changed_files() {
+    let uploads = tempfile::tempdir().unwrap();
+    let cache_directory = uploads.path().join(".metadata_cache");
+    let file_data = common::write_print_file(uploads.path(), "cube.sl1", 42.0, &[vec![0; 256]]);
+
+    let cache = MetadataCache::new(Some(cache_directory.clone()));
+    let cancellation_token = CancellationToken::new();
+    tokio::spawn(watch_files(
+        cache.clone(),
+        uploads.path().to_path_buf(),
+        cancellation_token.clone(),
+    ));
+
+    cache.print_metadata(file_data).unwrap();
+    cache.save().unwrap();
+    let index = cache_directory.join("metadata.json");
+    assert!(fs::read_to_string(&index).unwrap().contains("cube.sl1"));
+
+    // Give the watcher time to start
+    sleep(Duration::from_millis(200)).await;
+    fs::remove_file(uploads.path().join("cube.sl1")).unwrap();
+
+    let start = Instant::now();
+    while fs::read_to_string(&index).unwrap().contains("cube.sl1") {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "Entry not removed"
+        );
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn analysis_is_cached_with_the_metadata() {
+    let uploads = tempfile::tempdir().unwrap();
+    let cache_directory = tempfile::tempdir().unwrap();
+    let file_data =
+        common::write_print_file(uploads.path(), "cube.sl1", 42.0, &vec![vec![255; 256]; 2]);
+
+    let cache = MetadataCache::new(Some(cache_directory.path().to_path_buf()));
+    let analysis = cache.analysis(file_data.clone()).await.unwrap();
+    assert_eq!(analysis.layers.len(), 2);
+    cache.save().unwrap();
+
+    // Analysed again only once the file has visibly changed
+    overwrite_in_place(&uploads.path().join("cube.sl1"));
+    let cache = MetadataCache::new(Some(cache_directory.path().to_path_buf()));
+    let analysis = cache.analysis(file_data.clone()).await.unwrap();
+    assert_eq!(analysis.layers[1].pixel_area, 256.0);
+
+    cache.invalidate(uploads.path());
+    assert!(cache.analysis(file_data).await.is_err());
+}
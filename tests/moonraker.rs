@@ -0,0 +1,173 @@
+use std::sync::{Arc, Mutex};
+
+use futures::{SinkExt, StreamExt};
+use odyssey::{
+    configuration::MoonrakerConfig,
+    moonraker::Moonraker,
+    printer::{FirmwareRejection, HardwareControl},
+};
+use regex::Regex;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+mod common;
+
+/// Stand-in for Moonraker, answering JSON-RPC requests the way Klipper would.
+/// Plate moves land 5 microns short of their target, so tests can tell the
+/// reported position came from the server. Returns the websocket URL and the
+/// log of executed scripts.
+async fn spawn_moonraker() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+    let scripts = Arc::new(Mutex::new(Vec::new()));
+    let script_log = scripts.clone();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut websocket = accept_async(stream).await.unwrap();
+        let z_move = Regex::new(r"Z=(?P<z>[\d.]+)").unwrap();
+        let mut z = 0.0;
+
+        while let Some(Ok(Message::Text(text))) = websocket.next().await {
+            let request: Value = serde_json::from_str(text.as_str()).unwrap();
+            let id = request["id"].clone();
+            let status = json!({
+                "toolhead": { "position": [0.0, 0.0, z, 0.0], "homed_axes": "z" },
+                "print_stats": { "state": "standby" },
+            });
+
+            let response = match request["method"].as_str().unwrap() {
+                "server.info" => json!({ "id": id, "result": { "klippy_state": "ready" } }),
+                "printer.objects.subscribe" | "printer.objects.query" => {
+                    json!({ "id": id, "result": { "eventtime": 1.0, "status": status } })
+                }
+                "printer.gcode.script" => {
+                    let script = request["params"]["script"].as_str().unwrap().to_string();
+                    script_log.lock().unwrap().push(script.clone());
+
+                    if let Some(caps) = z_move.captures(&script) {
+                        z = caps["z"].parse::<f64>().unwrap() - 0.005;
+                    }
+
+                    if script.starts_with("BAD") {
+                        json!({ "id": id, "error": { "code": 400, "message": "Unknown command" } })
+                    } else {
+                        if script.starts_with("M115") {
+                            let notification = json!({
+                                "jsonrpc": "2.0",
+                                "method": "notify_gcode_response",
+                                "params": ["FIRMWARE_NAME:Klipper"],
+                            });
+                            websocket
+                                .send(Message::text(notification.to_string()))
+                                .await
+                                .unwrap();
+                        }
+                        if script.starts_with("LAYER_START") {
+                            let notification = json!({
+                                "jsonrpc": "2.0",
+                                "method": "notify_status_update",
+                                "params": [{ "print_stats": { "state": "printing" } }, 2.0],
+                            });
+                            websocket
+                                .send(Message::text(notification.to_string()))
+                                .await
+                                .unwrap();
+                        }
+                        json!({ "id": id, "result": "ok" })
+                    }
+                }
+                _ => {
+                    json!({ "id": id, "error": { "code": -32601, "message": "Method not found" } })
+                }
+            };
+
+            websocket
+                .send(Message::text(response.to_string()))
+                .await
+                .unwrap();
+        }
+    });
+
+    (url, scripts)
+}
+
+async fn test_moonraker() -> (Moonraker, Arc<Mutex<Vec<String>>>) {
+    let (url, scripts) = spawn_moonraker().await;
+
+    let mut configuration = common::default_test_configuration();
+    configuration.moonraker = Some(MoonrakerConfig { url });
+
+    let mut moonraker = Moonraker::new(configuration);
+    moonraker.initialize().await;
+
+    (moonraker, scripts)
+}
+
+#[tokio::test]
+async fn ready_when_klippy_ready() {
+    let (mut moonraker, _) = test_moonraker().await;
+
+    assert!(moonraker.is_ready().await);
+}
+
+#[tokio::test]
+async fn move_reports_actual_position() {
+    let (mut moonraker, scripts) = test_moonraker().await;
+
+    let state = moonraker.move_z(10000, 5.0).await.unwrap();
+
+    assert_eq!(state.z_microns, 9995);
+    assert!((state.z - 9.995).abs() < f64::EPSILON);
+    assert_eq!(
+        scripts.lock().unwrap().as_slice(),
+        ["MOVE_PLATE Z=10 F=300\nM400"]
+    );
+}
+
+#[tokio::test]
+async fn rejected_script_fails_command() {
+    let (mut moonraker, _) = test_moonraker().await;
+
+    let error = moonraker
+        .manual_command("BAD_COMMAND".to_string())
+        .await
+        .unwrap_err();
+    assert!(FirmwareRejection::is(&error));
+
+    assert!(moonraker.manual_command("G90".to_string()).await.is_ok());
+}
+
+#[tokio::test]
+async fn command_returns_gcode_responses() {
+    let (mut moonraker, _) = test_moonraker().await;
+
+    let responses = moonraker.manual_command("M115".to_string()).await.unwrap();
+    assert_eq!(responses, ["FIRMWARE_NAME:Klipper"]);
+
+    let responses = moonraker.manual_command("G90".to_string()).await.unwrap();
+    assert!(responses.is_empty());
+}
+
+#[tokio::test]
+async fn status_updates_are_tracked() {
+    let (mut moonraker, _) = test_moonraker().await;
+
+    assert_eq!(
+        moonraker
+            .status
+            .lock()
+            .unwrap()
+            .print_stats_state
+            .as_deref(),
+        Some("standby")
+    );
+
+    moonraker.add_print_variable("layer".to_string(), "1".to_string());
+    moonraker.start_layer(1).await.unwrap();
+
+    let status = moonraker.status.lock().unwrap().clone();
+    assert_eq!(status.print_stats_state.as_deref(), Some("printing"));
+    assert_eq!(status.homed_axes.as_deref(), Some("z"));
+}
@@ -2,12 +2,13 @@ use std::time::Duration;
 
 use odyssey::{
     api,
-    api_objects::PrinterState,
-    configuration::Configuration,
+    api_objects::{ConsoleResponse, PrinterState},
+    configuration::{Configuration, TrafficLogConfig},
     display::PrintDisplay,
     gcode::Gcode,
     printer::{Operation, Printer},
     shutdown_handler::ShutdownHandler,
+    traffic_log::TrafficLog,
 };
 use simple_logger::SimpleLogger;
 use tokio::{
@@ -58,6 +59,7 @@ fn no_hardware_mode() {
 
     let operation_channel = mpsc::channel::<Operation>(100);
     let status_channel = broadcast::channel::<PrinterState>(100);
+    let console_channel = broadcast::channel::<ConsoleResponse>(100);
 
     let runtime = build_runtime();
 
@@ -78,6 +80,7 @@ fn no_hardware_mode() {
             gcode,
             operation_channel.1,
             status_channel.0.clone(),
+            console_channel.0.clone(),
             shutdown_handler.cancellation_token.clone(),
         ));
 
@@ -85,6 +88,8 @@ fn no_hardware_mode() {
             configuration,
             sender,
             receiver,
+            console_channel.0.clone(),
+            TrafficLog::new(TrafficLogConfig::default()),
             shutdown_handler.cancellation_token.clone(),
         ));
 
@@ -118,11 +123,12 @@ pub async fn serial_feedback_loop(
             Ok(command) => {
                 log::info!("{}", command);
 
-                let response: String;
-                if command.as_str().trim() == configuration.gcode.status_check.as_str().trim() {
-                    response = configuration.gcode.status_desired.clone();
+                let response = if command.as_str().trim()
+                    == configuration.gcode.status_check.as_str().trim()
+                {
+                    configuration.gcode.status_desired.clone()
                 } else {
-                    response = configuration.gcode.move_sync.clone();
+                    configuration.gcode.move_sync.clone()
                 };
 
                 log::info!("command='{}', response='{}'", command.trim(), response);
@@ -130,6 +136,10 @@ pub async fn serial_feedback_loop(
                 sender
                     .send(response)
                     .expect("Unable to send gcode response message");
+
+                if let Some(ack) = configuration.gcode.ack_response.clone() {
+                    sender.send(ack).expect("Unable to send gcode ack message");
+                }
             }
             Err(err) => match err {
                 broadcast::error::TryRecvError::Empty => continue,
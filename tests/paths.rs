@@ -0,0 +1,92 @@
+use std::{fs, io::ErrorKind, os::unix::fs::symlink};
+
+use odyssey::paths::{is_within, resolve_existing, resolve_path};
+
+/// An upload directory alongside a directory it mustn't reach
+fn directories() -> (tempfile::TempDir, std::path::PathBuf, std::path::PathBuf) {
+    let base = tempfile::tempdir().unwrap();
+    let root = base.path().join("uploads");
+    let outside = base.path().join("secret");
+
+    fs::create_dir_all(root.join("prints")).unwrap();
+    fs::write(root.join("prints/cube.sl1"), "").unwrap();
+    fs::create_dir(&outside).unwrap();
+    fs::write(outside.join("passwd"), "").unwrap();
+
+    (base, root, outside)
+}
+
+#[test]
+fn resolves_paths_inside_root() {
+    let (_base, root, _) = directories();
+
+    assert_eq!(
+        resolve_existing(&root, "prints/cube.sl1").unwrap(),
+        root.join("prints/cube.sl1")
+    );
+    assert_eq!(resolve_existing(&root, "").unwrap(), root);
+    assert_eq!(
+        resolve_path(&root, "prints/new/box.sl1").unwrap(),
+        root.join("prints/new/box.sl1")
+    );
+    assert_eq!(
+        resolve_existing(&root, "prints/box.sl1")
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn rejects_traversal() {
+    let (_base, root, outside) = directories();
+
+    for path in [
+        "../secret/passwd",
+        "prints/../../secret/passwd",
+        "a/../../secret",
+        "./prints",
+        outside.join("passwd").to_str().unwrap(),
+        "/",
+        ".hidden",
+        "prints/.cube.sl1.part",
+    ] {
+        assert_eq!(
+            resolve_path(&root, path).unwrap_err().kind(),
+            ErrorKind::PermissionDenied,
+            "{path:?} should be rejected"
+        );
+    }
+}
+
+#[test]
+fn rejects_symlink_escapes() {
+    let (_base, root, outside) = directories();
+
+    symlink(&outside, root.join("linked")).unwrap();
+    symlink(outside.join("passwd"), root.join("passwd.sl1")).unwrap();
+    symlink(outside.join("missing"), root.join("dangling.sl1")).unwrap();
+    symlink(root.join("prints"), root.join("shortcut")).unwrap();
+
+    for path in [
+        "linked",
+        "linked/passwd",
+        "linked/new.sl1",
+        "passwd.sl1",
+        "dangling.sl1",
+    ] {
+        assert_eq!(
+            resolve_path(&root, path).unwrap_err().kind(),
+            ErrorKind::PermissionDenied,
+            "{path:?} should be rejected"
+        );
+    }
+
+    // Symlinks that stay inside the root are followed
+    assert_eq!(
+        resolve_existing(&root, "shortcut/cube.sl1").unwrap(),
+        root.join("shortcut/cube.sl1")
+    );
+    assert!(is_within(&root, &root.join("shortcut")));
+    assert!(!is_within(&root, &root.join("linked")));
+}
@@ -0,0 +1,193 @@
+use std::io::ErrorKind;
+
+use odyssey::{
+    api_objects::{CrossSectionAxis, ThumbnailSize},
+    preview::{cross_section, layer_preview, silhouette, thumbnail, Preview},
+    printfile::PrintFile,
+    sl1::Sl1,
+};
+
+mod common;
+
+/// A print whose first layer lights the left half of the display, and whose
+/// second lights its top four rows
+fn print_file(directory: &tempfile::TempDir) -> Sl1 {
+    let left_half = (0..256).map(|i| if i % 16 < 8 { 255 } else { 0 }).collect();
+    let top_rows = (0..256).map(|i| if i / 16 < 4 { 255 } else { 0 }).collect();
+
+    let file =
+        common::write_print_file(directory.path(), "preview.sl1", 1.0, &[left_half, top_rows]);
+    Sl1::from_file(file).unwrap()
+}
+
+fn row(lit: impl Fn(usize) -> bool) -> Vec<u8> {
+    (0..16).map(|i| if lit(i) { 255 } else { 0 }).collect()
+}
+
+#[tokio::test]
+async fn layer_preview_round_trips() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut file = print_file(&directory);
+
+    let preview = layer_preview(&mut file, 0).await.unwrap();
+    assert_eq!((preview.width, preview.height), (16, 16));
+    assert_eq!(&preview.pixels[..16], row(|x| x < 8));
+
+    let png = preview.to_png().unwrap();
+    let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    assert_eq!(pixels, preview.pixels);
+
+    let missing = layer_preview(&mut file, 2).await.unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn downscale_averages_pixels() {
+    let preview = Preview {
+        width: 4,
+        height: 2,
+        channels: 1,
+        pixels: vec![0, 255, 255, 255, 0, 255, 255, 0],
+    };
+
+    assert_eq!(preview.clone().downscale(4), preview);
+    assert_eq!(
+        preview.downscale(2),
+        Preview {
+            width: 2,
+            height: 1,
+            channels: 1,
+            pixels: vec![127, 191],
+        }
+    );
+}
+
+#[tokio::test]
+async fn cross_sections_stack_layers() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut file = print_file(&directory);
+
+    // Outline from the front, last layer at the top
+    let front = cross_section(&mut file, CrossSectionAxis::X, None)
+        .await
+        .unwrap();
+    assert_eq!((front.width, front.height), (16, 2));
+    assert_eq!(front.pixels, [row(|_| true), row(|x| x < 8)].concat());
+
+    // Cut below the second layer's lit rows
+    let cut = cross_section(&mut file, CrossSectionAxis::X, Some(10))
+        .await
+        .unwrap();
+    assert_eq!(cut.pixels, [row(|_| false), row(|x| x < 8)].concat());
+
+    let side = cross_section(&mut file, CrossSectionAxis::Y, None)
+        .await
+        .unwrap();
+    assert_eq!(side.pixels, [row(|y| y < 4), row(|_| true)].concat());
+
+    let outside = cross_section(&mut file, CrossSectionAxis::Y, Some(16))
+        .await
+        .unwrap_err();
+    assert_eq!(outside.kind(), ErrorKind::InvalidInput);
+}
+
+fn decode(png: &[u8]) -> Preview {
+    Preview::from_png(png).unwrap()
+}
+
+#[test]
+fn letterbox_centers_the_image() {
+    let preview = Preview {
+        width: 2,
+        height: 1,
+        channels: 1,
+        pixels: vec![255, 127],
+    };
+
+    assert_eq!(
+        preview.letterbox(4, 4),
+        Preview {
+            width: 4,
+            height: 4,
+            channels: 1,
+            pixels: [[0; 4], [255, 255, 127, 127], [255, 255, 127, 127], [0; 4]].concat(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn thumbnails_fall_back_to_the_silhouette() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut file = print_file(&directory);
+
+    let outline = silhouette(&mut file).await.unwrap();
+    assert_eq!(&outline.pixels[..16], row(|_| true));
+    assert_eq!(&outline.pixels[16 * 15..], row(|x| x < 8));
+
+    let small = decode(
+        &thumbnail(&mut file, ThumbnailSize::Small, None)
+            .await
+            .unwrap(),
+    );
+    assert_eq!((small.width, small.height), (400, 400));
+    assert_eq!(small.pixels[399], 255);
+    assert_eq!(small.pixels[400 * 399], 255);
+    assert_eq!(small.pixels[400 * 399 + 399], 0);
+
+    // Padded either side to fit the wider thumbnail
+    let large = decode(
+        &thumbnail(&mut file, ThumbnailSize::Large, None)
+            .await
+            .unwrap(),
+    );
+    assert_eq!((large.width, large.height), (800, 480));
+    assert_eq!(large.pixels[0], 0);
+    assert_eq!(large.pixels[160], 255);
+    assert_eq!(large.pixels[639], 255);
+    assert_eq!(large.pixels[640], 0);
+}
+
+#[tokio::test]
+async fn thumbnails_can_be_any_size() {
+    let directory = tempfile::tempdir().unwrap();
+    let mut file = print_file(&directory);
+
+    let red = Preview {
+        width: 2,
+        height: 2,
+        channels: 4,
+        pixels: [255, 0, 0, 255].repeat(4),
+    }
+    .to_png()
+    .unwrap();
+    common::add_to_print_file(
+        &directory.path().join("preview.sl1"),
+        "thumbnail/thumbnail400x400.png",
+        &red,
+    );
+    let mut file_with_thumbnail = Sl1::from_file(file.get_metadata().file_data).unwrap();
+
+    // Thumbnails already the requested size are returned as they are
+    let embedded = thumbnail(&mut file_with_thumbnail, ThumbnailSize::Small, None)
+        .await
+        .unwrap();
+    assert_eq!(embedded, red);
+
+    let scaled = decode(
+        &thumbnail(&mut file_with_thumbnail, ThumbnailSize::Small, Some((6, 3)))
+            .await
+            .unwrap(),
+    );
+    assert_eq!((scaled.width, scaled.height, scaled.channels), (6, 3, 4));
+    assert_eq!(&scaled.pixels[..4], [0, 0, 0, 0]);
+    assert_eq!(&scaled.pixels[4..8], [255, 0, 0, 255]);
+
+    for dimensions in [(0, 10), (10, 4096)] {
+        let error = thumbnail(&mut file, ThumbnailSize::Small, Some(dimensions))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
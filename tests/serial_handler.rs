@@ -0,0 +1,232 @@
+use std::{
+    io::{Read, Write},
+    os::unix::fs::symlink,
+    path::Path,
+    time::Duration,
+};
+
+use nix::{
+    fcntl::OFlag,
+    pty::{grantpt, posix_openpt, ptsname_r, unlockpt, PtyMaster},
+};
+use odyssey::{
+    api_objects::ConnectionStatus,
+    configuration::TrafficLogConfig,
+    serial_handler::{run_serial, Transport},
+    traffic_log::TrafficLog,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener},
+    sync::{broadcast, watch},
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Open a new pseudo-terminal, linking its device at the given path the way
+/// Klipper links `klippy.serial`
+fn open_pty_at(path: &Path) -> PtyMaster {
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+    grantpt(&master).unwrap();
+    unlockpt(&master).unwrap();
+
+    let _ = std::fs::remove_file(path);
+    symlink(ptsname_r(&master).unwrap(), path).unwrap();
+
+    master
+}
+
+async fn await_connection(
+    status: &mut watch::Receiver<ConnectionStatus>,
+    expected: ConnectionStatus,
+) {
+    timeout(
+        Duration::from_secs(10),
+        status.wait_for(|status| *status == expected),
+    )
+    .await
+    .expect("Timed out awaiting connection status")
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_when_port_returns() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("klippy.serial");
+
+    let (read_sender, mut read_receiver) = broadcast::channel(200);
+    let (write_sender, write_receiver) = broadcast::channel(200);
+    let (status_sender, mut status) = watch::channel(ConnectionStatus::Disconnected);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn(run_serial(
+        Transport::from_config(path.to_str().unwrap(), 250000),
+        read_sender,
+        write_receiver,
+        status_sender,
+        TrafficLog::new(TrafficLogConfig::default()),
+        cancellation_token.clone(),
+    ));
+
+    // The port doesn't exist yet, so the connection stays down
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(*status.borrow(), ConnectionStatus::Disconnected);
+
+    let mut master = open_pty_at(&path);
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+
+    master.write_all(b"ok\n").unwrap();
+    let line = timeout(Duration::from_secs(5), read_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(line, "ok\n");
+
+    // Closing the other end, as when Klipper restarts, drops the connection
+    drop(master);
+    await_connection(&mut status, ConnectionStatus::Disconnected).await;
+
+    let mut master = open_pty_at(&path);
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+
+    write_sender.send("G28\r\n".to_string()).unwrap();
+    let written = tokio::task::spawn_blocking(move || {
+        let mut buffer = [0u8; 5];
+        master.read_exact(&mut buffer).unwrap();
+        buffer
+    });
+    let written = timeout(Duration::from_secs(5), written)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&written, b"G28\r\n");
+
+    cancellation_token.cancel();
+}
+
+/// Exchange a line in each direction with a connected socket, then close it
+async fn exchange_lines<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    read_receiver: &mut broadcast::Receiver<String>,
+    write_sender: &broadcast::Sender<String>,
+) {
+    let mut socket = BufReader::new(socket);
+
+    socket.write_all(b"ok\n").await.unwrap();
+    let line = timeout(Duration::from_secs(5), read_receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(line, "ok\n");
+
+    write_sender.send("G28\r\n".to_string()).unwrap();
+    let mut written = String::new();
+    timeout(Duration::from_secs(5), socket.read_line(&mut written))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(written, "G28\r\n");
+}
+
+#[tokio::test]
+async fn transport_from_config() {
+    let directory = tempfile::tempdir().unwrap();
+    let socket_path = directory.path().join("klippy.sock");
+    let _listener = UnixListener::bind(&socket_path).unwrap();
+
+    assert_eq!(
+        Transport::from_config("/dev/ttyUSB0", 115200),
+        Transport::Tty {
+            path: "/dev/ttyUSB0".to_string(),
+            baudrate: 115200
+        }
+    );
+    assert_eq!(
+        Transport::from_config("tcp://printer.local:2000", 115200),
+        Transport::Tcp {
+            address: "printer.local:2000".to_string()
+        }
+    );
+    assert_eq!(
+        Transport::from_config("unix:///tmp/printer.sock", 115200),
+        Transport::Unix {
+            path: "/tmp/printer.sock".to_string()
+        }
+    );
+    assert_eq!(
+        Transport::from_config(socket_path.to_str().unwrap(), 115200),
+        Transport::Unix {
+            path: socket_path.to_str().unwrap().to_string()
+        }
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tcp_reconnects_when_peer_closes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let (read_sender, mut read_receiver) = broadcast::channel(200);
+    let (write_sender, write_receiver) = broadcast::channel(200);
+    let (status_sender, mut status) = watch::channel(ConnectionStatus::Disconnected);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn(run_serial(
+        Transport::from_config(&format!("tcp://{address}"), 250000),
+        read_sender,
+        write_receiver,
+        status_sender,
+        TrafficLog::new(TrafficLogConfig::default()),
+        cancellation_token.clone(),
+    ));
+
+    let (socket, _) = listener.accept().await.unwrap();
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+    exchange_lines(socket, &mut read_receiver, &write_sender).await;
+
+    // The socket was dropped, so the handler reconnects
+    await_connection(&mut status, ConnectionStatus::Disconnected).await;
+    let (socket, _) = timeout(Duration::from_secs(10), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+    exchange_lines(socket, &mut read_receiver, &write_sender).await;
+
+    cancellation_token.cancel();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unix_socket_reconnects_when_peer_closes() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("printer.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let (read_sender, mut read_receiver) = broadcast::channel(200);
+    let (write_sender, write_receiver) = broadcast::channel(200);
+    let (status_sender, mut status) = watch::channel(ConnectionStatus::Disconnected);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn(run_serial(
+        Transport::from_config(path.to_str().unwrap(), 250000),
+        read_sender,
+        write_receiver,
+        status_sender,
+        TrafficLog::new(TrafficLogConfig::default()),
+        cancellation_token.clone(),
+    ));
+
+    let (socket, _) = listener.accept().await.unwrap();
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+    exchange_lines(socket, &mut read_receiver, &write_sender).await;
+
+    await_connection(&mut status, ConnectionStatus::Disconnected).await;
+    let (socket, _) = timeout(Duration::from_secs(10), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    await_connection(&mut status, ConnectionStatus::Connected).await;
+    exchange_lines(socket, &mut read_receiver, &write_sender).await;
+
+    cancellation_token.cancel();
+}
@@ -0,0 +1,902 @@
+use std::{fs::File, io::ErrorKind, path::Path, time::Duration};
+
+use odyssey::{
+    api_objects::{ConnectionStatus, ConsoleResponse, LayerMovement, PrinterState, PrinterStatus},
+    configuration::{Configuration, LiftCurvePoint, SimulationConfig},
+    display::PrintDisplay,
+    printer::{HardwareControl, Operation, Printer},
+    simulated_hardware::{SimulatedHardware, SimulationHandle},
+};
+use tokio::{
+    sync::{
+        broadcast::{self, Receiver},
+        mpsc::{self, Sender},
+    },
+    time::{timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+fn simulated_config(directory: &Path, simulation: SimulationConfig) -> Configuration {
+    let mut configuration = common::default_test_configuration();
+
+    let frame_buffer = directory.join("frame_buffer");
+    File::create(&frame_buffer).unwrap();
+    configuration.display.frame_buffer = frame_buffer.to_str().unwrap().to_string();
+    configuration.display.screen_width = 16;
+    configuration.display.screen_height = 16;
+    configuration.printer.default_wait_before_exposure = 0.0;
+    configuration.printer.default_wait_after_exposure = 0.0;
+    configuration.simulation = Some(simulation);
+
+    configuration
+}
+
+/// Whether the last frame written to the simulated frame buffer shows a
+/// layer, rather than being blank. Frames are 16x16 at 8 bits per pixel.
+fn layer_displayed(directory: &Path) -> bool {
+    let written = std::fs::read(directory.join("frame_buffer")).unwrap();
+    written.len() >= 256
+        && written[written.len() - 256..]
+            .iter()
+            .any(|&level| level > 0)
+}
+
+/// Run a printer against simulated hardware, returning its operation sender,
+/// status and console receivers, along with the handle for steering the
+/// hardware
+fn start_printer(
+    configuration: Configuration,
+    cancellation_token: CancellationToken,
+) -> (
+    Sender<Operation>,
+    Receiver<PrinterState>,
+    Receiver<ConsoleResponse>,
+    SimulationHandle,
+) {
+    let hardware = SimulatedHardware::new(configuration.clone());
+    let handle = hardware.handle();
+
+    let (operation_sender, operation_receiver) = mpsc::channel(100);
+    let (status_sender, status_receiver) = broadcast::channel(100);
+    let (console_sender, console_receiver) = broadcast::channel(100);
+
+    tokio::spawn(Printer::start_printer(
+        configuration.printer.clone(),
+        PrintDisplay::new(configuration.display.clone()),
+        hardware,
+        operation_receiver,
+        status_sender,
+        console_sender,
+        cancellation_token,
+    ));
+
+    (operation_sender, status_receiver, console_receiver, handle)
+}
+
+/// Wait for a status update matching the given condition
+async fn await_status(
+    receiver: &mut Receiver<PrinterState>,
+    condition: fn(&PrinterState) -> bool,
+) -> PrinterState {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let state = receiver.recv().await.unwrap();
+            if condition(&state) {
+                return state;
+            }
+        }
+    })
+    .await
+    .expect("Timed out awaiting printer status")
+}
+
+#[tokio::test]
+async fn print_completes() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Printing)
+    })
+    .await;
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(!state.physical_state.curing);
+    assert_eq!(
+        handle.gcode_log(),
+        [
+            "G90",
+            "START_GCODE TOTAL_LAYERS=2",
+            "LAYER_START_GCODE LAYER=0",
+            "MOVE_PLATE Z=10.05 F=204",
+            "MOVE_PLATE Z=0.05 F=204",
+            "START_CURE",
+            "END_CURE",
+            "LAYER_START_GCODE LAYER=1",
+            "MOVE_PLATE Z=10.1 F=204",
+            "MOVE_PLATE Z=0.1 F=204",
+            "START_CURE",
+            "END_CURE",
+            "END_GCODE",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn lift_follows_layer_area() {
+    let directory = tempfile::tempdir().unwrap();
+    let quarter_lit = (0..256).map(|i| if i < 64 { 255 } else { 0 }).collect();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &[vec![255; 256], quarter_lit],
+    );
+    let stopped_data = common::write_print_file(
+        directory.path(),
+        "stopped.sl1",
+        1.0,
+        &vec![vec![255; 256]; 3],
+    );
+    let mut configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            // Only reached by the print which is stopped
+            rejected_gcode: Some(vec!["^LAYER_START_GCODE LAYER=2".to_string()]),
+            ..Default::default()
+        },
+    );
+    // Layers of 64mm² and 16mm²
+    configuration.display.pixel_pitch = Some(0.5);
+    configuration.printer.lift_curve = Some(vec![
+        LiftCurvePoint {
+            area: 64.0,
+            lift: 6.0,
+            up_speed: 1.0,
+            down_speed: 2.0,
+        },
+        LiftCurvePoint {
+            area: 0.0,
+            lift: 2.0,
+            up_speed: 4.0,
+            down_speed: 5.0,
+        },
+    ]);
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| {
+        state
+            .movement
+            .is_some_and(|movement| movement.area == Some(16.0))
+    })
+    .await;
+    assert_eq!(
+        state.movement,
+        Some(LayerMovement {
+            lift: 3.0,
+            lift_microns: 3000,
+            up_speed: 3.25,
+            down_speed: 4.25,
+            area: Some(16.0),
+        })
+    );
+
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    assert_eq!(state.movement, None);
+    let moves: Vec<String> = handle
+        .gcode_log()
+        .into_iter()
+        .filter(|line| line.starts_with("MOVE_PLATE"))
+        .collect();
+    assert_eq!(
+        moves,
+        [
+            "MOVE_PLATE Z=6.05 F=60",
+            "MOVE_PLATE Z=0.05 F=120",
+            "MOVE_PLATE Z=3.1 F=195",
+            "MOVE_PLATE Z=0.1 F=255",
+        ]
+    );
+
+    // A stopped print clears the movement of the layer it stopped on
+    operations
+        .send(Operation::StartPrint {
+            file_data: stopped_data,
+        })
+        .await
+        .unwrap();
+    let state = await_status(&mut status, |state| state.paused == Some(true)).await;
+    assert!(state.movement.is_some());
+
+    operations.send(Operation::StopPrint).await.unwrap();
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert_eq!(state.movement, None);
+}
+
+#[tokio::test]
+async fn layer_displayed_only_while_curing() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let mut configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+    configuration.display.bit_depth = vec![8];
+    configuration.printer.default_wait_before_exposure = 0.4;
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, _) = start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    // In position, but still waiting before exposure
+    await_status(&mut status, |state| {
+        state.physical_state.z_microns == 50 && !state.physical_state.curing
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!layer_displayed(directory.path()));
+
+    await_status(&mut status, |state| state.physical_state.curing).await;
+    assert!(layer_displayed(directory.path()));
+
+    await_status(&mut status, |state| !state.physical_state.curing).await;
+    assert!(!layer_displayed(directory.path()));
+
+    operations.send(Operation::PausePrint).await.unwrap();
+    await_status(&mut status, |state| state.paused == Some(true)).await;
+    assert!(!layer_displayed(directory.path()));
+
+    operations.send(Operation::StopPrint).await.unwrap();
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(!layer_displayed(directory.path()));
+}
+
+#[tokio::test]
+async fn early_layer_display_is_blanked_on_pause() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let mut configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["^START_CURE".to_string()]),
+            ..Default::default()
+        },
+    );
+    configuration.display.bit_depth = vec![8];
+    configuration.printer.default_wait_before_exposure = 0.4;
+    configuration.printer.early_layer_display = Some(true);
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, _) = start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    // Shown as soon as the plate is in position
+    await_status(&mut status, |state| {
+        state.physical_state.z_microns == 50 && !state.physical_state.curing
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(layer_displayed(directory.path()));
+
+    // Rejecting the cure pauses the print, which blanks the layer
+    await_status(&mut status, |state| state.paused == Some(true)).await;
+    assert!(!layer_displayed(directory.path()));
+
+    operations.send(Operation::StopPrint).await.unwrap();
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(!layer_displayed(directory.path()));
+}
+
+#[tokio::test]
+async fn rejected_gcode_pauses_print() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["^LAYER_START_GCODE LAYER=1".to_string()]),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, _) = start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| state.paused == Some(true)).await;
+    cancellation_token.cancel();
+
+    assert!(matches!(state.status, PrinterStatus::Printing));
+    assert_eq!(state.layer, Some(1));
+}
+
+#[tokio::test]
+async fn unrenderable_gcode_pauses_print() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let mut configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+    // Only fails to render once past the first layer
+    configuration.gcode.layer_start =
+        "LAYER_START_GCODE LAYER={layer}{% if layer > 0 %} {missing}{% endif %}".to_string();
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| state.paused == Some(true)).await;
+    cancellation_token.cancel();
+
+    assert!(matches!(state.status, PrinterStatus::Printing));
+    assert_eq!(state.layer, Some(1));
+    assert!(!handle
+        .gcode_log()
+        .iter()
+        .any(|line| line.starts_with("LAYER_START_GCODE LAYER=1")));
+}
+
+#[tokio::test]
+async fn rejected_cure_stop_shuts_down() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["^END_CURE".to_string()]),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Shutdown)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(state.error.unwrap().contains("Unable to stop curing"));
+    // Stopping the cure is retried before giving up
+    let log = handle.gcode_log();
+    assert_eq!(log.iter().filter(|line| *line == "END_CURE").count(), 2);
+}
+
+#[tokio::test]
+async fn corrupt_layer_pauses_print() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    common::add_to_print_file(
+        &directory.path().join("simulated.sl1"),
+        "simulated00002.png",
+        b"not a png",
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| state.paused == Some(true)).await;
+
+    assert!(matches!(state.status, PrinterStatus::Printing));
+    assert_eq!(state.layer, Some(2));
+    assert!(state.error.unwrap().contains("layer 2"));
+    assert!(!handle.gcode_log().contains(&"END_GCODE".to_string()));
+
+    // Cancelling leaves the error for the operator to see
+    operations.send(Operation::StopPrint).await.unwrap();
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(state.error.is_some());
+}
+
+#[tokio::test]
+async fn invalid_print_file_is_reported() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    std::fs::write(directory.path().join("simulated.sl1"), b"not a zip").unwrap();
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, _) = start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+
+    let state = await_status(&mut status, |state| state.error.is_some()).await;
+    cancellation_token.cancel();
+
+    assert!(matches!(state.status, PrinterStatus::Idle));
+    assert!(state.error.unwrap().contains("Invalid print file"));
+}
+
+#[tokio::test]
+async fn lost_connection_pauses_and_shuts_down() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Printing)
+    })
+    .await;
+
+    handle.set_connected(false);
+
+    let state = await_status(&mut status, |state| {
+        state.connection == ConnectionStatus::Disconnected
+    })
+    .await;
+    assert!(matches!(state.status, PrinterStatus::Printing));
+    assert_eq!(state.paused, Some(true));
+
+    let state = await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Shutdown)
+    })
+    .await;
+    cancellation_token.cancel();
+
+    assert!(!state.physical_state.curing);
+    assert!(!handle.gcode_log().contains(&"END_GCODE".to_string()));
+}
+
+#[tokio::test]
+async fn manual_move_requires_homing() {
+    let directory = tempfile::tempdir().unwrap();
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, _, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::ManualMove { z: 5000 })
+        .await
+        .unwrap();
+    operations.send(Operation::ManualHome).await.unwrap();
+    await_status(&mut status, |state| state.physical_state.homed).await;
+
+    operations
+        .send(Operation::ManualMove { z: 5000 })
+        .await
+        .unwrap();
+    let state = await_status(&mut status, |state| state.physical_state.z_microns == 5000).await;
+    cancellation_token.cancel();
+
+    assert!(state.physical_state.homed);
+    assert_eq!(
+        handle.gcode_log(),
+        ["G90", "HOME_AXIS", "MOVE_PLATE Z=5 F=204"]
+    );
+}
+
+#[tokio::test]
+async fn readiness_follows_configuration() {
+    let mut hardware = SimulatedHardware::new(Configuration {
+        simulation: Some(SimulationConfig {
+            startup_checks: Some(2),
+            flap_interval: Some(4),
+            ..Default::default()
+        }),
+        ..common::default_test_configuration()
+    });
+
+    let mut readiness = Vec::new();
+    for _ in 0..8 {
+        readiness.push(hardware.is_ready().await);
+    }
+    assert_eq!(
+        readiness,
+        [false, false, true, false, true, true, true, false]
+    );
+
+    hardware.handle().set_ready(Some(false));
+    assert!(!hardware.is_ready().await);
+}
+
+#[tokio::test]
+async fn invalid_rejected_gcode_pattern_is_ignored() {
+    let mut hardware = SimulatedHardware::new(Configuration {
+        simulation: Some(SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["(".to_string(), "^M999".to_string()]),
+            ..Default::default()
+        }),
+        ..common::default_test_configuration()
+    });
+
+    assert!(hardware.manual_command("G90".to_string()).await.is_ok());
+    assert!(hardware.manual_command("M999".to_string()).await.is_err());
+}
+
+#[tokio::test]
+async fn manual_command_is_sent_verbatim() {
+    let mut hardware = SimulatedHardware::new(common::default_test_configuration());
+
+    let responses = hardware
+        .manual_command("M117 {not a template}".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(responses, ["ok"]);
+    assert_eq!(hardware.handle().gcode_log(), ["M117 {not a template}"]);
+}
+
+#[tokio::test]
+async fn move_takes_simulated_time() {
+    let mut hardware = SimulatedHardware::new(common::default_test_configuration());
+
+    let start = Instant::now();
+    let state = hardware.move_z(500, 5.0).await.unwrap();
+
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(state.z_microns, 500);
+}
+
+#[tokio::test]
+async fn injected_fault_fails_next_operation() {
+    let mut hardware = SimulatedHardware::new(common::default_test_configuration());
+    hardware.handle().inject_fault(ErrorKind::TimedOut);
+
+    let error = hardware.home().await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+    assert!(hardware.home().await.is_ok());
+}
+
+#[tokio::test]
+async fn console_command_rejected_unless_idle() {
+    let directory = tempfile::tempdir().unwrap();
+    let file_data = common::write_print_file(
+        directory.path(),
+        "simulated.sl1",
+        1.0,
+        &vec![vec![255; 256]; 2],
+    );
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["^LAYER_START_GCODE LAYER=1".to_string()]),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, mut console, handle) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::StartPrint { file_data })
+        .await
+        .unwrap();
+    await_status(&mut status, |state| state.paused == Some(true)).await;
+
+    operations
+        .send(Operation::ManualCommand {
+            command: "M115".to_string(),
+            id: 1,
+        })
+        .await
+        .unwrap();
+    let printing = timeout(Duration::from_secs(10), console.recv())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Kept shut down, as the hardware no longer reports ready
+    handle.set_ready(Some(false));
+    operations.send(Operation::Shutdown).await.unwrap();
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Shutdown)
+    })
+    .await;
+
+    operations
+        .send(Operation::ManualCommand {
+            command: "M115".to_string(),
+            id: 2,
+        })
+        .await
+        .unwrap();
+    let shutdown = timeout(Duration::from_secs(10), console.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    cancellation_token.cancel();
+
+    for (response, id) in [(printing, 1), (shutdown, 2)] {
+        assert_eq!(response.id, id);
+        assert!(response.lines.is_empty());
+        assert!(response.error.unwrap().contains("must be idle"));
+    }
+    assert!(!handle.gcode_log().contains(&"M115".to_string()));
+}
+
+#[tokio::test]
+async fn console_command_reports_response() {
+    let directory = tempfile::tempdir().unwrap();
+    let configuration = simulated_config(
+        directory.path(),
+        SimulationConfig {
+            time_scale: Some(0.0),
+            rejected_gcode: Some(vec!["^M999".to_string()]),
+            ..Default::default()
+        },
+    );
+
+    let cancellation_token = CancellationToken::new();
+    let (operations, mut status, mut console, _) =
+        start_printer(configuration, cancellation_token.clone());
+
+    await_status(&mut status, |state| {
+        matches!(state.status, PrinterStatus::Idle)
+    })
+    .await;
+
+    operations
+        .send(Operation::ManualCommand {
+            command: "M115\nM400".to_string(),
+            id: 7,
+        })
+        .await
+        .unwrap();
+    operations
+        .send(Operation::ManualCommand {
+            command: "M999".to_string(),
+            id: 8,
+        })
+        .await
+        .unwrap();
+
+    let accepted = timeout(Duration::from_secs(10), console.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let rejected = timeout(Duration::from_secs(10), console.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    cancellation_token.cancel();
+
+    assert_eq!(accepted.id, 7);
+    assert_eq!(accepted.lines, ["ok", "ok"]);
+    assert_eq!(accepted.error, None);
+
+    assert_eq!(rejected.id, 8);
+    assert_eq!(rejected.command, "M999");
+    assert!(rejected.error.unwrap().contains("rejected"));
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use odyssey::{
+    configuration::Configuration,
+    template::{render, Template, TemplateCache, TemplateError},
+};
+
+mod common;
+
+fn variables(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn plain_substitution_is_unchanged() {
+    let variables = variables(&[("z", "10.05"), ("speed", "204")]);
+
+    assert_eq!(
+        render("MOVE_PLATE Z={z} F={speed}", &variables).unwrap(),
+        "MOVE_PLATE Z=10.05 F=204"
+    );
+}
+
+#[test]
+fn arithmetic_and_formatting() {
+    let variables = variables(&[("z", "10"), ("layer", "3")]);
+
+    assert_eq!(render("G1 Z{z + 5}", &variables).unwrap(), "G1 Z15");
+    assert_eq!(render("G1 Z{z / 3:.3}", &variables).unwrap(), "G1 Z3.333");
+    assert_eq!(
+        render("G4 P{(layer + 1) * 100 % 300}", &variables).unwrap(),
+        "G4 P100"
+    );
+    assert_eq!(render("G1 Z{-z}", &variables).unwrap(), "G1 Z-10");
+}
+
+#[test]
+fn conditionals() {
+    let template = Template::parse(
+        "{% if layer < 2 %}BOTTOM{% elif layer == 2 and not curing %}TRANSITION{% else %}NORMAL{% endif %}",
+    )
+    .unwrap();
+
+    let rendered: Vec<String> = ["0", "2", "5"]
+        .iter()
+        .map(|layer| {
+            template
+                .render(&variables(&[("layer", layer), ("curing", "false")]))
+                .unwrap()
+        })
+        .collect();
+
+    assert_eq!(rendered, ["BOTTOM", "TRANSITION", "NORMAL"]);
+}
+
+#[test]
+fn defaults_for_missing_variables() {
+    let variables = variables(&[("z", "1")]);
+
+    assert_eq!(render("F{speed ?? 300}", &variables).unwrap(), "F300");
+    assert_eq!(render("Z{z ?? 0}", &variables).unwrap(), "Z1");
+    assert_eq!(
+        render("{% if exposure ?? false %}ON{% endif %}", &variables).unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn missing_variable_is_an_error() {
+    assert_eq!(
+        render("G1 Z{z}", &HashMap::new()),
+        Err(TemplateError::MissingVariable("z".to_string()))
+    );
+}
+
+#[test]
+fn malformed_templates_fail_to_parse() {
+    for source in [
+        "G1 Z{z",
+        "G1 Z{z +}",
+        "{% if z > 1 %}G1",
+        "{% endif %}",
+        "{% for z %}",
+        "{z:3}",
+    ] {
+        assert!(
+            matches!(Template::parse(source), Err(TemplateError::Syntax(_))),
+            "{source} should not parse"
+        );
+    }
+}
+
+#[test]
+fn required_variables_exclude_defaulted() {
+    let template = Template::parse("{% if layer > 1 %}G1 F{speed ?? feed}{% endif %}").unwrap();
+
+    let mut required: Vec<String> = template.required_variables().into_iter().collect();
+    required.sort();
+
+    assert_eq!(required, ["feed", "layer"]);
+}
+
+#[test]
+fn cached_templates_render_like_parsed_ones() {
+    let mut templates = TemplateCache::new(["G1 Z{z:.2}"]);
+
+    assert_eq!(
+        templates.render("G1 Z{z:.2}", &variables(&[("z", "1.234")])),
+        Ok("G1 Z1.23".to_string())
+    );
+    assert_eq!(
+        templates.render("M117 {layer}", &variables(&[("layer", "3")])),
+        Ok("M117 3".to_string())
+    );
+    assert!(matches!(
+        templates.render("{% if z %}", &variables(&[])),
+        Err(TemplateError::Syntax(_))
+    ));
+}
+
+#[test]
+fn config_validation() {
+    let mut configuration = common::default_test_configuration();
+    assert!(configuration.gcode.validate().is_ok());
+
+    // The layer is only available once a print has started
+    configuration.gcode.boot = String::from("G90\nM117 {layer}");
+    let error = configuration.gcode.validate().unwrap_err();
+    assert_eq!(
+        error,
+        TemplateError::Gcode(
+            "boot".to_string(),
+            Box::new(TemplateError::MissingVariable("layer".to_string()))
+        )
+    );
+    assert_eq!(
+        error.to_string(),
+        "gcode.boot: Template variable layer is unavailable"
+    );
+
+    configuration.gcode.boot = String::from("G90\nM117 {layer ?? 0}");
+    assert!(configuration.gcode.validate().is_ok());
+
+    configuration.gcode.cure_start = String::from("{% if curing %}UVLED_ON");
+    assert!(matches!(
+        configuration.gcode.validate(),
+        Err(TemplateError::Gcode(name, error))
+            if name == "cure_start" && matches!(*error, TemplateError::Syntax(_))
+    ));
+}
+
+#[test]
+fn default_config_templates_are_valid() {
+    let config_file = format!("{}/resources/default.yaml", common::CARGO_DIR);
+
+    assert!(Configuration::from_file(config_file).is_ok());
+}
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use odyssey::{
+    api_objects::{TrafficDirection, TrafficEntry},
+    configuration::TrafficLogConfig,
+    simulated_hardware::SimulatedHardware,
+    traffic_log::{read_log, replay, rotated_path, TrafficLog},
+};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+mod common;
+
+fn entry(timestamp: f64, direction: TrafficDirection, line: &str) -> TrafficEntry {
+    TrafficEntry {
+        timestamp,
+        direction,
+        line: line.to_string(),
+    }
+}
+
+#[test]
+fn keeps_recent_lines() {
+    let traffic_log = TrafficLog::new(TrafficLogConfig {
+        history: Some(3),
+        ..Default::default()
+    });
+
+    for layer in 0..5 {
+        traffic_log.record(TrafficDirection::Sent, &format!("M117 {layer}\r\n"));
+    }
+    traffic_log.record(TrafficDirection::Received, "ok\n");
+
+    let lines: Vec<String> = traffic_log
+        .recent(None)
+        .into_iter()
+        .map(|entry| entry.line)
+        .collect();
+    assert_eq!(lines, ["M117 3", "M117 4", "ok"]);
+
+    let recent = traffic_log.recent(Some(1));
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].direction, TrafficDirection::Received);
+}
+
+#[test]
+fn rotates_log_file() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("traffic.log");
+
+    let traffic_log = TrafficLog::new(TrafficLogConfig {
+        path: Some(path.to_str().unwrap().to_string()),
+        max_size: Some(100),
+        max_files: Some(2),
+        history: None,
+    });
+
+    for layer in 0..20 {
+        traffic_log.record(
+            TrafficDirection::Sent,
+            &format!("LAYER_START LAYER={layer}"),
+        );
+        traffic_log.record(TrafficDirection::Received, "ok");
+    }
+
+    assert!(rotated_path(&path, 1).exists());
+    assert!(rotated_path(&path, 2).exists());
+    assert!(!rotated_path(&path, 3).exists());
+
+    let entries = read_log(&path).unwrap();
+    let last = entries.last().unwrap();
+    assert_eq!(last.direction, TrafficDirection::Received);
+    assert_eq!(last.line, "ok");
+    assert_eq!(
+        entries[entries.len() - 2].line,
+        "LAYER_START LAYER=19".to_string()
+    );
+    assert!(std::fs::metadata(&path).unwrap().len() <= 100);
+}
+
+#[tokio::test]
+async fn replays_sent_commands_with_timing() {
+    let mut hardware = SimulatedHardware::new(common::default_test_configuration());
+    let handle = hardware.handle();
+
+    let entries = [
+        entry(100.0, TrafficDirection::Sent, "N0 M110 N0*125"),
+        entry(100.01, TrafficDirection::Received, "ok"),
+        entry(100.1, TrafficDirection::Sent, "N1 G28*18"),
+        entry(100.15, TrafficDirection::Received, "Resend: 1"),
+        entry(100.2, TrafficDirection::Sent, "N1 G28*18"),
+        entry(100.3, TrafficDirection::Sent, "N2 G1 Z10*99"),
+        entry(100.35, TrafficDirection::Received, "ok"),
+        entry(100.4, TrafficDirection::Sent, "M400"),
+    ];
+
+    let start = Instant::now();
+    let sent = replay(&mut hardware, &entries, &CancellationToken::new())
+        .await
+        .unwrap();
+
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert_eq!(sent, 3);
+    assert_eq!(handle.gcode_log(), ["G28", "G1 Z10", "M400"]);
+}
+
+#[test]
+fn malformed_log_is_rejected() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("traffic.log");
+    std::fs::write(&path, "1.5 TX G28\nnot a record\n").unwrap();
+
+    let error = read_log(&path).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
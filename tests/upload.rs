@@ -0,0 +1,283 @@
+use std::{fs, io::ErrorKind, time::Duration};
+
+use odyssey::{
+    api_objects::UploadState,
+    upload::{sanitize_file_name, Uploads, SESSION_TTL},
+};
+
+const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+/// Names of all files in the directory, including hidden ones
+fn directory_contents(directory: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(directory)
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.file_name().to_str().unwrap().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn file_names_are_sanitized() {
+    assert_eq!(sanitize_file_name("cube.sl1").unwrap(), "cube.sl1");
+    assert_eq!(
+        sanitize_file_name("../../etc/cube.sl1").unwrap(),
+        "cube.sl1"
+    );
+    assert_eq!(
+        sanitize_file_name("C:\\Users\\me\\cube.sl1").unwrap(),
+        "cube.sl1"
+    );
+    assert_eq!(sanitize_file_name(".hidden.sl1").unwrap(), "hidden.sl1");
+    assert_eq!(sanitize_file_name("a|b\n.sl1").unwrap(), "a_b_.sl1");
+
+    for name in ["", "..", "dir/", "   ", &"a".repeat(300)] {
+        assert_eq!(
+            sanitize_file_name(name).unwrap_err().kind(),
+            ErrorKind::InvalidInput,
+            "{name:?} should be rejected"
+        );
+    }
+}
+
+#[tokio::test]
+async fn upload_is_verified_and_moved_into_place() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+    let mut progress = uploads.subscribe();
+
+    let path = uploads
+        .upload(
+            None,
+            "../hello.sl1",
+            &b"hello"[..],
+            Some(5),
+            Some(HELLO_SHA256),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(path, directory.path().join("hello.sl1"));
+    assert_eq!(fs::read(&path).unwrap(), b"hello");
+    assert_eq!(directory_contents(directory.path()), ["hello.sl1"]);
+
+    let update = progress.recv().await.unwrap();
+    assert_eq!(update.state, UploadState::Complete);
+    assert_eq!(update.received, 5);
+}
+
+#[tokio::test]
+async fn upload_with_wrong_checksum_is_discarded() {
+    let directory = tempfile::tempdir().unwrap();
+    fs::write(directory.path().join("hello.sl1"), "original").unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let error = uploads
+        .upload(
+            None,
+            "hello.sl1",
+            &b"corrupted"[..],
+            None,
+            Some(HELLO_SHA256),
+        )
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(directory_contents(directory.path()), ["hello.sl1"]);
+    assert_eq!(
+        fs::read_to_string(directory.path().join("hello.sl1")).unwrap(),
+        "original"
+    );
+}
+
+#[tokio::test]
+async fn discarded_upload_leaves_existing_file() {
+    let directory = tempfile::tempdir().unwrap();
+    fs::write(directory.path().join("hello.sl1"), "original").unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+    let mut progress = uploads.subscribe();
+
+    let received = uploads
+        .receive(None, "hello.sl1", &b"hello"[..], None, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(directory.path().join("hello.sl1")).unwrap(),
+        "original"
+    );
+
+    uploads.discard(received).await;
+
+    assert_eq!(directory_contents(directory.path()), ["hello.sl1"]);
+    assert_eq!(
+        fs::read_to_string(directory.path().join("hello.sl1")).unwrap(),
+        "original"
+    );
+    let update = progress.recv().await.unwrap();
+    assert_eq!(update.state, UploadState::Failed);
+}
+
+#[tokio::test]
+async fn chunked_upload_resumes_from_received_offset() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let session = uploads
+        .start_chunked(None, "hello.sl1", 5, Some(HELLO_SHA256.to_uppercase()))
+        .await
+        .unwrap();
+    assert_eq!(session.received, 0);
+
+    let session = uploads
+        .write_chunk(&session.id, 0, &b"hel"[..])
+        .await
+        .unwrap();
+    assert_eq!(session.received, 3);
+
+    // Incomplete uploads can't be finished, and chunks must follow on
+    assert_eq!(
+        uploads
+            .complete_chunked(&session.id)
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        uploads
+            .write_chunk(&session.id, 0, &b"hel"[..])
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+
+    let resumed = uploads.session(&session.id).unwrap();
+    uploads
+        .write_chunk(&session.id, resumed.received, &b"lo"[..])
+        .await
+        .unwrap();
+    let path = uploads.complete_chunked(&session.id).await.unwrap();
+
+    assert_eq!(fs::read(path).unwrap(), b"hello");
+    assert_eq!(directory_contents(directory.path()), ["hello.sl1"]);
+    assert_eq!(
+        uploads.session(&session.id).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[tokio::test]
+async fn aborted_upload_is_removed() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let session = uploads
+        .start_chunked(None, "cube.sl1", 10, None)
+        .await
+        .unwrap();
+    uploads
+        .write_chunk(&session.id, 0, &b"12345"[..])
+        .await
+        .unwrap();
+    uploads.abort_chunked(&session.id).await.unwrap();
+
+    assert!(directory_contents(directory.path()).is_empty());
+}
+
+#[tokio::test]
+async fn oversized_chunk_is_cut_at_total_size() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let session = uploads
+        .start_chunked(None, "hello.sl1", 5, Some(HELLO_SHA256.to_string()))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        uploads
+            .write_chunk(&session.id, 0, &b"hello, world"[..])
+            .await
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(uploads.session(&session.id).unwrap().received, 5);
+
+    let path = uploads.complete_chunked(&session.id).await.unwrap();
+    assert_eq!(fs::read(path).unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn idle_uploads_expire() {
+    let directory = tempfile::tempdir().unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let session = uploads
+        .start_chunked(None, "cube.sl1", 10, None)
+        .await
+        .unwrap();
+    uploads
+        .write_chunk(&session.id, 0, &b"12345"[..])
+        .await
+        .unwrap();
+
+    uploads.expire_sessions(SESSION_TTL).await;
+    assert!(uploads.session(&session.id).is_ok());
+
+    uploads.expire_sessions(Duration::ZERO).await;
+    assert_eq!(
+        uploads.session(&session.id).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert!(directory_contents(directory.path()).is_empty());
+}
+
+#[test]
+fn leftover_parts_are_removed() {
+    let directory = tempfile::tempdir().unwrap();
+    fs::write(directory.path().join(".abc123.part"), "partial").unwrap();
+    fs::write(directory.path().join("cube.sl1"), "").unwrap();
+
+    Uploads::new(directory.path().to_str().unwrap());
+
+    assert_eq!(directory_contents(directory.path()), ["cube.sl1"]);
+}
+
+#[tokio::test]
+async fn uploads_into_subdirectory() {
+    let directory = tempfile::tempdir().unwrap();
+    fs::create_dir(directory.path().join("prints")).unwrap();
+    let uploads = Uploads::new(directory.path().to_str().unwrap());
+
+    let path = uploads
+        .upload(Some("prints"), "hello.sl1", &b"hello"[..], None, None)
+        .await
+        .unwrap();
+    assert_eq!(path, directory.path().join("prints/hello.sl1"));
+
+    let session = uploads
+        .start_chunked(Some("prints"), "cube.sl1", 5, None)
+        .await
+        .unwrap();
+    uploads
+        .write_chunk(&session.id, 0, &b"hello"[..])
+        .await
+        .unwrap();
+    let path = uploads.complete_chunked(&session.id).await.unwrap();
+    assert_eq!(path, directory.path().join("prints/cube.sl1"));
+
+    for subdirectory in ["missing", "../prints", "prints/hello.sl1"] {
+        assert!(
+            uploads
+                .upload(Some(subdirectory), "cube.sl1", &b""[..], None, None)
+                .await
+                .is_err(),
+            "{subdirectory:?} should be rejected"
+        );
+    }
+    assert_eq!(directory_contents(directory.path()), ["prints"]);
+}
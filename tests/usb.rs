@@ -0,0 +1,135 @@
+use std::{fs, io::ErrorKind, path::Path, time::Duration};
+
+use odyssey::{
+    api_objects::UsbEvent,
+    usb::{watch_mounts, UsbDrives},
+};
+use tokio::{
+    sync::broadcast,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+fn usb_glob(media: &Path) -> String {
+    format!("{}/usb*/*.sl1", media.to_str().unwrap())
+}
+
+#[test]
+fn lists_mounted_drives() {
+    let media = tempfile::tempdir().unwrap();
+    fs::create_dir(media.path().join("usb0")).unwrap();
+    fs::create_dir(media.path().join("usb1")).unwrap();
+    fs::create_dir(media.path().join("sdcard")).unwrap();
+    fs::write(media.path().join("usb2"), "not a mount").unwrap();
+
+    let drives = UsbDrives::new(&usb_glob(media.path())).unwrap();
+
+    assert_eq!(drives.root(), media.path());
+    assert_eq!(
+        drives.mounts().into_iter().collect::<Vec<_>>(),
+        ["usb0", "usb1"]
+    );
+    assert!(drives.is_print_file(Path::new("usb0/cube.sl1")));
+    assert!(!drives.is_print_file(Path::new("usb0/cube.stl")));
+}
+
+#[test]
+fn single_mount_without_wildcard() {
+    let media = tempfile::tempdir().unwrap();
+    fs::create_dir(media.path().join("usb")).unwrap();
+
+    let drives = UsbDrives::new(&format!("{}/usb/*.sl1", media.path().to_str().unwrap())).unwrap();
+
+    assert_eq!(drives.root(), media.path());
+    assert_eq!(drives.mounts().into_iter().collect::<Vec<_>>(), ["usb"]);
+}
+
+#[test]
+fn resolves_files_on_mounted_drives() {
+    let media = tempfile::tempdir().unwrap();
+    fs::create_dir_all(media.path().join("usb0/prints")).unwrap();
+    fs::write(media.path().join("usb0/prints/cube.sl1"), "").unwrap();
+
+    let drives = UsbDrives::new(&usb_glob(media.path())).unwrap();
+
+    assert_eq!(
+        drives.resolve("usb0/prints/cube.sl1").unwrap(),
+        media.path().join("usb0/prints/cube.sl1")
+    );
+    assert_eq!(
+        drives.resolve("usb0/missing.sl1").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        drives.resolve("usb1/cube.sl1").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        drives
+            .resolve("usb0/../usb0/prints/cube.sl1")
+            .unwrap_err()
+            .kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        drives.resolve("/etc/passwd").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn files_cannot_escape_their_drive() {
+    let media = tempfile::tempdir().unwrap();
+    fs::create_dir(media.path().join("usb0")).unwrap();
+    fs::create_dir(media.path().join("usb1")).unwrap();
+    fs::write(media.path().join("usb1/cube.sl1"), "").unwrap();
+    std::os::unix::fs::symlink(media.path().join("usb1"), media.path().join("usb0/other")).unwrap();
+
+    let drives = UsbDrives::new(&usb_glob(media.path())).unwrap();
+
+    assert_eq!(
+        drives.resolve("usb0/other/cube.sl1").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(drives.resolve("usb0").unwrap(), media.path().join("usb0"));
+}
+
+#[test]
+fn rejects_glob_without_mount_directory() {
+    assert!(UsbDrives::new("*.sl1").is_err());
+}
+
+#[tokio::test]
+async fn reports_mount_changes() {
+    let media = tempfile::tempdir().unwrap();
+    let drives = UsbDrives::new(&usb_glob(media.path())).unwrap();
+
+    let (sender, mut receiver) = broadcast::channel(10);
+    let cancellation_token = CancellationToken::new();
+    tokio::spawn(watch_mounts(drives, sender, cancellation_token.clone()));
+
+    // Let the watcher see the drives mounted at startup first
+    sleep(Duration::from_millis(500)).await;
+
+    fs::create_dir(media.path().join("usb0")).unwrap();
+    let event = timeout(Duration::from_secs(10), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        event,
+        UsbEvent {
+            mount: "usb0".to_string(),
+            mounted: true
+        }
+    );
+
+    fs::remove_dir(media.path().join("usb0")).unwrap();
+    let event = timeout(Duration::from_secs(10), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!event.mounted);
+
+    cancellation_token.cancel();
+}